@@ -0,0 +1,87 @@
+// Settings fixed when the program is built
+
+use solana_program::pubkey::{Pubkey, MAX_SEEDS, MAX_SEED_LEN};
+
+/// The largest amount of Token Y an escrow may ask for.
+/// Risk-limited deployments build with the `escrow-amount-cap` feature to lower it.
+#[cfg(not(feature = "escrow-amount-cap"))]
+pub const MAX_ESCROW_AMOUNT: u64 = u64::MAX;
+#[cfg(feature = "escrow-amount-cap")]
+pub const MAX_ESCROW_AMOUNT: u64 = 1_000_000_000;
+
+/// The fewest seconds between an escrow's creation and its expiry, so that no escrow can be made
+/// and expire within the same slot. Deployments requiring it build with the `min-escrow-duration` feature,
+/// otherwise an escrow may even be created already expired.
+#[cfg(not(feature = "min-escrow-duration"))]
+pub const MIN_ESCROW_DURATION: Option<i64> = None;
+#[cfg(feature = "min-escrow-duration")]
+pub const MIN_ESCROW_DURATION: Option<i64> = Some(60);
+
+/// The lamports the fee vault adds to what the initializer gets back once their escrow is taken,
+/// as far as the vault goes. Deployments rewarding makers build with the `maker-rebate` feature.
+#[cfg(not(feature = "maker-rebate"))]
+pub const MAKER_REBATE_LAMPORTS: u64 = 0;
+#[cfg(feature = "maker-rebate")]
+pub const MAKER_REBATE_LAMPORTS: u64 = 100_000;
+
+/// The seed of the fee vault, a system account under the program's PDA that anyone may fund
+/// and that pays the maker rebates
+pub const FEE_VAULT_SEED: &[u8] = b"fee-vault";
+
+/// The seed prefix every escrow's PDA is derived from, followed by the escrow account's key.
+/// Forks deployed side by side can change it here to keep their PDAs apart.
+pub const ESCROW_SEED: &[u8] = b"escrow";
+
+/// The seed of the single PDA every baseline escrow, from before the version byte, shared.
+/// Unlike `ESCROW_SEED`, it stays as it is: their temp token accounts are still under that PDA
+/// until `MigrateState` hands them over to the PDA of each escrow.
+pub const BASELINE_ESCROW_SEED: &[u8] = b"escrow";
+
+/// The seed prefix of the escrow accounts `InitEscrowPda` creates,
+/// followed by the initializer's key and the temp token account's key.
+pub const ESCROW_STATE_SEED: &[u8] = b"escrow-state";
+
+/// The seed prefix of the temp token accounts `InitEscrowManaged` creates, followed by the escrow account's key.
+pub const TEMP_TOKEN_ACCOUNT_SEED: &[u8] = b"temp-token-account";
+
+/// How many seconds a taker who prepared an exchange has to finalize it,
+/// never past the escrow's own expiry. The escrow is locked for everyone else until then.
+pub const PREPARED_EXCHANGE_DURATION: i64 = 10 * 60;
+
+/// The seed of the program config, a PDA under the program holding the switches
+/// that apply to every escrow at once. It only exists once the admin first used one.
+pub const PROGRAM_CONFIG_SEED: &[u8] = b"program-config";
+
+/// The only key allowed to pause and resume every exchange, for emergencies.
+/// `8v1VrP8g83xwvm5NFf4WWcFsDtpHZfMQ5ATwbGATSitA` is the one the tests sign with,
+/// deployments set their own here before building.
+pub const ADMIN_PUBKEY: Pubkey = Pubkey::new_from_array([
+    117, 150, 39, 96, 198, 179, 80, 147, 123, 138, 126, 232, 90, 156, 200, 111, 201, 80, 177, 118,
+    213, 145, 75, 225, 221, 224, 65, 74, 108, 24, 27, 67,
+]);
+
+/// Whether a PDA can be derived from `seeds`, with room left for the bump seed
+/// `Pubkey::find_program_address` appends: no seed longer than `MAX_SEED_LEN`, and fewer than `MAX_SEEDS` of them.
+pub const fn seeds_fit(seeds: &[&[u8]]) -> bool {
+    if seeds.len() >= MAX_SEEDS {
+        return false;
+    }
+    let mut i = 0;
+    while i < seeds.len() {
+        if seeds[i].len() > MAX_SEED_LEN {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// A seed above grown past what the runtime takes fails the build, rather than every derivation from it.
+// `KEY` stands in for the keys following a seed.
+const KEY: &[u8] = &[0; 32];
+const _: () = assert!(seeds_fit(&[ESCROW_SEED, KEY]));
+const _: () = assert!(seeds_fit(&[BASELINE_ESCROW_SEED]));
+const _: () = assert!(seeds_fit(&[ESCROW_STATE_SEED, KEY, KEY]));
+const _: () = assert!(seeds_fit(&[TEMP_TOKEN_ACCOUNT_SEED, KEY]));
+const _: () = assert!(seeds_fit(&[FEE_VAULT_SEED]));
+const _: () = assert!(seeds_fit(&[PROGRAM_CONFIG_SEED]));
@@ -1,16 +1,76 @@
-use solana_program::program_error::ProgramError;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use solana_program::{
+    decode_error::DecodeError,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+};
 use thiserror::Error;
 
-#[derive(Error, Debug, Copy, Clone)]
+// Clients decode these codes, so a variant keeps its value forever and new ones are appended.
+// `FromPrimitive` turns a `ProgramError::Custom` code back into its variant.
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
 pub enum EscrowError {
     #[error("Invalid Instruction")]
-    InvalidInstruction,
+    InvalidInstruction = 0,
     #[error("Not Rent Exempt")]
-    NotRentExempt,
+    NotRentExempt = 1,
     #[error("Amount Overflow")]
-    AmountOverflow,
+    AmountOverflow = 2,
     #[error("Expected Amount Mismatch")]
-    ExpectedAmountMismatch,
+    ExpectedAmountMismatch = 3,
+    #[error("Initializer Mismatch")]
+    InitializerMismatch = 4,
+    #[error("Invalid Owner")]
+    InvalidOwner = 5,
+    #[error("Escrow Expired")]
+    EscrowExpired = 6,
+    #[error("Mint Mismatch")]
+    MintMismatch = 7,
+    #[error("Insufficient Funds")]
+    InsufficientFunds = 8,
+    #[error("Account Data Too Small")]
+    AccountDataTooSmall = 9,
+    #[error("Invalid Amount")]
+    InvalidAmount = 10,
+    #[error("Self Exchange Not Allowed")]
+    SelfExchangeNotAllowed = 11,
+    #[error("Lockup Active")]
+    LockupActive = 12,
+    #[error("Unaccepted Mint")]
+    UnacceptedMint = 13,
+    #[error("Decimals Mismatch")]
+    DecimalsMismatch = 14,
+    #[error("Unsupported State Version")]
+    UnsupportedStateVersion = 15,
+    #[error("Amount Too Large")]
+    AmountTooLarge = 16,
+    #[error("Invalid State")]
+    InvalidState = 17,
+    #[error("Escrow Not Expired")]
+    EscrowNotExpired = 18,
+    #[error("Slippage Exceeded")]
+    SlippageExceeded = 19,
+    #[error("Exchange Not Prepared")]
+    ExchangeNotPrepared = 20,
+    #[error("Account Frozen")]
+    AccountFrozen = 21,
+    #[error("Unauthorized Taker")]
+    UnauthorizedTaker = 22,
+    #[error("In Grace Period")]
+    InGracePeriod = 23,
+    #[error("Token Program Mismatch")]
+    TokenProgramMismatch = 24,
+    #[error("Receive Account Uninitialized")]
+    ReceiveAccountUninitialized = 25,
+    #[error("Exchanges Paused")]
+    ExchangesPaused = 26,
+    #[error("Seed Too Long")]
+    SeedTooLong = 27,
+    #[error("Duration Too Short")]
+    DurationTooShort = 28,
+    #[error("Memo Too Long")]
+    MemoTooLong = 29,
 }
 
 // By implementing From trait, we can convert EscrowError to ProgramError when using `?`
@@ -19,3 +79,90 @@ impl From<EscrowError> for ProgramError {
         ProgramError::Custom(e as u32)
     }
 }
+
+impl EscrowError {
+    /// Logs the error by name before handing it to the runtime, which only ever shows its code
+    pub fn log_and_convert(self) -> ProgramError {
+        msg!("{}", self);
+        self.into()
+    }
+}
+
+impl<T> DecodeError<T> for EscrowError {
+    fn type_of() -> &'static str {
+        "EscrowError"
+    }
+}
+
+impl PrintProgramError for EscrowError {
+    fn print<E>(&self)
+    where
+        E: 'static + std::error::Error + DecodeError<E> + PrintProgramError + FromPrimitive,
+    {
+        msg!("Escrow error: {}", self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let codes = [
+            (EscrowError::InvalidInstruction, 0),
+            (EscrowError::NotRentExempt, 1),
+            (EscrowError::AmountOverflow, 2),
+            (EscrowError::ExpectedAmountMismatch, 3),
+            (EscrowError::InitializerMismatch, 4),
+            (EscrowError::InvalidOwner, 5),
+            (EscrowError::EscrowExpired, 6),
+            (EscrowError::MintMismatch, 7),
+            (EscrowError::InsufficientFunds, 8),
+            (EscrowError::AccountDataTooSmall, 9),
+            (EscrowError::InvalidAmount, 10),
+            (EscrowError::SelfExchangeNotAllowed, 11),
+            (EscrowError::LockupActive, 12),
+            (EscrowError::UnacceptedMint, 13),
+            (EscrowError::DecimalsMismatch, 14),
+            (EscrowError::UnsupportedStateVersion, 15),
+            (EscrowError::AmountTooLarge, 16),
+            (EscrowError::InvalidState, 17),
+            (EscrowError::EscrowNotExpired, 18),
+            (EscrowError::SlippageExceeded, 19),
+            (EscrowError::ExchangeNotPrepared, 20),
+            (EscrowError::AccountFrozen, 21),
+            (EscrowError::UnauthorizedTaker, 22),
+            (EscrowError::InGracePeriod, 23),
+            (EscrowError::TokenProgramMismatch, 24),
+            (EscrowError::ReceiveAccountUninitialized, 25),
+            (EscrowError::ExchangesPaused, 26),
+            (EscrowError::SeedTooLong, 27),
+            (EscrowError::DurationTooShort, 28),
+            (EscrowError::MemoTooLong, 29),
+        ];
+
+        for (error, code) in codes {
+            assert_eq!(ProgramError::from(error), ProgramError::Custom(code));
+        }
+    }
+
+    #[test]
+    fn test_from_u32_round_trip() {
+        for code in 0..=EscrowError::MemoTooLong as u32 {
+            let error = EscrowError::from_u32(code).unwrap();
+            assert_eq!(error as u32, code);
+            assert_eq!(EscrowError::from_u64(code as u64), Some(error));
+        }
+
+        assert_eq!(
+            EscrowError::from_u32(EscrowError::MemoTooLong as u32 + 1),
+            None
+        );
+        assert_eq!(EscrowError::from_i64(-1), None);
+        assert_eq!(
+            <EscrowError as DecodeError<EscrowError>>::type_of(),
+            "EscrowError"
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{entrypoint::ProgramResult, log::sol_log_data, pubkey::Pubkey};
+
+// Events are emitted with `sol_log_data`, so they show up in the transaction logs
+// as `Program data: <base64>` lines that indexers can decode.
+//
+// Every event is Borsh-serialized:
+// - 1 byte: the variant index (0 = Initialized, 1 = Exchanged, 2 = AmountsTransferred)
+// - then each field in declaration order, pubkeys and the reference as 32 raw bytes and u64 in little endian
+//
+// Initialized: [0] escrow(32) initializer(32) amount(8) offered_amount(8) reference(32) -> 113 bytes
+// Exchanged:   [1] escrow(32) taker(32)       amount(8)                    reference(32) -> 105 bytes
+// AmountsTransferred: [2] escrow(32) token_x_amount(8) token_y_amount(8)                   -> 49 bytes
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub enum EscrowEvent {
+    /// An escrow was opened, asking for `amount` of token Y
+    Initialized {
+        escrow: Pubkey,
+        initializer: Pubkey,
+        amount: u64,
+        /// The token X, or lamports, locked in the escrow
+        offered_amount: u64,
+        /// The escrow's off-chain reference, as opaque bytes
+        reference: [u8; 32],
+    },
+    /// A taker took `amount` of token X out of an escrow
+    Exchanged {
+        escrow: Pubkey,
+        taker: Pubkey,
+        amount: u64,
+        /// The escrow's off-chain reference, as opaque bytes
+        reference: [u8; 32],
+    },
+    /// What an exchange finally moved, once every transfer went through
+    AmountsTransferred {
+        escrow: Pubkey,
+        /// The token X the taker got, less the taker fee
+        token_x_amount: u64,
+        /// The token Y, or lamports, the initializer got
+        token_y_amount: u64,
+    },
+}
+
+impl EscrowEvent {
+    pub fn emit(&self) -> ProgramResult {
+        sol_log_data(&[&self.try_to_vec()?]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_layout() {
+        let escrow = Pubkey::new_unique();
+        let taker = Pubkey::new_unique();
+        let event = EscrowEvent::Exchanged {
+            escrow,
+            taker,
+            amount: 42,
+            reference: [7; 32],
+        };
+
+        let data = event.try_to_vec().unwrap();
+
+        // Decode it the way an off-chain consumer would, following the documented layout
+        assert_eq!(data.len(), 105);
+        assert_eq!(data[0], 1);
+        assert_eq!(&data[1..33], escrow.as_ref());
+        assert_eq!(&data[33..65], taker.as_ref());
+        assert_eq!(u64::from_le_bytes(data[65..73].try_into().unwrap()), 42);
+        assert_eq!(data[73..105], [7; 32]);
+        assert_eq!(EscrowEvent::try_from_slice(&data).unwrap(), event);
+    }
+
+    #[test]
+    fn test_initialized_event_layout() {
+        let escrow = Pubkey::new_unique();
+        let initializer = Pubkey::new_unique();
+        let event = EscrowEvent::Initialized {
+            escrow,
+            initializer,
+            amount: 20,
+            offered_amount: 10,
+            reference: [7; 32],
+        };
+
+        let data = event.try_to_vec().unwrap();
+
+        assert_eq!(data.len(), 113);
+        assert_eq!(data[0], 0);
+        assert_eq!(&data[1..33], escrow.as_ref());
+        assert_eq!(&data[33..65], initializer.as_ref());
+        assert_eq!(u64::from_le_bytes(data[65..73].try_into().unwrap()), 20);
+        assert_eq!(u64::from_le_bytes(data[73..81].try_into().unwrap()), 10);
+        assert_eq!(data[81..113], [7; 32]);
+        assert_eq!(EscrowEvent::try_from_slice(&data).unwrap(), event);
+    }
+
+    #[test]
+    fn test_amounts_transferred_event_layout() {
+        let escrow = Pubkey::new_unique();
+        let event = EscrowEvent::AmountsTransferred {
+            escrow,
+            token_x_amount: 10,
+            token_y_amount: 20,
+        };
+
+        let data = event.try_to_vec().unwrap();
+
+        assert_eq!(data.len(), 49);
+        assert_eq!(data[0], 2);
+        assert_eq!(&data[1..33], escrow.as_ref());
+        assert_eq!(u64::from_le_bytes(data[33..41].try_into().unwrap()), 10);
+        assert_eq!(u64::from_le_bytes(data[41..49].try_into().unwrap()), 20);
+        assert_eq!(EscrowEvent::try_from_slice(&data).unwrap(), event);
+    }
+}
@@ -1,7 +1,134 @@
-use crate::error::EscrowError::InvalidInstruction;
-use {solana_program::program_error::ProgramError, std::convert::TryInto};
+use crate::{
+    config,
+    error::EscrowError::{self, InvalidInstruction},
+    memo, token,
+};
+use {
+    solana_program::{
+        instruction::{AccountMeta, Instruction},
+        program_error::ProgramError,
+        pubkey::Pubkey,
+        system_program, sysvar,
+    },
+    std::convert::TryInto,
+};
+
+/// The terms every instruction starting a trade takes, packed in this order right after the tag
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InitEscrowParams {
+    /// The amount party A expects to receive of token Y
+    pub amount: u64,
+    /// The unix timestamp after which the trade can no longer be taken
+    pub expire_at: i64,
+    /// The lamports taken from the escrow account as a protocol fee once the trade goes through
+    pub fee_lamports: u64,
+    /// The account receiving the protocol fee
+    pub fee_account: Pubkey,
+    /// The unix timestamp before which the initializer can't cancel the trade
+    pub not_before: i64,
+    /// The token Y mints the taker may pay with, empty slots as `Pubkey::default()`.
+    /// All empty means only the mint of the initializer's token Y account is accepted.
+    pub accepted_mints: [Pubkey; 3],
+    /// Whether the initializer is paid `amount` lamports rather than token Y
+    pub accept_native: bool,
+    /// The cut of the token X taken that goes to `fee_token_account` rather than the taker, in basis points
+    pub taker_fee_bps: u16,
+    /// The token X account receiving the taker fee
+    pub fee_token_account: Pubkey,
+    /// An off-chain reference to tag the trade with, kept as opaque bytes
+    pub reference: [u8; 32],
+    /// The token X left after a fill at or below which the escrow settles, sweeping it back to the initializer
+    pub dust_threshold: u64,
+    /// The only taker who may fill the escrow, `Pubkey::default()` to let anyone
+    pub allowed_taker: Pubkey,
+    /// How many seconds after `expire_at` only the initializer may close the escrow, before anyone may reap it
+    pub grace_seconds: i64,
+}
+
+impl InitEscrowParams {
+    fn unpack(reader: &mut InstructionReader) -> Result<Self, ProgramError> {
+        Ok(Self {
+            amount: reader.read_u64()?,
+            expire_at: reader.read_i64()?,
+            fee_lamports: reader.read_u64()?,
+            fee_account: reader.read_pubkey()?,
+            not_before: reader.read_i64()?,
+            accepted_mints: [
+                reader.read_pubkey()?,
+                reader.read_pubkey()?,
+                reader.read_pubkey()?,
+            ],
+            accept_native: reader.read_bool()?,
+            taker_fee_bps: reader.read_u16()?,
+            fee_token_account: reader.read_pubkey()?,
+            reference: reader.read_bytes()?,
+            dust_threshold: reader.read_u64()?,
+            allowed_taker: reader.read_pubkey()?,
+            grace_seconds: reader.read_i64()?,
+        })
+    }
+
+    fn pack_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.amount.to_le_bytes());
+        buf.extend_from_slice(&self.expire_at.to_le_bytes());
+        buf.extend_from_slice(&self.fee_lamports.to_le_bytes());
+        buf.extend_from_slice(self.fee_account.as_ref());
+        buf.extend_from_slice(&self.not_before.to_le_bytes());
+        for mint in &self.accepted_mints {
+            buf.extend_from_slice(mint.as_ref());
+        }
+        buf.push(self.accept_native as u8);
+        buf.extend_from_slice(&self.taker_fee_bps.to_le_bytes());
+        buf.extend_from_slice(self.fee_token_account.as_ref());
+        buf.extend_from_slice(&self.reference);
+        buf.extend_from_slice(&self.dust_threshold.to_le_bytes());
+        buf.extend_from_slice(self.allowed_taker.as_ref());
+        buf.extend_from_slice(&self.grace_seconds.to_le_bytes());
+    }
+}
+
+/// The accounts of an `Exchange`, which `EscrowInstruction::exchange` puts in the order `process_exchange` reads them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExchangeAccounts {
+    /// The account of the person taking the trade
+    pub taker: Pubkey,
+    /// The taker's token account for the token they send
+    pub takers_sending_token_account: Pubkey,
+    /// The taker's token account for the token they will receive
+    pub takers_token_to_receive_account: Pubkey,
+    /// The PDA's temp token account, or the initializer's own token account for a delegated escrow
+    pub pdas_temp_token_account: Pubkey,
+    /// The initializer's main account
+    pub initializers_main_account: Pubkey,
+    /// The initializer's token account that will receive tokens
+    pub initializers_token_to_receive_account: Pubkey,
+    /// The mint of the initializer's token account that will receive tokens
+    pub initializers_token_to_receive_mint: Pubkey,
+    /// The escrow account holding the escrow info
+    pub escrow_account: Pubkey,
+    /// The token program the escrow was initialized with
+    pub token_program: Pubkey,
+    /// The escrow's PDA
+    pub pda: Pubkey,
+    /// The mint of the PDA's temp token account
+    pub pdas_temp_token_mint: Pubkey,
+    /// Whether the taker pays to create the initializer's associated token account again,
+    /// which must be the one the escrow pays into
+    pub create_initializers_token_to_receive_account: bool,
+    /// The token account receiving the taker fee, when the escrow charges one
+    pub fee_token_account: Option<Pubkey>,
+    /// Every basket temp account, along with the taker's account for its token and its mint
+    pub basket_accounts: Vec<(Pubkey, Pubkey, Pubkey)>,
+    /// The initializer's token X account, when a custodial escrow has a dust threshold
+    pub initializers_token_x_account: Option<Pubkey>,
+    /// The fee account, when the escrow charges a protocol fee
+    pub fee_account: Option<Pubkey>,
+    /// The account that paid the escrow's rent, when it isn't the initializer
+    pub rent_payer: Option<Pubkey>,
+}
 
 // Defines the "API" of a program
+#[derive(Clone, Debug, PartialEq)]
 pub enum EscrowInstruction {
     /// Starts the trade by creating and populating an escrow account and transferring ownership of the given temp token account to the PDA
     ///
@@ -12,11 +139,15 @@ pub enum EscrowInstruction {
     /// 1. `[writable]` Temporary token account that should be created prior to this instruction and owned by the initializer
     /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
     /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 4. `[]` The rent sysvar
-    /// 5. `[]` The token program
+    /// 4. `[]` The token program, either SPL Token or Token-2022, owning both token accounts
+    /// 5. `[]` The mint of the initializer's token account for the token they will receive
+    /// 6. `[]` The account that paid the escrow account's rent and gets it back once the trade goes through.
+    ///    Optional, the initializer's main account gets it otherwise.
+    ///
+    /// With `accept_native`, account 2 is the initializer's main account and account 5 is left out.
     InitEscrow {
-        /// The amount party A expects to receive of token Y
-        amount: u64,
+        /// The terms of the trade
+        params: InitEscrowParams,
     },
     /// Accepts a trade
     ///
@@ -30,38 +161,1464 @@ pub enum EscrowInstruction {
     /// 4. `[writable]` The initializer's main account to send their rent fees to
     /// 5. `[writable]` The initializer's token account that will receive tokens
     /// 6. `[writable]` The escrow account holding the escrow info
-    /// 7. `[]` The token program
+    /// 7. `[]` The token program the escrow was initialized with
     /// 8. `[]` The PDA account
+    /// 9. `[]` The mint of the PDA's temp token account
+    /// 10. `[]` The mint of the initializer's token account that will receive tokens
+    /// 11. `[]` The system program, only when the initializer's receiving account is an associated token
+    ///     account that no longer exists. The taker then creates it again and must be writable to pay for it.
+    /// 12. `[]` The rent sysvar, along with the system program
+    /// 13. `[]` The associated token account program, along with the system program
+    /// 14. `[writable]` The token account receiving the taker fee, only when the escrow charges one
+    /// 15. `[writable]` The initializer's token account for the token they offered, to sweep dust back into.
+    ///     Only for a custodial escrow with a dust threshold.
+    /// 16. `[writable]` The fee account, only when the escrow charges a protocol fee
+    /// 17. `[writable]` The escrow's rent payer, only when it isn't the initializer
+    /// 18. `[writable]` The fee vault paying the maker rebate, only when built with `maker-rebate`
+    /// 19. `[]` The system program, along with the fee vault
+    /// 20. `[]` The program config. Optional, but exchanges can only be paused for clients passing it.
+    /// 21. `[]` The memo program, last, only with a memo
+    ///
+    /// Accounts left out move the ones after them up.
+    /// An escrow with a basket takes, right after the taker fee account, three accounts
+    /// for every basket temp account, in the order `AddToBasket` added them:
+    /// `[writable]` the PDA's basket temp token account to get all tokens from and close,
+    /// then `[writable]` the taker's token account for the token in it, then `[]` its mint.
+    ///
+    /// When the escrow accepts native SOL, the taker pays from their main account instead:
+    ///
+    /// 0. `[signer, writable]` The account of the person taking the trade, paying the lamports
+    /// 1. `[]` The system program
+    /// 2. - 9. Same as above, with the initializer's main account as account 5
+    /// 10. - 17. Same as accounts 14 - 21 above
     Exchange {
         /// the amount the taker expects to be paid in the other token, as a u64 because that's the max possible supply of a token
         amount: u64,
+        /// the least the taker accepts to be paid, in case the escrow shrank since they looked at it
+        min_amount_out: u64,
+        /// A note the memo program records about the trade, signed by the taker, UTF-8 as it wants it.
+        /// Runs to the end of the data, so none at all leaves the instruction as it always was.
+        /// With one, the memo program comes after every other account.
+        /// At most `EscrowInstruction::MAX_MEMO_LEN` bytes.
+        memo: Vec<u8>,
+    },
+    /// Cancels a trade, returning the tokens in the temp token account to the initializer
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The PDA's temp token account to get tokens from and eventually close
+    /// 2. `[writable]` The initializer's token account that will receive the refunded tokens
+    /// 3. `[writable]` The escrow account holding the escrow info
+    /// 4. `[]` The token program
+    /// 5. `[]` The PDA account
+    /// 6. `[]` The mint of the PDA's temp token account, only for an escrow holding it in custody
+    /// 7. `[]` The Clock sysvar, optional and told apart by its key
+    ///
+    /// Accounts left out move the ones after them up. They are
+    /// followed by the PDA's basket temp token accounts, `[writable]`, in the order `AddToBasket` added them.
+    /// Their ownership goes back to the initializer, tokens and all.
+    /// Last comes the escrow's rent payer, `[writable]`, only when it isn't the initializer.
+    ///
+    /// For an escrow made with `InitEscrowNative`, account 1 is the PDA account holding the lamports,
+    /// which go back to the initializer's main account, and account 2 is the system program.
+    CancelEscrow,
+    /// Accepts a part of a trade, leaving the rest of it open for other takers
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `Exchange`
+    PartialExchange {
+        /// the amount of the other token the taker takes, which must be paid for with an exact share of the expected amount
+        amount: u64,
+    },
+    /// Changes the amount the initializer expects to receive, without cancelling the trade
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    UpdateExpectedAmount {
+        /// The new amount party A expects to receive of token Y
+        new_amount: u64,
+    },
+    /// Logs every field of the escrow state with `sol_log_data`, one field per data slice in the packed order
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[]` The escrow account holding the escrow info
+    GetEscrowInfo,
+    /// Hands the escrow over to a new initializer, who gets paid and may cancel from then on
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    /// 2. `[]` The new initializer's token account for the token they will receive, only with `update_receive_account`.
+    ///    Their main account instead when the escrow accepts native SOL.
+    TransferInitializer {
+        /// The account taking over the escrow
+        new_initializer: Pubkey,
+        /// Whether the tokens should be received into the new initializer's account too,
+        /// rather than into the one the escrow was initialized with
+        update_receive_account: bool,
+    },
+    /// Adds more of the offered token to the temp token account of an open escrow.
+    /// The expected amount is left as is, use `UpdateExpectedAmount` to reprice.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The initializer's token account to take the tokens from
+    /// 2. `[writable]` The PDA's temp token account to put the tokens into
+    /// 3. `[writable]` The escrow account holding the escrow info
+    /// 4. `[]` The token program
+    /// 5. `[]` The mint of the PDA's temp token account
+    Deposit {
+        /// The amount of the offered token to add
+        amount: u64,
+    },
+    /// Starts a trade like `InitEscrow`, but without taking custody of the offered tokens:
+    /// they stay in the initializer's own token account, with the PDA approved as a delegate
+    /// for the offered amount. Cancelling revokes the delegation, as does settling with dust left
+    /// on Token-2022, which lets the PDA revoke its own approval.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `InitEscrow`, except for
+    /// 1. `[writable]` The initializer's token account holding the offered tokens
+    /// 6. `[]` The PDA account, approved as the delegate. Account 5 with `accept_native`.
+    ///    The optional rent payer comes after it.
+    InitEscrowDelegated {
+        /// The terms of the trade
+        params: InitEscrowParams,
+        /// The amount of token X the PDA may move out of the initializer's account
+        offered_amount: u64,
+    },
+    /// Returns the tokens and the rent of a token account that was handed over to an escrow's PDA
+    /// but that no escrow state refers to, then closes it.
+    ///
+    /// The token program keeps no trace of an account's previous owner, so the claim is proven
+    /// through the escrow account the PDA is derived from instead:
+    /// - if it holds an escrow state, the initializer stored in it must sign,
+    ///   and the state must refer to other temp accounts, its basket included (or else `CancelEscrow` is the way out);
+    /// - if it holds none, its own key must sign, which only whoever created it can do.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who set up the escrow, receiving the rent
+    /// 1. `[writable]` The orphaned token account, owned by the PDA
+    /// 2. `[writable]` The initializer's token account that will receive the tokens left in it
+    /// 3. `[]` The escrow account the PDA is derived from, `[signer]` when it holds no escrow state
+    /// 4. `[]` The token program owning the orphaned token account
+    /// 5. `[]` The PDA account
+    /// 6. `[]` The mint of the orphaned token account
+    ReclaimOrphan,
+    /// Closes any number of expired escrows at once, giving every initializer back
+    /// the tokens left in their temp account and its rent, and the escrow's rent to whoever paid it.
+    /// Anyone may crank it once an escrow's grace period is over, until then only its initializer
+    /// may cancel it. Delegated escrows and escrows with a basket can't be reaped, their initializers cancel them.
+    /// Escrows that can't be reaped, or are already gone, are skipped. Only a batch reaping none of them fails.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[]` The keeper cranking the reaping, who may sign but needn't
+    /// 1. `[]` The token program every reaped escrow was initialized with
+    ///
+    /// Then, for every escrow to reap:
+    ///
+    /// 0. `[writable]` The escrow account holding the escrow info
+    /// 1. `[writable]` The PDA's temp token account to get tokens from and eventually close
+    /// 2. `[]` The PDA account
+    /// 3. `[writable]` The initializer's main account to send their rent fees to
+    /// 4. `[writable]` The initializer's token account that will receive the refunded tokens
+    /// 5. `[writable]` The escrow's rent payer, the initializer's main account again when they paid it
+    /// 6. `[]` The mint of the PDA's temp token account
+    ReapExpired,
+    /// Locks an open escrow for the signing taker until `config::PREPARED_EXCHANGE_DURATION` from now,
+    /// leaving them time to gather the signatures `FinalizeExchange` needs, e.g. of a multisig.
+    /// Nobody else may take or reprice the escrow until then, though its initializer may still cancel it.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person taking the trade
+    /// 1. `[writable]` The escrow account holding the escrow info
+    /// 2. `[]` The Clock sysvar, optional
+    PrepareExchange,
+    /// Takes the whole of an escrow the signing taker prepared, before the preparation lapses
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `Exchange`
+    FinalizeExchange {
+        /// the amount the taker expects to be paid in the other token
+        amount: u64,
+    },
+    /// Moves part of an open escrow into a new one, so that each part can be priced and taken on its own.
+    /// The new escrow starts as a copy of the original, asking for the matching share of its expected amount.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    /// 2. `[writable]` The PDA's temp token account to take the tokens from
+    /// 3. `[]` The PDA account
+    /// 4. `[writable]` The new escrow account, created prior to this instruction, rent exempt and empty
+    /// 5. `[writable]` The new temp token account, created prior to this instruction and owned by the initializer,
+    ///    empty and without a delegate or close authority
+    /// 6. `[]` The token program the escrow was initialized with
+    /// 7. `[]` The mint of both temp token accounts
+    SplitEscrow {
+        /// The amount of the offered token to move into the new escrow
+        amount: u64,
+        /// The bump seed of the new escrow's PDA, which must be the canonical one
+        new_escrow_bump: u8,
+    },
+    /// Runs every check an `Exchange` would, without moving or writing anything,
+    /// so a front-end can tell whether it would go through before asking the taker to sign.
+    /// Logs the outcome with `sol_log_data` as a little endian u64: 0 when it would go through,
+    /// or else the code of the error, which the instruction fails with too.
+    /// The fee account and rent payer are only checked once an exchange closes the escrow, not here.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `Exchange`, except that the taker doesn't need to sign
+    SimulateExchange {
+        /// the amount the taker expects to be paid in the other token
+        amount: u64,
+    },
+    /// Points the escrow at another token account for the initializer to be paid in,
+    /// which must hold one of the mints the escrow accepts. Without an allow-list,
+    /// it must hold the same mint as the account the initializer is paid in now.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    /// 2. `[]` The new token account for the token the initializer will receive
+    /// 3. `[]` The token account the initializer is paid in now
+    SetReceiveAccount,
+    /// Starts a trade like `InitEscrow`, but creates the escrow account itself, at the address derived from
+    /// `config::ESCROW_STATE_SEED`, the initializer and the temp token account, funded by the initializer.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `InitEscrow`, without the optional rent payer, except for
+    /// 0. `[writable, signer]` The account of the person initializing the escrow, paying the escrow account's rent
+    /// 3. `[writable]` The escrow account to create, at its derived address
+    ///
+    /// followed by
+    /// 6. `[]` The system program. Account 5 with `accept_native`.
+    InitEscrowPda {
+        /// The terms of the trade
+        params: InitEscrowParams,
+    },
+    /// Grows an escrow account still in an outdated state layout to the current one,
+    /// giving the fields added since their defaults. The bigger account must stay rent exempt.
+    /// It must be the size `Escrow::OUTDATED_LENS` records for its version, or it fails with `UnsupportedStateVersion`.
+    /// A baseline account, from before the version byte, is told apart by `Escrow::BASELINE_LEN`.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the person who initialized the escrow, or the program's upgrade authority
+    /// 1. `[writable]` The escrow account holding the outdated escrow info
+    /// 2. `[]` The program's data account, only when the upgrade authority signs
+    ///
+    /// optionally followed by
+    /// 3. `[]` The system program, for the signer to pay the rent the bigger account lacks.
+    ///    Account 2 when the initializer signs. Without it, the escrow account must already hold enough.
+    ///
+    /// A baseline account needs the system program, followed by
+    /// 4. `[writable]` The temp token account, handed over from the PDA at `config::BASELINE_ESCROW_SEED` to the escrow's own
+    /// 5. `[]` The initializer's token account for the token they will receive
+    /// 6. `[]` The mint of that account
+    /// 7. `[]` The legacy token program
+    /// 8. `[]` The PDA at `config::BASELINE_ESCROW_SEED`
+    ///
+    /// Each one is an account earlier when the initializer signs.
+    MigrateState,
+    /// Starts a trade where the initializer offers lamports rather than token X.
+    /// The escrow's PDA holds them as a plain system account until the trade is taken or cancelled.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable, signer]` The account of the person initializing the escrow, paying the offered lamports
+    /// 1. `[writable]` The PDA account, derived from `config::ESCROW_SEED` and the escrow account, to hold the lamports
+    /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
+    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
+    /// 4. `[]` The token program owning the initializer's token account
+    /// 5. `[]` The mint of the initializer's token account for the token they will receive
+    /// 6. `[]` The system program
+    /// 7. `[]` The account that paid the escrow account's rent and gets it back once the trade goes through.
+    ///    Optional, the initializer's main account gets it otherwise.
+    InitEscrowNative {
+        /// The terms of the trade. `accept_native`, `taker_fee_bps`, `fee_token_account` and `dust_threshold`
+        /// don't apply to offered lamports and must be left at their defaults.
+        params: InitEscrowParams,
+        /// The lamports the initializer offers, moved to the PDA
+        offered_lamports: u64,
+    },
+    /// Takes a trade made with `InitEscrowNative`: the taker pays token Y and gets all of the offered lamports
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person taking the trade, receiving the lamports
+    /// 1. `[writable]` The taker's token account for the token they send
+    /// 2. `[writable]` The PDA account holding the offered lamports
+    /// 3. `[writable]` The initializer's main account to send their rent fees to
+    /// 4. `[writable]` The initializer's token account that will receive tokens
+    /// 5. `[writable]` The escrow account holding the escrow info
+    /// 6. `[]` The token program the escrow was initialized with
+    /// 7. `[]` The mint of the initializer's token account that will receive tokens
+    /// 8. `[]` The system program
+    /// 9. `[writable]` The fee account, only when the escrow charges a protocol fee
+    /// 10. `[writable]` The escrow's rent payer, only when it isn't the initializer
+    /// 11. `[writable]` The fee vault paying the maker rebate, only when built with `maker-rebate`
+    /// 12. `[]` The system program, along with the fee vault
+    /// 13. `[]` The program config, last. Optional, but exchanges can only be paused for clients passing it.
+    ///
+    /// Accounts left out move the ones after them up.
+    ExchangeNativeOffer {
+        /// the lamports the taker expects to be paid, which must be all of them
+        amount: u64,
+    },
+    /// Pushes back the time after which the trade can no longer be taken, without cancelling it
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    ExtendExpiry {
+        /// The new unix timestamp after which the trade can no longer be taken, later than the current one
+        new_expire_at: i64,
+    },
+    /// Pauses or resumes every exchange at once, for emergencies. Only `config::ADMIN_PUBKEY` may.
+    /// The program config is created the first time, paid for by the admin.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The admin
+    /// 1. `[writable]` The program config, the PDA at `config::PROGRAM_CONFIG_SEED`
+    /// 2. `[]` The system program
+    ToggleExchanges {
+        /// Whether exchanges are turned away from now on
+        paused: bool,
+    },
+    /// Bundles another token account with an open custodial escrow, handing it over to the PDA.
+    /// A taker gets all of every bundled token along with the main one, in the same exchange,
+    /// so an escrow with a basket can only be taken whole and can't be split.
+    /// It holds up to `Escrow::MAX_BASKET_LEN` of them.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    /// 2. `[writable]` The token account to bundle, created prior to this instruction, funded and owned by the initializer
+    /// 3. `[]` The token program the escrow was initialized with
+    AddToBasket,
+    /// Logs with `sol_log_data`, as a little endian u64, the lamports it takes to keep every account
+    /// of an escrow of the given configuration rent exempt: the escrow account, and the token accounts
+    /// the PDA takes custody of, sized as SPL Token ones. Token-2022 extensions need more on top.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// None
+    GetRent {
+        /// `RENT_TEMP_TOKEN_ACCOUNT` for an escrow holding Token X in a temp account, as `InitEscrow` makes,
+        /// plus the number of basket accounts bundled with it in the `RENT_BASKET_LEN_MASK` bits
+        config_flags: u8,
+    },
+    /// Starts a trade like `InitEscrow`, but creates the temp token account itself, at the address derived
+    /// from `config::TEMP_TOKEN_ACCOUNT_SEED` and the escrow account, and moves the offered tokens into it
+    /// from the initializer's own token account before handing it over to the PDA.
+    /// The temp token account gets room for whatever extensions a Token-2022 mint requires of its accounts.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `InitEscrow`, except for
+    /// 0. `[writable, signer]` The account of the person initializing the escrow, paying the temp token account's rent
+    /// 1. `[writable]` The temp token account to create, at its derived address
+    ///
+    /// followed by, after the optional rent payer,
+    /// 7. `[writable]` The initializer's token account holding the offered tokens
+    /// 8. `[]` The mint of the offered tokens
+    /// 9. `[]` The system program
+    /// 10. `[]` The rent sysvar
+    ///
+    /// Accounts left out move the ones after them up.
+    InitEscrowManaged {
+        /// The terms of the trade
+        params: InitEscrowParams,
+        /// The amount of token X moved into the new temp token account
+        offered_amount: u64,
     },
 }
 
 impl EscrowInstruction {
+    /// `GetRent` flag for an escrow whose Token X waits in a temp token account under the PDA
+    pub const RENT_TEMP_TOKEN_ACCOUNT: u8 = 0b001;
+    /// `GetRent` bits holding how many basket accounts come along with the temp token account
+    pub const RENT_BASKET_LEN_MASK: u8 = 0b110;
+    /// The longest memo an `Exchange` carries. A transaction is at most 1232 bytes,
+    /// and this still leaves room for an exchange passing every optional account.
+    pub const MAX_MEMO_LEN: usize = 128;
+
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
         // the first byte of the input is 'tag', which determines how to decode the rest(input from index 1 to the end).
         let (tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
+        // the rest is read field after field, in the order `pack` writes them
+        let mut reader = InstructionReader::new(rest);
 
-        Ok(match tag {
+        let instruction = match tag {
             0 => Self::InitEscrow {
-                amount: Self::unpack_amount(rest)?,
+                params: InitEscrowParams::unpack(&mut reader)?,
             },
             1 => Self::Exchange {
-                amount: Self::unpack_amount(rest)?,
+                amount: reader.read_u64()?,
+                min_amount_out: reader.read_u64()?,
+                memo: {
+                    let memo = reader.read_rest();
+                    if memo.len() > Self::MAX_MEMO_LEN {
+                        return Err(InvalidInstruction.into());
+                    }
+                    memo
+                },
             },
-            _ => return Err(InvalidInstruction.into()),
-        })
+            2 => Self::CancelEscrow,
+            3 => Self::PartialExchange {
+                amount: reader.read_u64()?,
+            },
+            4 => Self::UpdateExpectedAmount {
+                new_amount: reader.read_u64()?,
+            },
+            5 => Self::GetEscrowInfo,
+            6 => Self::TransferInitializer {
+                new_initializer: reader.read_pubkey()?,
+                update_receive_account: reader.read_bool()?,
+            },
+            7 => Self::Deposit {
+                amount: reader.read_u64()?,
+            },
+            8 => Self::InitEscrowDelegated {
+                params: InitEscrowParams::unpack(&mut reader)?,
+                offered_amount: reader.read_u64()?,
+            },
+            9 => Self::ReclaimOrphan,
+            10 => Self::ReapExpired,
+            11 => Self::PrepareExchange,
+            12 => Self::FinalizeExchange {
+                amount: reader.read_u64()?,
+            },
+            13 => Self::SplitEscrow {
+                amount: reader.read_u64()?,
+                new_escrow_bump: reader.read_u8()?,
+            },
+            14 => Self::SimulateExchange {
+                amount: reader.read_u64()?,
+            },
+            15 => Self::SetReceiveAccount,
+            16 => Self::InitEscrowPda {
+                params: InitEscrowParams::unpack(&mut reader)?,
+            },
+            17 => Self::MigrateState,
+            18 => Self::InitEscrowNative {
+                params: InitEscrowParams::unpack(&mut reader)?,
+                offered_lamports: reader.read_u64()?,
+            },
+            19 => Self::ExchangeNativeOffer {
+                amount: reader.read_u64()?,
+            },
+            20 => Self::ExtendExpiry {
+                new_expire_at: reader.read_i64()?,
+            },
+            // Was `ExchangeWithMemo`, before `Exchange` took the memo. Never handed out again.
+            21 => return Err(InvalidInstruction.into()),
+            22 => Self::ToggleExchanges {
+                paused: reader.read_bool()?,
+            },
+            23 => Self::AddToBasket,
+            24 => Self::GetRent {
+                config_flags: reader.read_u8()?,
+            },
+            25 => Self::InitEscrowManaged {
+                params: InitEscrowParams::unpack(&mut reader)?,
+                offered_amount: reader.read_u64()?,
+            },
+            // Every tag past the last instruction is reserved for new ones, never a fallback to an old one
+            26..=u8::MAX => return Err(InvalidInstruction.into()),
+        };
+        // Bytes nobody reads are as wrong as missing ones
+        reader.finish()?;
+        Ok(instruction)
+    }
+
+    // the reverse of `unpack`, used by the client side helpers
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Self::InitEscrow { params } => {
+                buf.push(0);
+                params.pack_into(&mut buf);
+            }
+            Self::Exchange {
+                amount,
+                min_amount_out,
+                memo,
+            } => {
+                buf.push(1);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&min_amount_out.to_le_bytes());
+                buf.extend_from_slice(memo);
+            }
+            Self::CancelEscrow => buf.push(2),
+            Self::PartialExchange { amount } => {
+                buf.push(3);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::UpdateExpectedAmount { new_amount } => {
+                buf.push(4);
+                buf.extend_from_slice(&new_amount.to_le_bytes());
+            }
+            Self::GetEscrowInfo => buf.push(5),
+            Self::TransferInitializer {
+                new_initializer,
+                update_receive_account,
+            } => {
+                buf.push(6);
+                buf.extend_from_slice(new_initializer.as_ref());
+                buf.push(*update_receive_account as u8);
+            }
+            Self::Deposit { amount } => {
+                buf.push(7);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::InitEscrowDelegated {
+                params,
+                offered_amount,
+            } => {
+                buf.push(8);
+                params.pack_into(&mut buf);
+                buf.extend_from_slice(&offered_amount.to_le_bytes());
+            }
+            Self::ReclaimOrphan => buf.push(9),
+            Self::ReapExpired => buf.push(10),
+            Self::PrepareExchange => buf.push(11),
+            Self::FinalizeExchange { amount } => {
+                buf.push(12);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::SplitEscrow {
+                amount,
+                new_escrow_bump,
+            } => {
+                buf.push(13);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.push(*new_escrow_bump);
+            }
+            Self::SimulateExchange { amount } => {
+                buf.push(14);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::SetReceiveAccount => buf.push(15),
+            Self::InitEscrowPda { params } => {
+                buf.push(16);
+                params.pack_into(&mut buf);
+            }
+            Self::MigrateState => buf.push(17),
+            Self::InitEscrowNative {
+                params,
+                offered_lamports,
+            } => {
+                buf.push(18);
+                params.pack_into(&mut buf);
+                buf.extend_from_slice(&offered_lamports.to_le_bytes());
+            }
+            Self::ExchangeNativeOffer { amount } => {
+                buf.push(19);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::ExtendExpiry { new_expire_at } => {
+                buf.push(20);
+                buf.extend_from_slice(&new_expire_at.to_le_bytes());
+            }
+            Self::ToggleExchanges { paused } => {
+                buf.push(22);
+                buf.push(*paused as u8);
+            }
+            Self::AddToBasket => buf.push(23),
+            Self::GetRent { config_flags } => {
+                buf.push(24);
+                buf.push(*config_flags);
+            }
+            Self::InitEscrowManaged {
+                params,
+                offered_amount,
+            } => {
+                buf.push(25);
+                params.pack_into(&mut buf);
+                buf.extend_from_slice(&offered_amount.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    /// Creates an `InitEscrow` instruction with the accounts in the order `process_init_escrow` reads them.
+    /// With `accept_native`, `token_to_receive_account` is the initializer's main account and the mint is unused.
+    /// Without a `rent_payer`, the escrow's rent goes back to the initializer.
+    #[allow(clippy::too_many_arguments)]
+    pub fn init_escrow(
+        program_id: &Pubkey,
+        initializer: &Pubkey,
+        temp_token_account: &Pubkey,
+        token_to_receive_account: &Pubkey,
+        escrow_account: &Pubkey,
+        token_program: &Pubkey,
+        token_to_receive_mint: &Pubkey,
+        params: InitEscrowParams,
+        rent_payer: Option<&Pubkey>,
+    ) -> Instruction {
+        let accept_native = params.accept_native;
+        let data = Self::InitEscrow { params }.pack();
+
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(*temp_token_account, false),
+            AccountMeta::new_readonly(*token_to_receive_account, false),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new_readonly(*token_program, false),
+        ];
+        if !accept_native {
+            accounts.push(AccountMeta::new_readonly(*token_to_receive_mint, false));
+        }
+        if let Some(rent_payer) = rent_payer {
+            accounts.push(AccountMeta::new_readonly(*rent_payer, false));
+        }
+
+        Instruction {
+            program_id: *program_id,
+            accounts,
+            data,
+        }
+    }
+
+    /// Creates an `Exchange` instruction with the accounts in the order `process_exchange` reads them
+    pub fn exchange(
+        program_id: &Pubkey,
+        accounts: &ExchangeAccounts,
+        amount: u64,
+        min_amount_out: u64,
+    ) -> Instruction {
+        let mut account_metas = vec![
+            AccountMeta::new_readonly(accounts.taker, true),
+            AccountMeta::new(accounts.takers_sending_token_account, false),
+            AccountMeta::new(accounts.takers_token_to_receive_account, false),
+            AccountMeta::new(accounts.pdas_temp_token_account, false),
+            AccountMeta::new(accounts.initializers_main_account, false),
+            AccountMeta::new(accounts.initializers_token_to_receive_account, false),
+            AccountMeta::new(accounts.escrow_account, false),
+            AccountMeta::new_readonly(accounts.token_program, false),
+            AccountMeta::new_readonly(accounts.pda, false),
+            AccountMeta::new_readonly(accounts.pdas_temp_token_mint, false),
+            AccountMeta::new_readonly(accounts.initializers_token_to_receive_mint, false),
+        ];
+        if accounts.create_initializers_token_to_receive_account {
+            account_metas[0].is_writable = true;
+            account_metas.push(AccountMeta::new_readonly(system_program::id(), false));
+            account_metas.push(AccountMeta::new_readonly(sysvar::rent::id(), false));
+            account_metas.push(AccountMeta::new_readonly(
+                token::associated_token::id(),
+                false,
+            ));
+        }
+        Self::exchange_with_optional_accounts(
+            program_id,
+            account_metas,
+            accounts,
+            amount,
+            min_amount_out,
+        )
+    }
+
+    /// Creates an `Exchange` instruction for an escrow accepting native SOL,
+    /// paid from the taker's main account to the initializer's main account.
+    /// The taker's sending token account, the initializer's receiving token account and its mint
+    /// aren't used, and neither is `create_initializers_token_to_receive_account`.
+    pub fn exchange_native(
+        program_id: &Pubkey,
+        accounts: &ExchangeAccounts,
+        amount: u64,
+        min_amount_out: u64,
+    ) -> Instruction {
+        let account_metas = vec![
+            AccountMeta::new(accounts.taker, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(accounts.takers_token_to_receive_account, false),
+            AccountMeta::new(accounts.pdas_temp_token_account, false),
+            AccountMeta::new(accounts.initializers_main_account, false),
+            AccountMeta::new(accounts.initializers_main_account, false),
+            AccountMeta::new(accounts.escrow_account, false),
+            AccountMeta::new_readonly(accounts.token_program, false),
+            AccountMeta::new_readonly(accounts.pda, false),
+            AccountMeta::new_readonly(accounts.pdas_temp_token_mint, false),
+        ];
+        Self::exchange_with_optional_accounts(
+            program_id,
+            account_metas,
+            accounts,
+            amount,
+            min_amount_out,
+        )
+    }
+
+    // The accounts after the ones every `Exchange` starts with are the same however the taker pays.
+    // The fee vault follows in builds paying maker rebates, and the program config comes last,
+    // so that the taker is turned away while the admin has exchanges paused.
+    fn exchange_with_optional_accounts(
+        program_id: &Pubkey,
+        mut account_metas: Vec<AccountMeta>,
+        accounts: &ExchangeAccounts,
+        amount: u64,
+        min_amount_out: u64,
+    ) -> Instruction {
+        if let Some(fee_token_account) = accounts.fee_token_account {
+            account_metas.push(AccountMeta::new(fee_token_account, false));
+        }
+        for (basket_temp_token_account, takers_basket_token_account, basket_mint) in
+            &accounts.basket_accounts
+        {
+            account_metas.push(AccountMeta::new(*basket_temp_token_account, false));
+            account_metas.push(AccountMeta::new(*takers_basket_token_account, false));
+            account_metas.push(AccountMeta::new_readonly(*basket_mint, false));
+        }
+        if let Some(initializers_token_x_account) = accounts.initializers_token_x_account {
+            account_metas.push(AccountMeta::new(initializers_token_x_account, false));
+        }
+        if let Some(fee_account) = accounts.fee_account {
+            account_metas.push(AccountMeta::new(fee_account, false));
+        }
+        if let Some(rent_payer) = accounts.rent_payer {
+            account_metas.push(AccountMeta::new(rent_payer, false));
+        }
+        if config::MAKER_REBATE_LAMPORTS != 0 {
+            let (fee_vault, _bump_seed) =
+                Pubkey::find_program_address(&[config::FEE_VAULT_SEED], program_id);
+            account_metas.push(AccountMeta::new(fee_vault, false));
+            account_metas.push(AccountMeta::new_readonly(system_program::id(), false));
+        }
+        let (program_config, _bump_seed) =
+            Pubkey::find_program_address(&[config::PROGRAM_CONFIG_SEED], program_id);
+        account_metas.push(AccountMeta::new_readonly(program_config, false));
+
+        Instruction {
+            program_id: *program_id,
+            accounts: account_metas,
+            data: Self::Exchange {
+                amount,
+                min_amount_out,
+                memo: vec![],
+            }
+            .pack(),
+        }
+    }
+
+    /// Creates an `Exchange` instruction like `EscrowInstruction::exchange`,
+    /// having the memo program record `memo` about the trade, signed by the taker.
+    /// Fails with `EscrowError::MemoTooLong` on a memo longer than `EscrowInstruction::MAX_MEMO_LEN`,
+    /// and an empty one records nothing.
+    pub fn exchange_with_memo(
+        program_id: &Pubkey,
+        accounts: &ExchangeAccounts,
+        amount: u64,
+        min_amount_out: u64,
+        memo: &[u8],
+    ) -> Result<Instruction, ProgramError> {
+        if memo.len() > Self::MAX_MEMO_LEN {
+            return Err(EscrowError::MemoTooLong.into());
+        }
+        let mut instruction = Self::exchange(program_id, accounts, amount, min_amount_out);
+        if !memo.is_empty() {
+            instruction
+                .accounts
+                .push(AccountMeta::new_readonly(memo::id(), false));
+            instruction.data = Self::Exchange {
+                amount,
+                min_amount_out,
+                memo: memo.to_vec(),
+            }
+            .pack();
+        }
+        Ok(instruction)
+    }
+
+    /// Creates a `ToggleExchanges` instruction, to be signed by `config::ADMIN_PUBKEY`
+    pub fn toggle_exchanges(program_id: &Pubkey, admin: &Pubkey, paused: bool) -> Instruction {
+        let (program_config, _bump_seed) =
+            Pubkey::find_program_address(&[config::PROGRAM_CONFIG_SEED], program_id);
+
+        Instruction {
+            program_id: *program_id,
+            accounts: vec![
+                AccountMeta::new(*admin, true),
+                AccountMeta::new(program_config, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+            data: Self::ToggleExchanges { paused }.pack(),
+        }
+    }
+}
+
+// Reads an instruction's data front to back, every field checked against the bytes left
+struct InstructionReader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> InstructionReader<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self { input }
     }
 
-    // it will decode the rest
-    fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
-        let amount = input
-            // the first 8 bytes will be used as `amount` (u8 * 8 = u64)
-            .get(..8)
+    // takes the next `N` bytes, or fails on a short read without moving
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], ProgramError> {
+        let bytes = self
+            .input
+            .get(..N)
             .and_then(|slice| slice.try_into().ok())
-            .map(u64::from_le_bytes)
             .ok_or(InvalidInstruction)?;
-        Ok(amount)
+        self.input = &self.input[N..];
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ProgramError> {
+        self.read_bytes::<1>().map(|[byte]| byte)
+    }
+
+    // a single byte which must be either 0 or 1
+    fn read_bool(&mut self) -> Result<bool, ProgramError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+
+    fn read_u16(&mut self) -> Result<u16, ProgramError> {
+        self.read_bytes().map(u16::from_le_bytes)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, ProgramError> {
+        self.read_bytes().map(u64::from_le_bytes)
+    }
+
+    fn read_i64(&mut self) -> Result<i64, ProgramError> {
+        self.read_bytes().map(i64::from_le_bytes)
+    }
+
+    // a 32 bytes long pubkey
+    fn read_pubkey(&mut self) -> Result<Pubkey, ProgramError> {
+        self.read_bytes().map(Pubkey::new_from_array)
+    }
+
+    // every byte left, for data running to the end
+    fn read_rest(&mut self) -> Vec<u8> {
+        let bytes = self.input.to_vec();
+        self.input = &[];
+        bytes
+    }
+
+    // every byte must have been read by now
+    fn finish(self) -> Result<(), ProgramError> {
+        if self.input.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidInstruction.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_init_escrow_round_trip() {
+        let program_id = Pubkey::new_unique();
+        let initializer = Pubkey::new_unique();
+        let params = InitEscrowParams {
+            amount: 20,
+            expire_at: 1_700_000_000,
+            fee_lamports: 5000,
+            fee_account: Pubkey::new_unique(),
+            not_before: 1_600_000_000,
+            accepted_mints: [
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::default(),
+            ],
+            accept_native: false,
+            taker_fee_bps: 250,
+            fee_token_account: Pubkey::new_unique(),
+            reference: [7; 32],
+            dust_threshold: 3,
+            allowed_taker: Pubkey::new_unique(),
+            grace_seconds: 3600,
+        };
+
+        let instruction = EscrowInstruction::init_escrow(
+            &program_id,
+            &initializer,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &spl_token::id(),
+            &Pubkey::new_unique(),
+            params.clone(),
+            None,
+        );
+
+        assert_eq!(instruction.program_id, program_id);
+        assert_eq!(instruction.accounts.len(), 6);
+        assert_eq!(instruction.accounts[0].pubkey, initializer);
+        assert!(instruction.accounts[0].is_signer);
+        assert_eq!(
+            EscrowInstruction::unpack(&instruction.data).unwrap(),
+            EscrowInstruction::InitEscrow { params }
+        );
+    }
+
+    #[test]
+    fn test_exchange_accounts() {
+        let program_id = Pubkey::new_unique();
+        let taker = Pubkey::new_unique();
+        let fee_account = Pubkey::new_unique();
+        let accounts = ExchangeAccounts {
+            taker,
+            takers_sending_token_account: Pubkey::new_unique(),
+            takers_token_to_receive_account: Pubkey::new_unique(),
+            pdas_temp_token_account: Pubkey::new_unique(),
+            initializers_main_account: Pubkey::new_unique(),
+            initializers_token_to_receive_account: Pubkey::new_unique(),
+            initializers_token_to_receive_mint: Pubkey::new_unique(),
+            escrow_account: Pubkey::new_unique(),
+            token_program: spl_token::id(),
+            pda: Pubkey::new_unique(),
+            pdas_temp_token_mint: Pubkey::new_unique(),
+            ..ExchangeAccounts::default()
+        };
+
+        let instruction = EscrowInstruction::exchange(&program_id, &accounts, 10, 10);
+
+        // 0. taker, 1. taker's sending account, 2. taker's receiving account, 3. temp account,
+        // 4. initializer, 5. initializer's receiving account, 6. escrow, 7. token program, 8. PDA,
+        // 9. mint of the temp account, 10. mint of the initializer's receiving account, then the fee vault
+        // and the system program in `maker-rebate` builds, and the program config last
+        let (program_config_account, account_metas) = instruction.accounts.split_last().unwrap();
+        let (account_metas, rebate_accounts) = account_metas.split_at(11);
+        let signers: Vec<bool> = account_metas.iter().map(|a| a.is_signer).collect();
+        assert_eq!(
+            signers,
+            [true, false, false, false, false, false, false, false, false, false, false]
+        );
+        let writables: Vec<bool> = account_metas.iter().map(|a| a.is_writable).collect();
+        assert_eq!(
+            writables,
+            [false, true, true, true, true, true, true, false, false, false, false]
+        );
+        assert_eq!(account_metas[0].pubkey, taker);
+        assert_eq!(account_metas[7].pubkey, spl_token::id());
+        assert_eq!(account_metas[9].pubkey, accounts.pdas_temp_token_mint);
+        assert_eq!(
+            account_metas[10].pubkey,
+            accounts.initializers_token_to_receive_mint
+        );
+        if config::MAKER_REBATE_LAMPORTS != 0 {
+            let (fee_vault, _bump_seed) =
+                Pubkey::find_program_address(&[config::FEE_VAULT_SEED], &program_id);
+            assert_eq!(
+                rebate_accounts,
+                [
+                    AccountMeta::new(fee_vault, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ]
+            );
+        } else {
+            assert!(rebate_accounts.is_empty());
+        }
+        // and the optional accounts below go ahead of them
+        let rebate_accounts_len = rebate_accounts.len();
+        let (program_config, _bump_seed) =
+            Pubkey::find_program_address(&[config::PROGRAM_CONFIG_SEED], &program_id);
+        assert_eq!(
+            *program_config_account,
+            AccountMeta::new_readonly(program_config, false)
+        );
+        assert_eq!(
+            EscrowInstruction::unpack(&instruction.data).unwrap(),
+            EscrowInstruction::Exchange {
+                amount: 10,
+                min_amount_out: 10,
+                memo: vec![],
+            }
+        );
+
+        // 11. fee account, only for escrows charging a fee
+        let accounts = ExchangeAccounts {
+            fee_account: Some(fee_account),
+            ..accounts
+        };
+        let instruction = EscrowInstruction::exchange(&program_id, &accounts, 10, 10);
+        assert_eq!(instruction.accounts.len(), 13 + rebate_accounts_len);
+        assert_eq!(instruction.accounts[11].pubkey, fee_account);
+        assert!(instruction.accounts[11].is_writable);
+        assert!(!instruction.accounts[11].is_signer);
+
+        // 12. rent payer, only for escrows whose rent someone else paid
+        let rent_payer = Pubkey::new_unique();
+        let accounts = ExchangeAccounts {
+            rent_payer: Some(rent_payer),
+            ..accounts
+        };
+        let instruction = EscrowInstruction::exchange(&program_id, &accounts, 10, 10);
+        assert_eq!(instruction.accounts.len(), 14 + rebate_accounts_len);
+        assert_eq!(instruction.accounts[12].pubkey, rent_payer);
+        assert!(instruction.accounts[12].is_writable);
+        assert!(!instruction.accounts[12].is_signer);
+
+        // 11. fee token account, ahead of the others, only for escrows charging a taker fee
+        let fee_token_account = Pubkey::new_unique();
+        let accounts = ExchangeAccounts {
+            fee_token_account: Some(fee_token_account),
+            ..accounts
+        };
+        let instruction = EscrowInstruction::exchange(&program_id, &accounts, 10, 10);
+        assert_eq!(instruction.accounts.len(), 15 + rebate_accounts_len);
+        assert_eq!(instruction.accounts[11].pubkey, fee_token_account);
+        assert!(instruction.accounts[11].is_writable);
+        assert_eq!(instruction.accounts[12].pubkey, fee_account);
+        assert_eq!(instruction.accounts[13].pubkey, rent_payer);
+
+        // 12. initializer's token X account, right after it, only for custodial escrows with a dust threshold
+        let initializers_token_x_account = Pubkey::new_unique();
+        let accounts = ExchangeAccounts {
+            initializers_token_x_account: Some(initializers_token_x_account),
+            ..accounts
+        };
+        let instruction = EscrowInstruction::exchange(&program_id, &accounts, 10, 10);
+        assert_eq!(instruction.accounts.len(), 16 + rebate_accounts_len);
+        assert_eq!(
+            instruction.accounts[12].pubkey,
+            initializers_token_x_account
+        );
+        assert!(instruction.accounts[12].is_writable);
+        assert_eq!(instruction.accounts[13].pubkey, fee_account);
+
+        // 11. - 13. what it takes to create the initializer's associated token account again,
+        // ahead of all the others, with the taker paying for it
+        let instruction = EscrowInstruction::exchange(
+            &program_id,
+            &ExchangeAccounts {
+                create_initializers_token_to_receive_account: true,
+                initializers_token_x_account: None,
+                fee_account: None,
+                rent_payer: None,
+                ..accounts.clone()
+            },
+            10,
+            10,
+        );
+        assert_eq!(instruction.accounts.len(), 16 + rebate_accounts_len);
+        assert!(instruction.accounts[0].is_signer);
+        assert!(instruction.accounts[0].is_writable);
+        assert_eq!(instruction.accounts[11].pubkey, system_program::id());
+        assert_eq!(instruction.accounts[12].pubkey, sysvar::rent::id());
+        assert_eq!(
+            instruction.accounts[13].pubkey,
+            token::associated_token::id()
+        );
+        assert_eq!(instruction.accounts[14].pubkey, fee_token_account);
+
+        // 12. - 17. the basket's temp accounts, each with the taker's account for its token and its mint,
+        // right after the fee token account
+        let basket_accounts = vec![
+            (
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+            ),
+            (
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+            ),
+        ];
+        let accounts = ExchangeAccounts {
+            basket_accounts: basket_accounts.clone(),
+            fee_account: None,
+            rent_payer: None,
+            ..accounts
+        };
+        let instruction = EscrowInstruction::exchange(&program_id, &accounts, 10, 10);
+        assert_eq!(instruction.accounts.len(), 20 + rebate_accounts_len);
+        assert_eq!(instruction.accounts[11].pubkey, fee_token_account);
+        let basket_keys: Vec<Pubkey> = instruction.accounts[12..18]
+            .iter()
+            .map(|a| a.pubkey)
+            .collect();
+        assert_eq!(
+            basket_keys,
+            [
+                basket_accounts[0].0,
+                basket_accounts[0].1,
+                basket_accounts[0].2,
+                basket_accounts[1].0,
+                basket_accounts[1].1,
+                basket_accounts[1].2,
+            ]
+        );
+        let basket_writables: Vec<bool> = instruction.accounts[12..18]
+            .iter()
+            .map(|a| a.is_writable)
+            .collect();
+        assert_eq!(basket_writables, [true, true, false, true, true, false]);
+        assert!(instruction.accounts[12..18].iter().all(|a| !a.is_signer));
+        assert_eq!(
+            instruction.accounts[18].pubkey,
+            initializers_token_x_account
+        );
+
+        // Paying in native SOL, the taker's main account pays through the system program
+        // and the initializer's main account is paid, with no Token Y mint to check.
+        // Everything after those comes just the same.
+        let native_instruction = EscrowInstruction::exchange_native(&program_id, &accounts, 10, 10);
+        assert_eq!(
+            native_instruction.accounts[0],
+            AccountMeta::new(taker, true)
+        );
+        assert_eq!(
+            native_instruction.accounts[1],
+            AccountMeta::new_readonly(system_program::id(), false)
+        );
+        assert_eq!(
+            native_instruction.accounts[5].pubkey,
+            accounts.initializers_main_account
+        );
+        assert_eq!(
+            native_instruction.accounts[2..5],
+            instruction.accounts[2..5]
+        );
+        assert_eq!(
+            native_instruction.accounts[6..10],
+            instruction.accounts[6..10]
+        );
+        assert_eq!(
+            native_instruction.accounts[10..],
+            instruction.accounts[11..]
+        );
+        assert_eq!(native_instruction.data, instruction.data);
+    }
+
+    #[test]
+    fn test_transfer_initializer_round_trip() {
+        let instruction = EscrowInstruction::TransferInitializer {
+            new_initializer: Pubkey::new_unique(),
+            update_receive_account: true,
+        };
+        let mut data = instruction.pack();
+        assert_eq!(data.len(), 1 + 32 + 1);
+        assert_eq!(EscrowInstruction::unpack(&data).unwrap(), instruction);
+
+        // The flag is a strict bool
+        data[33] = 2;
+        assert!(EscrowInstruction::unpack(&data).is_err());
+        data.pop();
+        assert!(EscrowInstruction::unpack(&data).is_err());
+    }
+
+    #[test]
+    fn test_init_escrow_delegated_round_trip() {
+        let instruction = EscrowInstruction::InitEscrowDelegated {
+            params: InitEscrowParams {
+                amount: 20,
+                expire_at: 1_700_000_000,
+                fee_lamports: 5000,
+                fee_account: Pubkey::new_unique(),
+                not_before: 1_600_000_000,
+                accepted_mints: [Pubkey::new_unique(), Pubkey::default(), Pubkey::default()],
+                accept_native: true,
+                taker_fee_bps: 250,
+                fee_token_account: Pubkey::new_unique(),
+                reference: [7; 32],
+                dust_threshold: 3,
+                allowed_taker: Pubkey::new_unique(),
+                grace_seconds: 3600,
+            },
+            offered_amount: 10,
+        };
+        let mut data = instruction.pack();
+        assert_eq!(data.len(), 1 + 275 + 8);
+        assert_eq!(EscrowInstruction::unpack(&data).unwrap(), instruction);
+
+        // The offered amount can't be left out
+        data.truncate(1 + 275);
+        assert!(EscrowInstruction::unpack(&data).is_err());
+    }
+
+    #[test]
+    fn test_unpack_truncated_data() {
+        let invalid_instruction = Err(ProgramError::from(InvalidInstruction));
+
+        assert_eq!(EscrowInstruction::unpack(&[]), invalid_instruction);
+        // Every variant carrying data, given its tag only
+        for tag in [0, 1, 3, 4, 6, 7, 8, 12, 13, 14, 16, 18, 19, 20, 22, 24, 25] {
+            assert_eq!(EscrowInstruction::unpack(&[tag]), invalid_instruction);
+        }
+        // An amount one byte short
+        assert_eq!(
+            EscrowInstruction::unpack(&[1, 0, 0, 0, 0, 0, 0, 0]),
+            invalid_instruction
+        );
+        let data = EscrowInstruction::InitEscrow {
+            params: InitEscrowParams {
+                amount: 20,
+                expire_at: i64::MAX,
+                ..InitEscrowParams::default()
+            },
+        }
+        .pack();
+        // InitEscrow missing its grace period's last byte
+        assert_eq!(
+            EscrowInstruction::unpack(&data[..data.len() - 1]),
+            invalid_instruction
+        );
+        // and with a native flag that is not a bool
+        let mut data = data;
+        data[1 + 160] = 2;
+        assert_eq!(EscrowInstruction::unpack(&data), invalid_instruction);
+    }
+
+    #[test]
+    fn test_unpack_payload_sizes() {
+        let invalid_instruction = Err(ProgramError::from(InvalidInstruction));
+        let params = InitEscrowParams {
+            amount: 20,
+            expire_at: 1_700_000_000,
+            fee_lamports: 5000,
+            fee_account: Pubkey::new_unique(),
+            not_before: 1_600_000_000,
+            accepted_mints: [Pubkey::new_unique(), Pubkey::default(), Pubkey::default()],
+            accept_native: true,
+            taker_fee_bps: 250,
+            fee_token_account: Pubkey::new_unique(),
+            reference: [7; 32],
+            dust_threshold: 3,
+            allowed_taker: Pubkey::new_unique(),
+            grace_seconds: 3600,
+        };
+
+        // Every variant with the size of its payload, tag excluded.
+        // `Exchange` takes whatever follows as its memo, so it has its own test.
+        let instructions = [
+            (
+                EscrowInstruction::InitEscrow {
+                    params: params.clone(),
+                },
+                275,
+            ),
+            (EscrowInstruction::CancelEscrow, 0),
+            (EscrowInstruction::PartialExchange { amount: 5 }, 8),
+            (
+                EscrowInstruction::UpdateExpectedAmount { new_amount: 30 },
+                8,
+            ),
+            (EscrowInstruction::GetEscrowInfo, 0),
+            (
+                EscrowInstruction::TransferInitializer {
+                    new_initializer: Pubkey::new_unique(),
+                    update_receive_account: true,
+                },
+                33,
+            ),
+            (EscrowInstruction::Deposit { amount: 5 }, 8),
+            (
+                EscrowInstruction::InitEscrowDelegated {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        ..InitEscrowParams::default()
+                    },
+                    offered_amount: 10,
+                },
+                283,
+            ),
+            (EscrowInstruction::ReclaimOrphan, 0),
+            (EscrowInstruction::ReapExpired, 0),
+            (EscrowInstruction::PrepareExchange, 0),
+            (EscrowInstruction::FinalizeExchange { amount: 10 }, 8),
+            (
+                EscrowInstruction::SplitEscrow {
+                    amount: 5,
+                    new_escrow_bump: 254,
+                },
+                9,
+            ),
+            (EscrowInstruction::SimulateExchange { amount: 10 }, 8),
+            (EscrowInstruction::SetReceiveAccount, 0),
+            (EscrowInstruction::InitEscrowPda { params }, 275),
+            (EscrowInstruction::MigrateState, 0),
+            (
+                EscrowInstruction::InitEscrowNative {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        fee_lamports: 5000,
+                        fee_account: Pubkey::new_unique(),
+                        reference: [7; 32],
+                        grace_seconds: 3600,
+                        ..InitEscrowParams::default()
+                    },
+                    offered_lamports: 1_000_000_000,
+                },
+                283,
+            ),
+            (EscrowInstruction::ExchangeNativeOffer { amount: 10 }, 8),
+            (
+                EscrowInstruction::ExtendExpiry {
+                    new_expire_at: 1_800_000_000,
+                },
+                8,
+            ),
+            (EscrowInstruction::ToggleExchanges { paused: true }, 1),
+            (EscrowInstruction::AddToBasket, 0),
+            (EscrowInstruction::GetRent { config_flags: 5 }, 1),
+            (
+                EscrowInstruction::InitEscrowManaged {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        ..InitEscrowParams::default()
+                    },
+                    offered_amount: 10,
+                },
+                283,
+            ),
+        ];
+        for (instruction, payload_len) in instructions {
+            let mut data = instruction.pack();
+            assert_eq!(data.len(), 1 + payload_len);
+            // Exactly sized, it decodes back
+            assert_eq!(EscrowInstruction::unpack(&data).unwrap(), instruction);
+            // a byte too many is rejected
+            data.push(0);
+            assert_eq!(EscrowInstruction::unpack(&data), invalid_instruction);
+            // and so is a byte too few
+            if payload_len > 0 {
+                data.truncate(payload_len);
+                assert_eq!(EscrowInstruction::unpack(&data), invalid_instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn test_exchange_memo() {
+        let invalid_instruction = Err(ProgramError::from(InvalidInstruction));
+        let program_id = Pubkey::new_unique();
+        let accounts = ExchangeAccounts {
+            taker: Pubkey::new_unique(),
+            ..ExchangeAccounts::default()
+        };
+
+        // Without a memo it packs just as it always has
+        let exchange = EscrowInstruction::exchange(&program_id, &accounts, 10, 20);
+        assert_eq!(exchange.data.len(), 17);
+        assert_eq!(
+            EscrowInstruction::exchange_with_memo(&program_id, &accounts, 10, 20, b"").unwrap(),
+            exchange
+        );
+        // One byte short of the minimum is still rejected
+        assert_eq!(
+            EscrowInstruction::unpack(&exchange.data[..16]),
+            invalid_instruction
+        );
+
+        // The longest memo goes after the minimum, the memo program after every account
+        let memo = vec![b'a'; EscrowInstruction::MAX_MEMO_LEN];
+        let instruction =
+            EscrowInstruction::exchange_with_memo(&program_id, &accounts, 10, 20, &memo).unwrap();
+        assert_eq!(instruction.data.len(), 17 + memo.len());
+        assert_eq!(
+            EscrowInstruction::unpack(&instruction.data).unwrap(),
+            EscrowInstruction::Exchange {
+                amount: 10,
+                min_amount_out: 20,
+                memo: memo.clone(),
+            }
+        );
+        assert_eq!(instruction.accounts.len(), exchange.accounts.len() + 1);
+        assert_eq!(
+            instruction.accounts[..exchange.accounts.len()],
+            exchange.accounts
+        );
+        assert_eq!(
+            *instruction.accounts.last().unwrap(),
+            AccountMeta::new_readonly(memo::id(), false)
+        );
+
+        // A byte longer is refused by the helper, and by the program if packed anyway
+        let memo = vec![b'a'; EscrowInstruction::MAX_MEMO_LEN + 1];
+        assert_eq!(
+            EscrowInstruction::exchange_with_memo(&program_id, &accounts, 10, 20, &memo),
+            Err(EscrowError::MemoTooLong.into())
+        );
+        let data = EscrowInstruction::Exchange {
+            amount: 10,
+            min_amount_out: 20,
+            memo,
+        }
+        .pack();
+        assert_eq!(EscrowInstruction::unpack(&data), invalid_instruction);
+    }
+
+    #[test]
+    fn test_unpack_reserved_tags() {
+        let invalid_instruction = Err(ProgramError::from(InvalidInstruction));
+
+        // The last tag in use still decodes...
+        let mut data = vec![25];
+        data.extend_from_slice(&[0; 283]);
+        assert!(matches!(
+            EscrowInstruction::unpack(&data),
+            Ok(EscrowInstruction::InitEscrowManaged { .. })
+        ));
+        // ...and none after it does, whatever data follows
+        // (nor the one `ExchangeWithMemo` had)
+        assert_eq!(EscrowInstruction::unpack(&[21; 28]), invalid_instruction);
+        for tag in 26..=u8::MAX {
+            assert_eq!(EscrowInstruction::unpack(&[tag]), invalid_instruction);
+            let mut data = vec![tag];
+            data.extend_from_slice(&[0; 283]);
+            assert_eq!(EscrowInstruction::unpack(&data), invalid_instruction);
+        }
     }
 }
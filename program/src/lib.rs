@@ -1,5 +1,16 @@
+pub mod config;
 pub mod entrypoint;
 pub mod error;
+pub mod events;
 pub mod instructions;
+pub mod math;
+pub mod memo;
 pub mod processor;
 pub mod state;
+pub mod token;
+
+// Lets `test_utils` name the program the same way here as in the integration tests including it
+#[cfg(test)]
+extern crate self as solana_escrow_program;
+#[cfg(test)]
+mod test_utils;
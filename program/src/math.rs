@@ -0,0 +1,72 @@
+use crate::error::EscrowError;
+
+// Token amounts are u64, so `amount * numerator` always fits in a u128.
+// Only the final division result may not fit back into a u64.
+
+/// A whole in basis points, which fees are given in
+pub const BASIS_POINTS: u64 = 10_000;
+
+/// `amount * numerator / denominator`, rounded down
+pub fn proportional(amount: u64, numerator: u64, denominator: u64) -> Result<u64, EscrowError> {
+    let result = (amount as u128)
+        .checked_mul(numerator as u128)
+        .and_then(|product| product.checked_div(denominator as u128))
+        .ok_or(EscrowError::AmountOverflow)?;
+    u64::try_from(result).map_err(|_| EscrowError::AmountOverflow)
+}
+
+/// Whether `amount * numerator / denominator` has no remainder
+pub fn divides_evenly(amount: u64, numerator: u64, denominator: u64) -> Result<bool, EscrowError> {
+    let remainder = (amount as u128)
+        .checked_mul(numerator as u128)
+        .and_then(|product| product.checked_rem(denominator as u128))
+        .ok_or(EscrowError::AmountOverflow)?;
+    Ok(remainder == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proportional() {
+        assert_eq!(proportional(4, 20, 10), Ok(8));
+        // rounded down
+        assert_eq!(proportional(1, 10, 3), Ok(3));
+        assert_eq!(proportional(0, 20, 10), Ok(0));
+    }
+
+    #[test]
+    fn test_proportional_near_u64_max() {
+        // The intermediate product overflows u64, but not u128
+        assert_eq!(proportional(u64::MAX, u64::MAX, u64::MAX), Ok(u64::MAX));
+        assert_eq!(
+            proportional(u64::MAX, u64::MAX - 1, u64::MAX),
+            Ok(u64::MAX - 1)
+        );
+        assert_eq!(proportional(u64::MAX, 1, 2), Ok(u64::MAX / 2));
+        // The result itself doesn't fit in a u64
+        assert_eq!(
+            proportional(u64::MAX, 2, 1),
+            Err(EscrowError::AmountOverflow)
+        );
+        assert_eq!(
+            proportional(u64::MAX, u64::MAX, u64::MAX - 1),
+            Err(EscrowError::AmountOverflow)
+        );
+    }
+
+    #[test]
+    fn test_proportional_by_zero() {
+        assert_eq!(proportional(1, 1, 0), Err(EscrowError::AmountOverflow));
+        assert_eq!(divides_evenly(1, 1, 0), Err(EscrowError::AmountOverflow));
+    }
+
+    #[test]
+    fn test_divides_evenly() {
+        assert_eq!(divides_evenly(4, 20, 10), Ok(true));
+        assert_eq!(divides_evenly(1, 10, 3), Ok(false));
+        assert_eq!(divides_evenly(u64::MAX, u64::MAX, u64::MAX), Ok(true));
+        assert_eq!(divides_evenly(u64::MAX, 2, 4), Ok(false));
+    }
+}
@@ -0,0 +1,39 @@
+// The SPL Memo program, which only checks its signers and logs the memo bytes.
+// Block explorers show the memos of a transaction next to its instructions.
+
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+
+solana_program::declare_id!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+
+/// Same as `spl_memo::build_memo`. The memo program fails on bytes that aren't valid UTF-8.
+pub fn build_memo(memo: &[u8], signer_pubkeys: &[&Pubkey]) -> Instruction {
+    Instruction {
+        program_id: id(),
+        accounts: signer_pubkeys
+            .iter()
+            .map(|pubkey| AccountMeta::new_readonly(**pubkey, true))
+            .collect(),
+        data: memo.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_memo() {
+        let signer = Pubkey::new_unique();
+        let instruction = build_memo(b"trade #42", &[&signer]);
+
+        assert_eq!(instruction.program_id, id());
+        assert_eq!(
+            instruction.accounts,
+            vec![AccountMeta::new_readonly(signer, true)]
+        );
+        assert_eq!(instruction.data, b"trade #42");
+    }
+}
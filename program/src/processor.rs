@@ -1,18 +1,79 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    clock::Clock,
     entrypoint::ProgramResult,
+    instruction::Instruction,
+    log::sol_log_data,
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack},
+    program_utils::limited_deserialize,
     pubkey::Pubkey,
     rent::Rent,
-    sysvar::Sysvar,
+    system_instruction, system_program,
+    sysvar::{self, Sysvar},
 };
 
-use crate::{error::EscrowError, instructions::EscrowInstruction, state::Escrow};
+use crate::{
+    config,
+    error::EscrowError,
+    events::EscrowEvent,
+    instructions::{EscrowInstruction, InitEscrowParams},
+    math, memo,
+    state::{Escrow, EscrowMode, EscrowStatus, ProgramConfig},
+    token::{self, AuthorityType},
+};
+
+// The fewest accounts each instruction reads, not counting the optional ones at the end.
+// Anything shorter is turned away upfront rather than wherever the accounts happen to run out.
+const INIT_ESCROW_ACCOUNTS: usize = 5;
+// `InitEscrowDelegated` adds the PDA
+const INIT_ESCROW_DELEGATED_ACCOUNTS: usize = INIT_ESCROW_ACCOUNTS + 1;
+// `InitEscrowPda` adds the system program
+const INIT_ESCROW_PDA_ACCOUNTS: usize = INIT_ESCROW_ACCOUNTS + 1;
+const INIT_ESCROW_NATIVE_ACCOUNTS: usize = 7;
+// What `InitEscrowManaged` needs on top to create the temp token account: the initializer's
+// Token X account, its mint, the system program and the rent sysvar
+const CREATE_TEMP_TOKEN_ACCOUNT_ACCOUNTS: usize = 4;
+const INIT_ESCROW_MANAGED_ACCOUNTS: usize =
+    INIT_ESCROW_ACCOUNTS + CREATE_TEMP_TOKEN_ACCOUNT_ACCOUNTS;
+// Shared by `Exchange`, `PartialExchange`, `FinalizeExchange` and `SimulateExchange`
+const EXCHANGE_ACCOUNTS: usize = 10;
+// An `Exchange` with a memo adds the memo program
+const EXCHANGE_WITH_MEMO_ACCOUNTS: usize = EXCHANGE_ACCOUNTS + 1;
+const EXCHANGE_NATIVE_OFFER_ACCOUNTS: usize = 9;
+const UPDATE_EXPECTED_AMOUNT_ACCOUNTS: usize = 2;
+const EXTEND_EXPIRY_ACCOUNTS: usize = 2;
+const GET_ESCROW_INFO_ACCOUNTS: usize = 1;
+// One more when the receive account is updated as well
+const TRANSFER_INITIALIZER_ACCOUNTS: usize = 2;
+const DEPOSIT_ACCOUNTS: usize = 6;
+const CANCEL_ESCROW_ACCOUNTS: usize = 6;
+const RECLAIM_ORPHAN_ACCOUNTS: usize = 7;
+// The keeper and the token program, the escrows follow seven accounts each
+const REAP_EXPIRED_ACCOUNTS: usize = 2;
+const PREPARE_EXCHANGE_ACCOUNTS: usize = 2;
+const SPLIT_ESCROW_ACCOUNTS: usize = 8;
+const SET_RECEIVE_ACCOUNT_ACCOUNTS: usize = 4;
+const MIGRATE_STATE_ACCOUNTS: usize = 2;
+const TOGGLE_EXCHANGES_ACCOUNTS: usize = 3;
+const ADD_TO_BASKET_ACCOUNTS: usize = 4;
 
-use spl_token::state::Account as TokenAccount;
+// How Bob takes an escrow, the one thing `Exchange` and its variants differ in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExchangeKind {
+    // Everything at once, as long as that is at least `min_amount_out`
+    Full { min_amount_out: u64 },
+    // Only the amount Bob names out of the remaining Token X, for the proportional share of Token Y
+    Partial,
+    // The escrow Bob locked for himself with `PrepareExchange`
+    Finalize,
+    // Every check of a full exchange, without Bob signing, stopping right before anything is written
+    Simulate,
+}
 
 pub struct Processor;
 
@@ -24,22 +85,380 @@ impl Processor {
     ) -> ProgramResult {
         let instruction = EscrowInstruction::unpack(instruction_data)?;
         match instruction {
-            EscrowInstruction::InitEscrow { amount } => {
+            EscrowInstruction::InitEscrow { params } => {
                 msg!("Instruction: InitEscrow");
-                Self::process_init_escrow(accounts, amount, program_id)
+                Self::process_init_escrow(accounts, params, None, program_id)
+            }
+            EscrowInstruction::InitEscrowDelegated {
+                params,
+                offered_amount,
+            } => {
+                msg!("Instruction: InitEscrowDelegated");
+                Self::process_init_escrow(accounts, params, Some(offered_amount), program_id)
             }
-            EscrowInstruction::Exchange { amount } => {
+            EscrowInstruction::Exchange {
+                amount,
+                min_amount_out,
+                memo,
+            } => {
                 msg!("Instruction: Exchange");
-                Self::process_exchange(accounts, amount, program_id)
+                if memo.is_empty() {
+                    return Self::process_exchange(
+                        accounts,
+                        amount,
+                        ExchangeKind::Full { min_amount_out },
+                        program_id,
+                    );
+                }
+                if accounts.len() < EXCHANGE_WITH_MEMO_ACCOUNTS {
+                    return Err(ProgramError::NotEnoughAccountKeys);
+                }
+                // The memo program comes after every account the exchange reads
+                let (memo_program_account, accounts) = accounts
+                    .split_last()
+                    .ok_or(ProgramError::NotEnoughAccountKeys)?;
+                if !memo::check_id(memo_program_account.key) {
+                    return Err(ProgramError::IncorrectProgramId);
+                }
+                Self::process_exchange(
+                    accounts,
+                    amount,
+                    ExchangeKind::Full { min_amount_out },
+                    program_id,
+                )?;
+                Self::record_memo(&accounts[0], memo_program_account, &memo)
+            }
+            EscrowInstruction::PartialExchange { amount } => {
+                msg!("Instruction: PartialExchange");
+                // Bob names the exact amount he takes, there is nothing to slip
+                Self::process_exchange(accounts, amount, ExchangeKind::Partial, program_id)
+            }
+            EscrowInstruction::UpdateExpectedAmount { new_amount } => {
+                msg!("Instruction: UpdateExpectedAmount");
+                Self::process_update_expected_amount(accounts, new_amount, program_id)
+            }
+            EscrowInstruction::GetEscrowInfo => {
+                msg!("Instruction: GetEscrowInfo");
+                Self::process_get_escrow_info(accounts, program_id)
+            }
+            EscrowInstruction::TransferInitializer {
+                new_initializer,
+                update_receive_account,
+            } => {
+                msg!("Instruction: TransferInitializer");
+                Self::process_transfer_initializer(
+                    accounts,
+                    new_initializer,
+                    update_receive_account,
+                    program_id,
+                )
+            }
+            EscrowInstruction::Deposit { amount } => {
+                msg!("Instruction: Deposit");
+                Self::process_deposit(accounts, amount, program_id)
+            }
+            EscrowInstruction::CancelEscrow => {
+                msg!("Instruction: CancelEscrow");
+                Self::process_cancel_escrow(accounts, program_id)
+            }
+            EscrowInstruction::ReclaimOrphan => {
+                msg!("Instruction: ReclaimOrphan");
+                Self::process_reclaim_orphan(accounts, program_id)
+            }
+            EscrowInstruction::ReapExpired => {
+                msg!("Instruction: ReapExpired");
+                Self::process_reap_expired(accounts, program_id)
+            }
+            EscrowInstruction::PrepareExchange => {
+                msg!("Instruction: PrepareExchange");
+                Self::process_prepare_exchange(accounts, program_id)
+            }
+            EscrowInstruction::FinalizeExchange { amount } => {
+                msg!("Instruction: FinalizeExchange");
+                // The escrow was locked for Bob since he prepared it, there is nothing to slip
+                Self::process_exchange(accounts, amount, ExchangeKind::Finalize, program_id)
+            }
+            EscrowInstruction::SimulateExchange { amount } => {
+                msg!("Instruction: SimulateExchange");
+                Self::process_simulate_exchange(accounts, amount, program_id)
+            }
+            EscrowInstruction::SplitEscrow {
+                amount,
+                new_escrow_bump,
+            } => {
+                msg!("Instruction: SplitEscrow");
+                Self::process_split_escrow(accounts, amount, new_escrow_bump, program_id)
             }
+            EscrowInstruction::SetReceiveAccount => {
+                msg!("Instruction: SetReceiveAccount");
+                Self::process_set_receive_account(accounts, program_id)
+            }
+            EscrowInstruction::InitEscrowPda { params } => {
+                msg!("Instruction: InitEscrowPda");
+                if accounts.len() < INIT_ESCROW_PDA_ACCOUNTS {
+                    return Err(ProgramError::NotEnoughAccountKeys);
+                }
+                // The system program comes after every account `InitEscrow` reads
+                let (system_program_account, accounts) = accounts
+                    .split_last()
+                    .ok_or(ProgramError::NotEnoughAccountKeys)?;
+                Self::create_escrow_account(accounts, system_program_account, program_id)?;
+                Self::process_init_escrow(accounts, params, None, program_id)
+            }
+            EscrowInstruction::MigrateState => {
+                msg!("Instruction: MigrateState");
+                Self::process_migrate_state(accounts, program_id)
+            }
+            EscrowInstruction::InitEscrowNative {
+                params,
+                offered_lamports,
+            } => {
+                msg!("Instruction: InitEscrowNative");
+                Self::process_init_escrow_native(accounts, params, offered_lamports, program_id)
+            }
+            EscrowInstruction::ExchangeNativeOffer { amount } => {
+                msg!("Instruction: ExchangeNativeOffer");
+                Self::process_exchange_native_offer(accounts, amount, program_id)
+            }
+            EscrowInstruction::ExtendExpiry { new_expire_at } => {
+                msg!("Instruction: ExtendExpiry");
+                Self::process_extend_expiry(accounts, new_expire_at, program_id)
+            }
+            EscrowInstruction::ToggleExchanges { paused } => {
+                msg!("Instruction: ToggleExchanges");
+                Self::process_toggle_exchanges(accounts, paused, program_id)
+            }
+            EscrowInstruction::AddToBasket => {
+                msg!("Instruction: AddToBasket");
+                Self::process_add_to_basket(accounts, program_id)
+            }
+            EscrowInstruction::GetRent { config_flags } => {
+                msg!("Instruction: GetRent");
+                Self::process_get_rent(config_flags)
+            }
+            EscrowInstruction::InitEscrowManaged {
+                params,
+                offered_amount,
+            } => {
+                msg!("Instruction: InitEscrowManaged");
+                if accounts.len() < INIT_ESCROW_MANAGED_ACCOUNTS {
+                    return Err(ProgramError::NotEnoughAccountKeys);
+                }
+                // The accounts creating the temp token account come after every account `InitEscrow` reads
+                let (accounts, creation_accounts) =
+                    accounts.split_at(accounts.len() - CREATE_TEMP_TOKEN_ACCOUNT_ACCOUNTS);
+                Self::create_temp_token_account(
+                    accounts,
+                    creation_accounts,
+                    offered_amount,
+                    program_id,
+                )?;
+                Self::process_init_escrow(accounts, params, None, program_id)
+            }
+        }
+    }
+
+    // Creates the escrow account at the address derived from Alice and her temp account,
+    // sized for the escrow state and funded to be rent exempt, for `process_init_escrow` to fill in
+    fn create_escrow_account<'a>(
+        accounts: &[AccountInfo<'a>],
+        system_program_account: &AccountInfo<'a>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        // Alice pays for the escrow account
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let temp_token_account = next_account_info(account_info_iter)?;
+        let _token_to_receive_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        if !system_program::check_id(system_program_account.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // A temp account can back a single escrow, so one derived address per temp account is enough
+        let (escrow_key, escrow_bump) = Self::find_pda(
+            &[
+                config::ESCROW_STATE_SEED,
+                initializer.key.as_ref(),
+                temp_token_account.key.as_ref(),
+            ],
+            program_id,
+        )?;
+        if *escrow_account.key != escrow_key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        msg!("Calling the system program to create the escrow account...");
+        Self::create_pda_account(
+            initializer,
+            escrow_account,
+            system_program_account,
+            Escrow::LEN,
+            program_id,
+            &[
+                config::ESCROW_STATE_SEED,
+                initializer.key.as_ref(),
+                temp_token_account.key.as_ref(),
+                &[escrow_bump],
+            ],
+        )
+    }
+
+    // Creates the temp token account at the address derived from the escrow account and moves
+    // `offered_amount` of Alice's Token X into it. It is still hers, for `process_init_escrow`
+    // to check and hand over to the PDA like any temp account she made herself.
+    fn create_temp_token_account<'a>(
+        accounts: &[AccountInfo<'a>],
+        creation_accounts: &[AccountInfo<'a>],
+        offered_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        // Alice pays for the temp token account and fills it
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if offered_amount == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+
+        let temp_token_account = next_account_info(account_info_iter)?;
+        let _token_to_receive_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        if !token::is_token_program(token_program.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let creation_info_iter = &mut creation_accounts.iter();
+        let source_token_account = next_account_info(creation_info_iter)?;
+        let mint = next_account_info(creation_info_iter)?;
+        let system_program_account = next_account_info(creation_info_iter)?;
+        let rent_sysvar_account = next_account_info(creation_info_iter)?;
+        if !system_program::check_id(system_program_account.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // The temp token account holds whatever Alice's own account does
+        if *source_token_account.owner != *token_program.key || *mint.owner != *token_program.key {
+            return Err(ProgramError::IncorrectProgramId);
         }
+        let source_token_account_info =
+            token::unpack_account(&source_token_account.try_borrow_data()?)?;
+        if source_token_account_info.mint != *mint.key {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+
+        // An escrow has a single temp account, so one derived address per escrow account is enough
+        let (temp_token_account_key, temp_token_account_bump) = Self::find_pda(
+            &[config::TEMP_TOKEN_ACCOUNT_SEED, escrow_account.key.as_ref()],
+            program_id,
+        )?;
+        if *temp_token_account.key != temp_token_account_key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let temp_token_account_seeds: &[&[u8]] = &[
+            config::TEMP_TOKEN_ACCOUNT_SEED,
+            escrow_account.key.as_ref(),
+            &[temp_token_account_bump],
+        ];
+
+        // Token-2022 mints with extensions need room for matching ones on their accounts
+        let temp_token_account_len = token::account_len(&mint.try_borrow_data()?)?;
+        let decimals = token::unpack_mint(&mint.try_borrow_data()?)?.decimals;
+
+        msg!("Calling the system program to create the temp token account...");
+        Self::create_pda_account(
+            initializer,
+            temp_token_account,
+            system_program_account,
+            temp_token_account_len,
+            token_program.key,
+            temp_token_account_seeds,
+        )?;
+
+        let initialize_account_ix = token::initialize_account(
+            token_program.key,
+            temp_token_account.key,
+            mint.key,
+            initializer.key,
+        )?;
+
+        msg!("Calling the token program to initialize the temp token account...");
+        Self::invoke_signed_in_order(
+            &initialize_account_ix,
+            &[
+                temp_token_account.clone(),
+                mint.clone(),
+                initializer.clone(),
+                rent_sysvar_account.clone(),
+                token_program.clone(),
+            ],
+            &[temp_token_account_seeds],
+        )?;
+
+        let transfer_to_temp_ix = token::transfer_checked(
+            token_program.key,
+            source_token_account.key,
+            mint.key,
+            temp_token_account.key,
+            initializer.key,
+            &[&initializer.key],
+            offered_amount,
+            decimals,
+        )?;
+
+        msg!("Calling the token program to move the offered tokens to the temp token account...");
+        Self::invoke_in_order(
+            &transfer_to_temp_ix,
+            &[
+                source_token_account.clone(),
+                mint.clone(),
+                temp_token_account.clone(),
+                initializer.clone(),
+                token_program.clone(),
+            ],
+        )
     }
 
+    // With `offered_amount`, Alice keeps her Token X and only approves the PDA to move that much
+    // of them. Otherwise she hands the whole temp account over to the PDA.
     fn process_init_escrow(
         accounts: &[AccountInfo],
-        amount: u64,
+        params: InitEscrowParams,
+        offered_amount: Option<u64>,
         program_id: &Pubkey,
     ) -> ProgramResult {
+        let InitEscrowParams {
+            amount,
+            expire_at,
+            fee_lamports,
+            fee_account,
+            not_before,
+            accepted_mints,
+            accept_native,
+            taker_fee_bps,
+            fee_token_account,
+            reference,
+            dust_threshold,
+            allowed_taker,
+            grace_seconds,
+        } = params;
+        let expected_accounts = if offered_amount.is_some() {
+            INIT_ESCROW_DELEGATED_ACCOUNTS
+        } else {
+            INIT_ESCROW_ACCOUNTS
+        };
+        if accounts.len() < expected_accounts {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
         let account_info_iter = &mut accounts.iter();
         // An account(or person) who first made the escrow (in our example, Alice is an initializer)
         let initializer = next_account_info(account_info_iter)?;
@@ -48,80 +467,501 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        // Asking for nothing in return would just give the Token X's away
+        if amount == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        // Without the `escrow-amount-cap` feature the cap is u64::MAX and this never fires
+        #[allow(clippy::absurd_extreme_comparisons)]
+        let too_large = amount > config::MAX_ESCROW_AMOUNT;
+        if too_large {
+            return Err(EscrowError::AmountTooLarge.log_and_convert());
+        }
+        // The marketplace can't take more than the whole of what Bob gets
+        if u64::from(taker_fee_bps) > math::BASIS_POINTS {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        // A grace period can only end after the expiry
+        if grace_seconds < 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        let clock = Clock::get()?;
+        Self::check_escrow_duration(expire_at, &clock)?;
+
         let temp_token_account = next_account_info(account_info_iter)?;
+        // Alice's temp Token X account should be a real token account, owned by SPL-Token or Token-2022
+        if !token::is_token_program(temp_token_account.owner) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // and Alice must still own it, or else she can't hand it over to the PDA
+        let temp_token_account_info =
+            token::unpack_account(&temp_token_account.try_borrow_data()?)?;
+        if temp_token_account_info.owner != *initializer.key {
+            return Err(EscrowError::InvalidOwner.log_and_convert());
+        }
+        // and there should be something in it to trade
+        if temp_token_account_info.amount == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        // A delegated escrow offers only part of the account, which must be there too
+        if let Some(offered_amount) = offered_amount {
+            if offered_amount == 0 {
+                return Err(EscrowError::InvalidAmount.log_and_convert());
+            }
+            if offered_amount > temp_token_account_info.amount {
+                return Err(EscrowError::InsufficientFunds.log_and_convert());
+            }
+        }
+
         let token_to_receive_account = next_account_info(account_info_iter)?;
-        // Alice's Token Y account should be owned by SPL-Token program
-        if *token_to_receive_account.owner != spl_token::id() {
+        if accept_native {
+            // Lamports go straight to Alice's main account
+            if token_to_receive_account.key != initializer.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+        } else if token_to_receive_account.owner != temp_token_account.owner {
+            // Alice's Token Y account should be owned by the very same token program,
+            // since a single token program settles the whole trade
             return Err(ProgramError::IncorrectProgramId);
         }
 
         let escrow_account = next_account_info(account_info_iter)?;
+        // Clients usually create the escrow account in the same transaction, so it may well sign here
+        if !escrow_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // A client that created the escrow account but forgot to assign it to us leaves it
+        // with the system program. Its zeroed data would read as a fresh escrow all the same,
+        // so we say so here instead of leaving it to the runtime once we write to it.
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // Passing one account for two roles would leave the escrow state pointing at the wrong thing
+        if temp_token_account.key == token_to_receive_account.key
+            || temp_token_account.key == escrow_account.key
+            || token_to_receive_account.key == escrow_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // The escrow account should have enough room for the whole escrow state.
+        // Rent is checked against the actual size below, so a well funded account
+        // of an older, shorter layout must be caught here before packing into it.
+        if escrow_account.data_len() < Escrow::LEN {
+            return Err(EscrowError::AccountDataTooSmall.log_and_convert());
+        }
+
+        // A brand new account, or a closed escrow account that was allocated again, is all zeros.
+        // Anything else is either a live escrow or leftovers we can't trust, so we don't write over it.
+        // Whether it pays its rent doesn't matter then, so we fail fast before checking that.
+        if escrow_account
+            .try_borrow_data()?
+            .iter()
+            .any(|byte| *byte != 0)
+        {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
         // To sustain a 'state data' in our account, we have to pay the 'rent' for the space we are using.
         // Or else, our account will be destroyed.
         // In recent version of Solana-program crate, you don't need to pass an additional account
-        // for using sysvar like Rent.
-        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        // for using sysvar like Rent, the runtime hands it to us.
+        let rent = Rent::get()?;
 
         // The threshold of balance which is rent-exempt is calculated from the length of data.
         if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
-            return Err(EscrowError::NotRentExempt.into());
+            return Err(EscrowError::NotRentExempt.log_and_convert());
         }
 
-        // since the data in account are just &[u8], we need to deserialize(in other words, unpack) it.
-        let mut escrow_info = Escrow::unpack_unchecked(&escrow_account.try_borrow_data()?)?;
-        if escrow_info.is_initialized() {
-            return Err(ProgramError::AccountAlreadyInitialized);
+        // Nothing has been taken yet, the whole offer is up for grabs
+        let locked_amount = offered_amount.unwrap_or(temp_token_account_info.amount);
+        let mut escrow_info = Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: *initializer.key,
+            temp_token_account_pubkey: *temp_token_account.key,
+            initializer_token_to_receive_account_pubkey: *token_to_receive_account.key,
+            expected_amount: amount,
+            expire_at,
+            remaining_amount: locked_amount,
+            offered_amount: locked_amount,
+            mode: if offered_amount.is_some() {
+                EscrowMode::Delegated
+            } else {
+                EscrowMode::Custodial
+            },
+            fee_account,
+            fee_lamports,
+            not_before,
+            accepted_mints,
+            accept_native,
+            taker_fee_bps,
+            fee_token_account,
+            reference,
+            dust_threshold,
+            allowed_taker,
+            grace_seconds,
+            created_at_slot: clock.slot,
+            ..Escrow::default()
+        };
+
+        // Alice could never be paid if her own Token Y account isn't in her allow-list
+        let token_to_receive_account_info = if accept_native {
+            None
+        } else {
+            let token_to_receive_account_info =
+                token::unpack_account(&token_to_receive_account.try_borrow_data()?)?;
+            if !escrow_info.accepts_mint(&token_to_receive_account_info.mint) {
+                return Err(EscrowError::UnacceptedMint.log_and_convert());
+            }
+            Some(token_to_receive_account_info)
+        };
+
+        let token_program = next_account_info(account_info_iter)?;
+        // We hand over Alice's tokens to whatever program this is,
+        // so it had better be the one holding her temp account
+        if token_program.key != temp_token_account.owner {
+            return Err(ProgramError::IncorrectProgramId);
         }
+        escrow_info.token_program = *token_program.key;
 
-        escrow_info.is_initialized = true;
-        escrow_info.initializer_pubkey = *initializer.key;
-        escrow_info.temp_token_account_pubkey = *temp_token_account.key;
-        escrow_info.initializer_token_to_receive_account_pubkey = *token_to_receive_account.key;
-        escrow_info.expected_amount = amount;
+        // The mint of Alice's Token Y account tells how `amount` is scaled,
+        // so we keep its decimals to check them again when the trade is taken.
+        // Lamports have no mint to ask.
+        if let Some(token_to_receive_account_info) = token_to_receive_account_info {
+            let token_to_receive_mint = next_account_info(account_info_iter)?;
+            if *token_to_receive_mint.key != token_to_receive_account_info.mint {
+                return Err(EscrowError::MintMismatch.log_and_convert());
+            }
+            if token_to_receive_mint.owner != token_program.key {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            escrow_info.initializer_mint_decimals =
+                token::unpack_mint(&token_to_receive_mint.try_borrow_data()?)?.decimals;
+        }
+
+        // Unlike normal Solana account, PDA account has no private key, because it's not on the elliptic curve.
+        // We make it with (program id, seed word, escrow account), so that every escrow gets its own PDA.
+        // Searching the bump seed is expensive, so we do it only once here and keep it in the escrow.
+        let (pda, bump_seed) = Self::find_pda(
+            &[config::ESCROW_SEED, escrow_account.key.as_ref()],
+            program_id,
+        )?;
+        escrow_info.bump_seed = bump_seed;
+
+        // The token program needs the delegate's account to approve it, even if it never signs
+        let delegation = match offered_amount {
+            Some(offered_amount) => {
+                let pda_account = next_account_info(account_info_iter)?;
+                if *pda_account.key != pda {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                Some((offered_amount, pda_account))
+            }
+            None => None,
+        };
+
+        // A front-end may have paid the escrow account's rent for Alice and wants it back.
+        // Left out, the rent goes back to Alice like it always did.
+        if let Some(rent_payer) = account_info_iter.next() {
+            escrow_info.rent_payer = *rent_payer.key;
+        }
 
         // This will internally call `pack_into_slice()`
         Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
 
-        // Unlike normal Solana account, PDA account has no private key, because it's not on the elliptic curve.
-        // We make it with (program id, seed word)
-        let (pda, _bump_seed) = Pubkey::find_program_address(&[b"escrow"], program_id);
+        match delegation {
+            None => {
+                // Make an instruction that changes the ownership from temp token account to PDA
+                let owner_change_ix = token::set_authority(
+                    token_program.key,      // Tell token program to move authority
+                    temp_token_account.key, // from temp token account
+                    Some(&pda),             // to escrow's derived account.
+                    AuthorityType::AccountOwner,
+                    initializer.key,     // Alice own's this
+                    &[&initializer.key], // Alice will sign this
+                )?;
+
+                msg!("Calling the token program to transfer account ownership ...");
+                // We are using other program(a token program) from our escrow program!
+                // This is called 'Cross-Program Invocation'.
+                Self::invoke_in_order(
+                    &owner_change_ix,
+                    &[
+                        temp_token_account.clone(),
+                        initializer.clone(),
+                        token_program.clone(),
+                    ],
+                )?;
+            }
+            Some((offered_amount, pda_account)) => {
+                // Alice keeps her account, the PDA may only move the offered amount out of it
+                let approve_ix = token::approve(
+                    token_program.key,      // Tell token program to approve
+                    temp_token_account.key, // on Alice's Token X account
+                    &pda,                   // escrow's derived account as a delegate
+                    initializer.key,        // Alice own's this
+                    &[&initializer.key],    // Alice will sign this
+                    offered_amount,         // for the offered amount
+                )?;
+
+                msg!("Calling the token program to approve the PDA as a delegate ...");
+                Self::invoke_in_order(
+                    &approve_ix,
+                    &[
+                        temp_token_account.clone(),
+                        pda_account.clone(),
+                        initializer.clone(),
+                        token_program.clone(),
+                    ],
+                )?;
+            }
+        }
+
+        EscrowEvent::Initialized {
+            escrow: *escrow_account.key,
+            initializer: *initializer.key,
+            amount,
+            offered_amount: locked_amount,
+            reference,
+        }
+        .emit()?;
+        Self::log_escrow_created(escrow_account.key, &pda, temp_token_account.key);
+
+        Ok(())
+    }
+
+    // Without the `min-escrow-duration` feature there is no minimum and this never fires
+    fn check_escrow_duration(expire_at: i64, clock: &Clock) -> ProgramResult {
+        if let Some(min_duration) = config::MIN_ESCROW_DURATION {
+            if expire_at < clock.unix_timestamp.saturating_add(min_duration) {
+                return Err(EscrowError::DurationTooShort.log_and_convert());
+            }
+        }
+        Ok(())
+    }
+
+    // Everything a client needs to follow the new escrow, in a single line it can match on
+    fn log_escrow_created(escrow: &Pubkey, pda: &Pubkey, temp_token_account: &Pubkey) {
+        msg!(
+            "Escrow created: {}, PDA: {}, temp token account: {}",
+            escrow,
+            pda,
+            temp_token_account
+        );
+    }
+
+    // Alice offers lamports rather than Token X. The PDA holds them as a plain system account,
+    // which only the system program can debit, with the PDA signing.
+    fn process_init_escrow_native(
+        accounts: &[AccountInfo],
+        params: InitEscrowParams,
+        offered_lamports: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < INIT_ESCROW_NATIVE_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        // The taker gets the lamports whole and pays Alice in Token Y,
+        // leaving no native payment, taker fee or dust to set up
+        if params.accept_native
+            || params.taker_fee_bps != 0
+            || params.fee_token_account != Pubkey::default()
+            || params.dust_threshold != 0
+        {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+        let InitEscrowParams {
+            amount,
+            expire_at,
+            fee_lamports,
+            fee_account,
+            not_before,
+            accepted_mints,
+            reference,
+            allowed_taker,
+            grace_seconds,
+            ..
+        } = params;
+        let account_info_iter = &mut accounts.iter();
+        // Alice, paying the lamports she offers
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Asking for nothing in return would just give the lamports away, and offering none is no trade
+        if amount == 0 || offered_lamports == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        // Without the `escrow-amount-cap` feature the cap is u64::MAX and this never fires
+        #[allow(clippy::absurd_extreme_comparisons)]
+        let too_large = amount > config::MAX_ESCROW_AMOUNT;
+        if too_large {
+            return Err(EscrowError::AmountTooLarge.log_and_convert());
+        }
+        // A grace period can only end after the expiry
+        if grace_seconds < 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        let clock = Clock::get()?;
+        Self::check_escrow_duration(expire_at, &clock)?;
+
+        // The PDA account, to hold the lamports
+        let pda_account = next_account_info(account_info_iter)?;
+        // Alice's Token Y account
+        let token_to_receive_account = next_account_info(account_info_iter)?;
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        if !escrow_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // Same as `InitEscrow`, we only write into an escrow account that is ours
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // Passing one account for two roles would leave the escrow state pointing at the wrong thing
+        if pda_account.key == token_to_receive_account.key
+            || pda_account.key == escrow_account.key
+            || token_to_receive_account.key == escrow_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow_account.data_len() < Escrow::LEN {
+            return Err(EscrowError::AccountDataTooSmall.log_and_convert());
+        }
+        // Same as `InitEscrow`, we never write over anything but a zeroed account
+        if escrow_account
+            .try_borrow_data()?
+            .iter()
+            .any(|byte| *byte != 0)
+        {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        let rent = Rent::get()?;
+        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.log_and_convert());
+        }
 
         let token_program = next_account_info(account_info_iter)?;
-        // Make an instruction that changes the ownership from temp token account to PDA
-        let owner_change_ix = spl_token::instruction::set_authority(
-            token_program.key,      // Tell token program to move authority
-            temp_token_account.key, // from temp token account
-            Some(&pda),             // to escrow's derived account.
-            spl_token::instruction::AuthorityType::AccountOwner,
-            initializer.key,     // Alice own's this
-            &[&initializer.key], // Alice will sign this
+        if !token::is_token_program(token_program.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // Alice's Token Y account should be a real token account of that very program
+        if token_to_receive_account.owner != token_program.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let token_to_receive_account_info =
+            token::unpack_account(&token_to_receive_account.try_borrow_data()?)?;
+
+        let mut escrow_info = Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: *initializer.key,
+            // The PDA stands in for the temp account, it is where the offer waits
+            temp_token_account_pubkey: *pda_account.key,
+            initializer_token_to_receive_account_pubkey: *token_to_receive_account.key,
+            expected_amount: amount,
+            expire_at,
+            remaining_amount: offered_lamports,
+            offered_amount: offered_lamports,
+            mode: EscrowMode::NativeOffer,
+            fee_account,
+            fee_lamports,
+            not_before,
+            accepted_mints,
+            token_program: *token_program.key,
+            reference,
+            allowed_taker,
+            grace_seconds,
+            created_at_slot: clock.slot,
+            ..Escrow::default()
+        };
+
+        // Alice could never be paid if her own Token Y account isn't in her allow-list
+        if !escrow_info.accepts_mint(&token_to_receive_account_info.mint) {
+            return Err(EscrowError::UnacceptedMint.log_and_convert());
+        }
+
+        // The decimals `amount` is scaled with, checked again when the trade is taken
+        let token_to_receive_mint = next_account_info(account_info_iter)?;
+        if *token_to_receive_mint.key != token_to_receive_account_info.mint {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+        if token_to_receive_mint.owner != token_program.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        escrow_info.initializer_mint_decimals =
+            token::unpack_mint(&token_to_receive_mint.try_borrow_data()?)?.decimals;
+
+        let (pda, bump_seed) = Self::find_pda(
+            &[config::ESCROW_SEED, escrow_account.key.as_ref()],
+            program_id,
         )?;
+        if *pda_account.key != pda {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        escrow_info.bump_seed = bump_seed;
+        // The PDA has no data, the lamports it holds have to keep it rent exempt on their own
+        if !rent.is_exempt(pda_account.lamports().saturating_add(offered_lamports), 0) {
+            return Err(EscrowError::NotRentExempt.log_and_convert());
+        }
 
-        msg!("Calling the token program to transfer account ownership ...");
-        // We are using other program(a token program) from our escrow program!
-        // This is called 'Cross-Program Invocation'.
-        invoke(
-            &owner_change_ix,
+        let system_program_account = next_account_info(account_info_iter)?;
+        if !system_program::check_id(system_program_account.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // Left out, the rent goes back to Alice
+        if let Some(rent_payer) = account_info_iter.next() {
+            escrow_info.rent_payer = *rent_payer.key;
+        }
+
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        let transfer_to_pda_ix =
+            system_instruction::transfer(initializer.key, pda_account.key, offered_lamports);
+
+        msg!("Calling the system program to move the offered lamports to the PDA...");
+        Self::invoke_in_order(
+            &transfer_to_pda_ix,
             &[
-                temp_token_account.clone(),
                 initializer.clone(),
-                token_program.clone(),
+                pda_account.clone(),
+                system_program_account.clone(),
             ],
         )?;
 
+        EscrowEvent::Initialized {
+            escrow: *escrow_account.key,
+            initializer: *initializer.key,
+            amount,
+            offered_amount: offered_lamports,
+            reference,
+        }
+        .emit()?;
+        Self::log_escrow_created(escrow_account.key, &pda, &pda);
+
         Ok(())
     }
 
+    // Bob takes `amount_expected_by_taker` of Token X in whichever way `kind` says
     fn process_exchange(
         accounts: &[AccountInfo],
         amount_expected_by_taker: u64,
+        kind: ExchangeKind,
         program_id: &Pubkey,
     ) -> ProgramResult {
+        if accounts.len() < EXCHANGE_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let accounts = Self::check_exchanges_allowed(accounts, program_id)?;
         let account_info_iter = &mut accounts.iter();
         let taker = next_account_info(account_info_iter)?;
 
         // This time, Bob is the signer
-        if !taker.is_signer {
+        if !taker.is_signer && kind != ExchangeKind::Simulate {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
@@ -131,126 +971,2857 @@ impl Processor {
         let takers_token_to_receive_account = next_account_info(account_info_iter)?;
         // Alice's temp Token X account
         let pdas_temp_token_account = next_account_info(account_info_iter)?;
-        let pdas_temp_token_account_info =
-            TokenAccount::unpack(&pdas_temp_token_account.try_borrow_data()?)?;
-        // Recreate PDA with seed word and programId
-        let (pda, bump_seed) = Pubkey::find_program_address(&[b"escrow"], program_id);
-
-        // The amount that Alice wants and Bob willing to send should be the same
-        if amount_expected_by_taker != pdas_temp_token_account_info.amount {
-            return Err(EscrowError::ExpectedAmountMismatch.into());
-        }
 
         // Alice's account
         let initializers_main_account = next_account_info(account_info_iter)?;
         // Alice's Token Y account
         let initializers_token_to_receive_account = next_account_info(account_info_iter)?;
+        // Passing one account for two roles turns a transfer between them into a no-op onto itself,
+        // while every check made on each role on its own still passes
+        let token_accounts = [
+            takers_sending_token_account,
+            takers_token_to_receive_account,
+            pdas_temp_token_account,
+            initializers_token_to_receive_account,
+        ];
+        for (i, token_account) in token_accounts.iter().enumerate() {
+            if token_accounts[i + 1..]
+                .iter()
+                .any(|other| other.key == token_account.key)
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+        }
         // Escrow state account
         let escrow_account = next_account_info(account_info_iter)?;
+        // Only the escrow accounts our program owns can be trusted to hold a real escrow state
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // An earlier exchange in the same transaction may have closed the escrow already.
+        // Its account lingers until the transaction ends, drained and, depending on the runtime, emptied.
+        if escrow_account.lamports() == 0 || escrow_account.data_is_empty() {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+        // The escrow's key only ever signs for its own creation. Signing here means someone holds it
+        // and is up to something, and we write the state back before any token moves.
+        if escrow_account.is_signer || !escrow_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
         // Deserialize the escrow data
-        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+        // `unpack` already refuses a zeroed state, but nothing below may ever run on one
+        if !escrow_info.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        // Lamports offered by Alice are taken with `ExchangeNativeOffer`
+        if escrow_info.mode == EscrowMode::NativeOffer {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+        // A basket is taken whole, there is no share of it to price
+        if kind == ExchangeKind::Partial && escrow_info.basket_len > 0 {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
 
         // Check if the temp account address stored in escrow account
-        // is same as one we recreated with seed word and programId
+        // is same as one Bob passed, before trusting anything in it, its balance included
         if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
             return Err(ProgramError::InvalidAccountData);
         }
+        let pdas_temp_token_account_info =
+            token::unpack_account(&pdas_temp_token_account.try_borrow_data()?)?;
 
-        // Check if the initializer(Alice) stored in escrow account
-        // is same as one Bob is said to be Alice.
-        if escrow_info.initializer_pubkey != *initializers_main_account.key {
-            return Err(ProgramError::InvalidAccountData);
+        let clock = Clock::get()?;
+        if kind == ExchangeKind::Finalize {
+            // Only the taker who prepared the exchange may finalize it, and only in time
+            if escrow_info.status != EscrowStatus::Settling
+                || escrow_info.prepared_taker != *taker.key
+            {
+                return Err(EscrowError::ExchangeNotPrepared.log_and_convert());
+            }
+            if clock.unix_timestamp > escrow_info.prepared_deadline {
+                return Err(EscrowError::EscrowExpired.log_and_convert());
+            }
+        } else if !escrow_info.is_open(clock.unix_timestamp) {
+            // Only an escrow waiting for takers can be taken, never one in the middle of settling
+            // or one another taker is still finalizing
+            return Err(EscrowError::InvalidState.log_and_convert());
         }
 
-        // Check if the Token Y account for Alice stored in escrow account
-        // is same as one Bob is said to be Alice's Token Y account.
-        if escrow_info.initializer_token_to_receive_account_pubkey
+        // A drained temp account would take Bob's Token Y for no Token X at all
+        if pdas_temp_token_account_info.amount == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        // The token program refuses to move anything in or out of a frozen account,
+        // so we tell that apart before it fails somewhere deep in a transfer
+        if pdas_temp_token_account_info.is_frozen() {
+            return Err(EscrowError::AccountFrozen.log_and_convert());
+        }
+        // Until a taker takes some of it or Alice deposits more, the temp account holds at least
+        // what she locked at init. Anyone may send Token X into it, but only we take any out.
+        if escrow_info.mode == EscrowMode::Custodial
+            && escrow_info.remaining_amount == escrow_info.offered_amount
+            && pdas_temp_token_account_info.amount < escrow_info.offered_amount
+        {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+
+        // Without partial fill, the amount that Alice offers and Bob expects should be the same.
+        // A delegated escrow offers only what the PDA was approved for, not the whole account.
+        let offered_amount = match escrow_info.mode {
+            EscrowMode::Custodial => pdas_temp_token_account_info.amount,
+            EscrowMode::Delegated => escrow_info.remaining_amount,
+            // Turned away above already, lamports are taken with `ExchangeNativeOffer`
+            EscrowMode::NativeOffer => {
+                return Err(EscrowError::InvalidInstruction.log_and_convert())
+            }
+        };
+        match kind {
+            // What Bob takes of it is checked against what is left further down
+            ExchangeKind::Partial => {}
+            // Someone else got there first and took a part of it
+            ExchangeKind::Full { min_amount_out } if offered_amount < min_amount_out => {
+                return Err(EscrowError::SlippageExceeded.log_and_convert());
+            }
+            _ if amount_expected_by_taker != offered_amount => {
+                return Err(EscrowError::ExpectedAmountMismatch.log_and_convert());
+            }
+            _ => {}
+        }
+
+        // Alice can't take her own trade
+        if *taker.key == escrow_info.initializer_pubkey {
+            return Err(EscrowError::SelfExchangeNotAllowed.log_and_convert());
+        }
+        // nor anyone but the taker she named for a private one
+        if !escrow_info.allows_taker(taker.key) {
+            return Err(EscrowError::UnauthorizedTaker.log_and_convert());
+        }
+
+        // Bob is too late if the deadline Alice set has already passed
+        if clock.unix_timestamp > escrow_info.expire_at {
+            return Err(EscrowError::EscrowExpired.log_and_convert());
+        }
+
+        // Recreate PDA with seed word, escrow account, the bump seed stored at init and programId
+        let bump_seed = escrow_info.bump_seed;
+        let pda = Pubkey::create_program_address(
+            &[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ],
+            program_id,
+        )?;
+
+        // The temp account must really be under the PDA's control,
+        // or else we would pay Bob with tokens that were never locked.
+        match escrow_info.mode {
+            EscrowMode::Custodial => {
+                if pdas_temp_token_account_info.owner != pda {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+            }
+            EscrowMode::Delegated => {
+                // Alice may have revoked or lowered the approval behind our back
+                if pdas_temp_token_account_info.delegate != COption::Some(pda) {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if pdas_temp_token_account_info.delegated_amount < amount_expected_by_taker {
+                    return Err(EscrowError::InsufficientFunds.log_and_convert());
+                }
+            }
+            EscrowMode::NativeOffer => {
+                return Err(EscrowError::InvalidInstruction.log_and_convert())
+            }
+        }
+
+        // Check if the initializer(Alice) stored in escrow account
+        // is same as one Bob is said to be Alice.
+        if escrow_info.initializer_pubkey != *initializers_main_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // Check if the Token Y account for Alice stored in escrow account
+        // is same as one Bob is said to be Alice's Token Y account.
+        if escrow_info.initializer_token_to_receive_account_pubkey
+            != *initializers_token_to_receive_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // Bob should send the very token Alice wants to receive...
+        let (takers_balance, initializers_token_to_receive_mint_key) = if escrow_info.accept_native
+        {
+            // ...or pay lamports from his main account, through the system program
+            if !system_program::check_id(takers_sending_token_account.key) {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            (taker.lamports(), None)
+        } else {
+            let takers_sending_token_account_info =
+                token::unpack_account(&takers_sending_token_account.try_borrow_data()?)?;
+            if !escrow_info.accepts_mint(&takers_sending_token_account_info.mint) {
+                return Err(EscrowError::UnacceptedMint.log_and_convert());
+            }
+            // Alice may have closed her Token Y account since. If it was her associated one,
+            // it is created again below, for the very mint Bob pays with.
+            if !initializers_token_to_receive_account.data_is_empty() {
+                let initializers_token_to_receive_data =
+                    initializers_token_to_receive_account.try_borrow_data()?;
+                // Transferring into a never initialized account fails deep in the token program
+                if !token::is_initialized_account(&initializers_token_to_receive_data) {
+                    return Err(EscrowError::ReceiveAccountUninitialized.log_and_convert());
+                }
+                let initializers_token_to_receive_account_info =
+                    token::unpack_account(&initializers_token_to_receive_data)?;
+                if takers_sending_token_account_info.mint
+                    != initializers_token_to_receive_account_info.mint
+                {
+                    return Err(EscrowError::MintMismatch.log_and_convert());
+                }
+                if initializers_token_to_receive_account_info.is_frozen() {
+                    return Err(EscrowError::AccountFrozen.log_and_convert());
+                }
+            }
+            if takers_sending_token_account_info.is_frozen() {
+                return Err(EscrowError::AccountFrozen.log_and_convert());
+            }
+            (
+                takers_sending_token_account_info.amount,
+                Some(takers_sending_token_account_info.mint),
+            )
+        };
+
+        // ...and receive the very token Alice put in the escrow.
+        let takers_token_to_receive_account_info =
+            token::unpack_account(&takers_token_to_receive_account.try_borrow_data()?)?;
+        if takers_token_to_receive_account_info.mint != pdas_temp_token_account_info.mint {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+        if takers_token_to_receive_account_info.is_frozen() {
+            return Err(EscrowError::AccountFrozen.log_and_convert());
+        }
+
+        // How much Token Y Bob owes Alice for the Token X he takes.
+        // `expected_amount` is always the price for the whole `remaining_amount`,
+        // so a partial fill pays `amount * expected_amount / remaining_amount`.
+        let amount_to_initializer = match kind {
+            ExchangeKind::Partial => {
+                if amount_expected_by_taker == 0
+                    || amount_expected_by_taker > escrow_info.remaining_amount
+                {
+                    return Err(EscrowError::ExpectedAmountMismatch.log_and_convert());
+                }
+
+                // Rounding would let Bob underpay (or overpay) Alice, so only exact shares are accepted
+                if !math::divides_evenly(
+                    amount_expected_by_taker,
+                    escrow_info.expected_amount,
+                    escrow_info.remaining_amount,
+                )
+                .map_err(EscrowError::log_and_convert)?
+                {
+                    return Err(EscrowError::ExpectedAmountMismatch.log_and_convert());
+                }
+                math::proportional(
+                    amount_expected_by_taker,
+                    escrow_info.expected_amount,
+                    escrow_info.remaining_amount,
+                )
+                .map_err(EscrowError::log_and_convert)?
+            }
+            _ => escrow_info.expected_amount,
+        };
+
+        // Fail early with a clear error rather than deep inside the token or system program
+        if takers_balance < amount_to_initializer {
+            return Err(EscrowError::InsufficientFunds.log_and_convert());
+        }
+
+        // The token program
+        let token_program = next_account_info(account_info_iter)?;
+        // We hand over Alice's and Bob's tokens to whatever program this is,
+        // so it had better be the one the escrow was made with
+        if *token_program.key != escrow_info.token_program {
+            return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+        }
+        // Temp Token X account for Alice
+        let pda_account = next_account_info(account_info_iter)?;
+        // Every transfer of Token X names its mint
+        let token_x_mint = next_account_info(account_info_iter)?;
+        let token_x_decimals = Self::mint_decimals(
+            token_x_mint,
+            &pdas_temp_token_account_info.mint,
+            token_program.key,
+        )?;
+
+        // The mint of Alice's Token Y account must still have the decimals `expected_amount` was set for
+        let initializers_token_to_receive_mint = match initializers_token_to_receive_mint_key {
+            None => None,
+            Some(initializers_token_to_receive_mint_key) => {
+                let initializers_token_to_receive_mint = next_account_info(account_info_iter)?;
+                if *initializers_token_to_receive_mint.key != initializers_token_to_receive_mint_key
+                {
+                    return Err(EscrowError::MintMismatch.log_and_convert());
+                }
+                if *initializers_token_to_receive_mint.owner != escrow_info.token_program {
+                    return Err(ProgramError::IncorrectProgramId);
+                }
+                let initializers_token_to_receive_mint_info =
+                    token::unpack_mint(&initializers_token_to_receive_mint.try_borrow_data()?)?;
+                if !escrow_info.priced_in_decimals(initializers_token_to_receive_mint_info.decimals)
+                {
+                    return Err(EscrowError::DecimalsMismatch.log_and_convert());
+                }
+                Some((
+                    initializers_token_to_receive_mint,
+                    initializers_token_to_receive_mint_info.decimals,
+                ))
+            }
+        };
+
+        // Bob pays for Alice's associated Token Y account if it is gone. Everything it takes is
+        // checked here, but the account is only created once every other check passed too.
+        let associated_token_creation = match initializers_token_to_receive_mint {
+            Some((initializers_token_to_receive_mint, _))
+                if initializers_token_to_receive_account.data_is_empty() =>
+            {
+                let system_program_account = next_account_info(account_info_iter)?;
+                if !system_program::check_id(system_program_account.key) {
+                    return Err(ProgramError::IncorrectProgramId);
+                }
+                let rent_sysvar_account = next_account_info(account_info_iter)?;
+                let associated_token_program = next_account_info(account_info_iter)?;
+                if !token::associated_token::check_id(associated_token_program.key) {
+                    return Err(ProgramError::IncorrectProgramId);
+                }
+                // Any other account couldn't be told apart from one that never existed
+                if token::associated_token_address(
+                    initializers_main_account.key,
+                    initializers_token_to_receive_mint.key,
+                    token_program.key,
+                ) != *initializers_token_to_receive_account.key
+                {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                // Bob funds the new account
+                if !taker.is_writable {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                Some((
+                    initializers_token_to_receive_mint,
+                    system_program_account,
+                    rent_sysvar_account,
+                    associated_token_program,
+                ))
+            }
+            _ => None,
+        };
+
+        // The marketplace's cut comes out of the Token X Bob takes, into its own Token X account
+        let taker_fee = math::proportional(
+            amount_expected_by_taker,
+            u64::from(escrow_info.taker_fee_bps),
+            math::BASIS_POINTS,
+        )
+        .map_err(EscrowError::log_and_convert)?;
+        let fee_token_account = if escrow_info.taker_fee_bps > 0 {
+            let fee_token_account = next_account_info(account_info_iter)?;
+            if escrow_info.fee_token_account != *fee_token_account.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let fee_token_account_info =
+                token::unpack_account(&fee_token_account.try_borrow_data()?)?;
+            if fee_token_account_info.mint != pdas_temp_token_account_info.mint {
+                return Err(EscrowError::MintMismatch.log_and_convert());
+            }
+            if fee_token_account_info.is_frozen() {
+                return Err(EscrowError::AccountFrozen.log_and_convert());
+            }
+            Some(fee_token_account)
+        } else {
+            None
+        };
+
+        // Bob gets all of every other Token X Alice bundled, each into his own account for it
+        let mut basket = Vec::with_capacity(escrow_info.basket().len());
+        for basket_temp_token_account_key in escrow_info.basket() {
+            let basket_temp_token_account = next_account_info(account_info_iter)?;
+            let takers_basket_token_account = next_account_info(account_info_iter)?;
+            let basket_mint = next_account_info(account_info_iter)?;
+            if *basket_temp_token_account.key != *basket_temp_token_account_key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let basket_temp_token_account_info =
+                token::unpack_account(&basket_temp_token_account.try_borrow_data()?)?;
+            // Just like the main temp account, it must really be under the PDA's control
+            if basket_temp_token_account_info.owner != pda {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if basket_temp_token_account_info.is_frozen() {
+                return Err(EscrowError::AccountFrozen.log_and_convert());
+            }
+            let takers_basket_token_account_info =
+                token::unpack_account(&takers_basket_token_account.try_borrow_data()?)?;
+            if takers_basket_token_account_info.mint != basket_temp_token_account_info.mint {
+                return Err(EscrowError::MintMismatch.log_and_convert());
+            }
+            if takers_basket_token_account_info.is_frozen() {
+                return Err(EscrowError::AccountFrozen.log_and_convert());
+            }
+            let basket_decimals = Self::mint_decimals(
+                basket_mint,
+                &basket_temp_token_account_info.mint,
+                token_program.key,
+            )?;
+            basket.push((
+                basket_temp_token_account,
+                takers_basket_token_account,
+                basket_mint,
+                basket_temp_token_account_info.amount,
+                basket_decimals,
+            ));
+        }
+
+        if kind == ExchangeKind::Simulate {
+            return Ok(());
+        }
+
+        // Every check passed, mark the escrow before any token moves.
+        // Whatever preparation there was is used up or lapsed by now.
+        escrow_info.status = EscrowStatus::Settling;
+        escrow_info.prepared_taker = Pubkey::default();
+        escrow_info.prepared_deadline = 0;
+        Escrow::pack(
+            escrow_info.clone(),
+            &mut escrow_account.try_borrow_mut_data()?,
+        )?;
+
+        if let Some((
+            initializers_token_to_receive_mint,
+            system_program_account,
+            rent_sysvar_account,
+            associated_token_program,
+        )) = associated_token_creation
+        {
+            let create_account_ix = token::create_associated_token_account(
+                taker.key,                              // Bob pays the rent
+                initializers_main_account.key,          // of Alice's
+                initializers_token_to_receive_mint.key, // associated Token Y account
+                token_program.key,
+            );
+
+            msg!(
+                "Calling the associated token account program to create Alice's Token Y account..."
+            );
+
+            Self::invoke_in_order(
+                &create_account_ix,
+                &[
+                    taker.clone(),
+                    initializers_token_to_receive_account.clone(),
+                    initializers_main_account.clone(),
+                    initializers_token_to_receive_mint.clone(),
+                    system_program_account.clone(),
+                    token_program.clone(),
+                    rent_sysvar_account.clone(),
+                    associated_token_program.clone(),
+                ],
+            )?;
+        }
+
+        if let Some((initializers_token_to_receive_mint, token_y_decimals)) =
+            initializers_token_to_receive_mint
+        {
+            // Instruction that transfers amount of token to initializer(Alice)
+            let transfer_to_initializer_ix = token::transfer_checked(
+                token_program.key,                      // Tell token program to transfer Y tokens
+                takers_sending_token_account.key,       // From Bob's Token Y account
+                initializers_token_to_receive_mint.key, // of the Token Y mint
+                initializers_token_to_receive_account.key, // To Alice's Token Y account
+                taker.key,                              // Authorized by Bob's main account
+                &[&taker.key],                          // Signed by Bob's main account
+                amount_to_initializer,
+                token_y_decimals,
+            )?;
+
+            msg!("Calling the token program to transfer tokens to escrow's initializer...");
+
+            // invoke token program to execute transfer ix
+            Self::invoke_in_order(
+                &transfer_to_initializer_ix,
+                &[
+                    takers_sending_token_account.clone(),
+                    initializers_token_to_receive_mint.clone(),
+                    initializers_token_to_receive_account.clone(),
+                    taker.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+        } else {
+            // Bob signed the transaction, so he can pay Alice's main account directly
+            let transfer_to_initializer_ix = system_instruction::transfer(
+                taker.key,                                 // From Bob's main account
+                initializers_token_to_receive_account.key, // To Alice's main account
+                amount_to_initializer,
+            );
+
+            msg!("Calling the system program to transfer lamports to escrow's initializer...");
+
+            Self::invoke_in_order(
+                &transfer_to_initializer_ix,
+                &[
+                    taker.clone(),
+                    initializers_token_to_receive_account.clone(),
+                    takers_sending_token_account.clone(),
+                ],
+            )?;
+        }
+
+        if let Some(fee_token_account) = fee_token_account {
+            let transfer_to_fee_ix = token::transfer_checked(
+                token_program.key,           // Tell token program to transfer Token X
+                pdas_temp_token_account.key, // From Alice's temp Token X account
+                token_x_mint.key,            // of the Token X mint
+                fee_token_account.key,       // To the marketplace's Token X account
+                &pda,                        // authorized by the PDA, as owner or delegate
+                &[&pda],                     // signed by the PDA
+                taker_fee,                   // for the marketplace's cut
+                token_x_decimals,
+            )?;
+
+            msg!("Calling the token program to transfer the taker fee...");
+
+            Self::invoke_signed_in_order(
+                &transfer_to_fee_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    token_x_mint.clone(),
+                    fee_token_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+        }
+
+        //
+        let transfer_to_taker_ix = token::transfer_checked(
+            token_program.key,                    // Tell token program to transfer Token X
+            pdas_temp_token_account.key,          // From Alice's temp Token X account
+            token_x_mint.key,                     // of the Token X mint
+            takers_token_to_receive_account.key,  // To Bob's Token X account
+            &pda,                                 // authorized by the PDA, as owner or delegate
+            &[&pda],                              // signed by the PDA
+            amount_expected_by_taker - taker_fee, // for this amount, less the marketplace's cut
+            token_x_decimals,
+        )?;
+
+        msg!("Calling the token program to transfer tokens to the taker...");
+
+        // Since the signer is PDA which has no private key,
+        // we have to use `invoke_signed` and give the seed and bump seed.
+        Self::invoke_signed_in_order(
+            &transfer_to_taker_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                token_x_mint.clone(),
+                takers_token_to_receive_account.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ]], // this will be used to recreate the PDA
+        )?;
+
+        // The rest of the basket goes along in the same transaction, or nothing does
+        for (
+            basket_temp_token_account,
+            takers_basket_token_account,
+            basket_mint,
+            amount,
+            decimals,
+        ) in basket
+        {
+            let transfer_basket_ix = token::transfer_checked(
+                token_program.key,               // Tell token program to transfer the basket token
+                basket_temp_token_account.key,   // From Alice's basket temp account
+                basket_mint.key,                 // of its mint
+                takers_basket_token_account.key, // To Bob's account for it
+                &pda,                            // authorized by pda
+                &[&pda],                         // signed by pda
+                amount,                          // for the whole balance
+                decimals,
+            )?;
+
+            msg!("Calling the token program to transfer basket tokens to the taker...");
+
+            Self::invoke_signed_in_order(
+                &transfer_basket_ix,
+                &[
+                    basket_temp_token_account.clone(),
+                    basket_mint.clone(),
+                    takers_basket_token_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+
+            let close_basket_acc_ix = token::close_account(
+                token_program.key,             // tell token program to close
+                basket_temp_token_account.key, // the basket temp account
+                initializers_main_account.key, // And the remaining balance should be sent to Alice
+                &pda,                          // authorized by pda
+                &[&pda],                       // signed by pda
+            )?;
+
+            msg!("Calling the token program to close a basket temp account...");
+
+            Self::invoke_signed_in_order(
+                &close_basket_acc_ix,
+                &[
+                    basket_temp_token_account.clone(),
+                    initializers_main_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+        }
+        // Whatever is left of the escrow, its basket is gone
+        escrow_info.basket_len = 0;
+        escrow_info.basket_temp_token_accounts = [Pubkey::default(); Escrow::MAX_BASKET_LEN];
+
+        EscrowEvent::Exchanged {
+            escrow: *escrow_account.key,
+            taker: *taker.key,
+            amount: amount_expected_by_taker,
+            reference: escrow_info.reference,
+        }
+        .emit()?;
+        // Every transfer between Alice and Bob went through, so these are the amounts that moved
+        EscrowEvent::AmountsTransferred {
+            escrow: *escrow_account.key,
+            token_x_amount: amount_expected_by_taker - taker_fee,
+            token_y_amount: amount_to_initializer,
+        }
+        .emit()?;
+
+        // Some Token X's are still left for other takers, so keep the escrow open,
+        // unless what is left is no more than the dust Alice would rather have back
+        let left_over = escrow_info
+            .remaining_amount
+            .saturating_sub(amount_expected_by_taker);
+        if left_over > escrow_info.dust_threshold {
+            escrow_info.remaining_amount = left_over;
+            escrow_info.expected_amount -= amount_to_initializer;
+            escrow_info.status = EscrowStatus::Open;
+            Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+            return Ok(());
+        }
+
+        // Lamports are swept into Alice's main account from here on. It was checked long before,
+        // but checking again right where it pays out keeps any later change from sending them elsewhere.
+        if *initializers_main_account.key != escrow_info.initializer_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // Token X's are all sent. We don't need temp Token X account anymore.
+        // We should close it, unless it is Alice's own account of a delegated escrow,
+        // where any dust simply stays with her.
+        if escrow_info.mode == EscrowMode::Custodial {
+            // The dust goes back to Alice's own Token X account, or else the temp account can't be closed
+            if escrow_info.dust_threshold > 0 {
+                let initializers_token_x_account = next_account_info(account_info_iter)?;
+                let initializers_token_x_account_info =
+                    token::unpack_account(&initializers_token_x_account.try_borrow_data()?)?;
+                if initializers_token_x_account_info.owner != escrow_info.initializer_pubkey {
+                    return Err(EscrowError::InvalidOwner.log_and_convert());
+                }
+                if initializers_token_x_account_info.mint != pdas_temp_token_account_info.mint {
+                    return Err(EscrowError::MintMismatch.log_and_convert());
+                }
+
+                if left_over > 0 {
+                    let transfer_dust_ix = token::transfer_checked(
+                        token_program.key,                // Tell token program to transfer Token X
+                        pdas_temp_token_account.key,      // From Alice's temp Token X account
+                        token_x_mint.key,                 // of the Token X mint
+                        initializers_token_x_account.key, // Back to Alice's Token X account
+                        &pda,                             // authorized by pda
+                        &[&pda],                          // signed by pda
+                        left_over,                        // for the dust nobody took
+                        token_x_decimals,
+                    )?;
+
+                    msg!("Calling the token program to return the dust to the initializer...");
+
+                    Self::invoke_signed_in_order(
+                        &transfer_dust_ix,
+                        &[
+                            pdas_temp_token_account.clone(),
+                            token_x_mint.clone(),
+                            initializers_token_x_account.clone(),
+                            pda_account.clone(),
+                            token_program.clone(),
+                        ],
+                        &[&[
+                            config::ESCROW_SEED,
+                            escrow_account.key.as_ref(),
+                            &[bump_seed],
+                        ]],
+                    )?;
+                }
+            }
+
+            let close_pdas_temp_acc_ix = token::close_account(
+                token_program.key,             // tell token program to close
+                pdas_temp_token_account.key,   // temp Token X account
+                initializers_main_account.key, // And the remaining balance should be sent to Alice
+                &pda,                          // authorized by pda
+                &[&pda],                       // signed by pda
+            )?;
+
+            msg!("Calling the token program to close pda's temp account...");
+
+            // Closing the account requires signing from escrow account
+            Self::invoke_signed_in_order(
+                &close_pdas_temp_acc_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    initializers_main_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+        }
+
+        // The dust of a delegated escrow stays in Alice's account, and so would the PDA's
+        // approval of it. Only Token-2022 lets a delegate revoke itself, SPL Token leaves
+        // that to Alice, whose approval then covers no more than the dust.
+        if escrow_info.mode == EscrowMode::Delegated
+            && left_over > 0
+            && *token_program.key == spl_token_2022::id()
+        {
+            let revoke_ix = token::revoke(
+                token_program.key,           // Tell token program to revoke
+                pdas_temp_token_account.key, // the PDA's approval on Alice's Token X account
+                &pda,                        // authorized by pda
+                &[&pda],                     // signed by pda
+            )?;
+
+            msg!("Calling the token program to revoke the PDA's delegation...");
+
+            Self::invoke_signed_in_order(
+                &revoke_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+        }
+
+        Self::close_taken_escrow(
+            escrow_account,
+            &escrow_info,
+            initializers_main_account,
+            account_info_iter,
+            program_id,
+        )
+    }
+
+    // Closes an escrow that was taken in full. The marketplace takes its protocol fee out of the
+    // escrow's lamports, and the maker rebate goes to Alice. Reads the fee account, the rent payer
+    // and the fee vault, as far as the escrow needs them.
+    // Only the fee vault, found from `program_id`, is left out of builds without `maker-rebate`.
+    #[cfg_attr(not(feature = "maker-rebate"), allow(unused_variables))]
+    fn close_taken_escrow<'a, 'b>(
+        escrow_account: &'b AccountInfo<'a>,
+        escrow_info: &Escrow,
+        initializers_main_account: &'b AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // The marketplace takes its cut out of the escrow's lamports first,
+        // and whoever paid the rent gets back exactly what is left after it.
+        let initializers_lamports = escrow_account
+            .lamports()
+            .checked_sub(escrow_info.fee_lamports)
+            .ok_or_else(|| EscrowError::AmountOverflow.log_and_convert())?;
+        if escrow_info.fee_lamports > 0 {
+            let fee_account = next_account_info(account_info_iter)?;
+            if escrow_info.fee_account != *fee_account.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            msg!("Transferring the protocol fee...");
+
+            **fee_account.lamports.borrow_mut() = fee_account
+                .lamports()
+                .checked_add(escrow_info.fee_lamports)
+                .ok_or_else(|| EscrowError::AmountOverflow.log_and_convert())?;
+            **escrow_account.lamports.borrow_mut() = initializers_lamports;
+        }
+
+        let rent_collector =
+            Self::rent_collector(escrow_info, initializers_main_account, account_info_iter)?;
+
+        // Deployments rewarding makers top Alice up out of the fee vault, as far as it goes,
+        // so that an empty vault never holds up a trade
+        #[cfg(feature = "maker-rebate")]
+        {
+            let fee_vault = next_account_info(account_info_iter)?;
+            let (fee_vault_key, fee_vault_bump) =
+                Pubkey::find_program_address(&[config::FEE_VAULT_SEED], program_id);
+            if *fee_vault.key != fee_vault_key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let system_program_account = next_account_info(account_info_iter)?;
+            if !system_program::check_id(system_program_account.key) {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            let rebate = config::MAKER_REBATE_LAMPORTS.min(fee_vault.lamports());
+            if rebate > 0 {
+                let rebate_ix = system_instruction::transfer(
+                    fee_vault.key,                 // From the fee vault
+                    initializers_main_account.key, // to Alice's main account
+                    rebate,
+                );
+
+                msg!("Calling the system program to pay the maker rebate...");
+                Self::invoke_signed_in_order(
+                    &rebate_ix,
+                    &[
+                        fee_vault.clone(),
+                        initializers_main_account.clone(),
+                        system_program_account.clone(),
+                    ],
+                    &[&[config::FEE_VAULT_SEED, &[fee_vault_bump]]],
+                )?;
+            }
+        }
+
+        // Closing the account is what makes the escrow `Closed`, there is no state left to mark
+        msg!("Closing the escrow account...");
+
+        Self::close_account(escrow_account, rent_collector)
+    }
+
+    // Bob pays Token Y for all of the lamports Alice offered. There is no partial fill,
+    // the PDA holding them is a plain system account that must not be left short of its rent.
+    fn process_exchange_native_offer(
+        accounts: &[AccountInfo],
+        amount_expected_by_taker: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < EXCHANGE_NATIVE_OFFER_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let accounts = Self::check_exchanges_allowed(accounts, program_id)?;
+        let account_info_iter = &mut accounts.iter();
+        // Bob, receiving the lamports in his main account
+        let taker = next_account_info(account_info_iter)?;
+
+        if !taker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Bob's Token Y account
+        let takers_sending_token_account = next_account_info(account_info_iter)?;
+        // The PDA holding Alice's lamports
+        let pda_account = next_account_info(account_info_iter)?;
+        // Alice's account
+        let initializers_main_account = next_account_info(account_info_iter)?;
+        // Alice's Token Y account
+        let initializers_token_to_receive_account = next_account_info(account_info_iter)?;
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // Same as `Exchange`, an escrow closed earlier in the transaction can't be taken again
+        if escrow_account.lamports() == 0 || escrow_account.data_is_empty() {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+        if escrow_account.is_signer || !escrow_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        // Token X offered by Alice is taken with `Exchange`
+        if escrow_info.mode != EscrowMode::NativeOffer {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+        if escrow_info.temp_token_account_pubkey != *pda_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let clock = Clock::get()?;
+        if !escrow_info.is_open(clock.unix_timestamp) {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+        // Bob takes all of the lamports, and has to know how many that is
+        if amount_expected_by_taker != escrow_info.remaining_amount {
+            return Err(EscrowError::ExpectedAmountMismatch.log_and_convert());
+        }
+        if *taker.key == escrow_info.initializer_pubkey {
+            return Err(EscrowError::SelfExchangeNotAllowed.log_and_convert());
+        }
+        if !escrow_info.allows_taker(taker.key) {
+            return Err(EscrowError::UnauthorizedTaker.log_and_convert());
+        }
+        if clock.unix_timestamp > escrow_info.expire_at {
+            return Err(EscrowError::EscrowExpired.log_and_convert());
+        }
+
+        if escrow_info.initializer_pubkey != *initializers_main_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow_info.initializer_token_to_receive_account_pubkey
             != *initializers_token_to_receive_account.key
         {
             return Err(ProgramError::InvalidAccountData);
         }
 
-        // The token program
+        // Bob should send the very token Alice wants to receive
+        let takers_sending_token_account_info =
+            token::unpack_account(&takers_sending_token_account.try_borrow_data()?)?;
+        if !escrow_info.accepts_mint(&takers_sending_token_account_info.mint) {
+            return Err(EscrowError::UnacceptedMint.log_and_convert());
+        }
+        let initializers_token_to_receive_account_info = {
+            let data = initializers_token_to_receive_account.try_borrow_data()?;
+            if !token::is_initialized_account(&data) {
+                return Err(EscrowError::ReceiveAccountUninitialized.log_and_convert());
+            }
+            token::unpack_account(&data)?
+        };
+        if takers_sending_token_account_info.mint != initializers_token_to_receive_account_info.mint
+        {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+        if takers_sending_token_account_info.is_frozen()
+            || initializers_token_to_receive_account_info.is_frozen()
+        {
+            return Err(EscrowError::AccountFrozen.log_and_convert());
+        }
+        if takers_sending_token_account_info.amount < escrow_info.expected_amount {
+            return Err(EscrowError::InsufficientFunds.log_and_convert());
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != escrow_info.token_program {
+            return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+        }
+        // The mint of Alice's Token Y account must still have the decimals `expected_amount` was set for
+        let initializers_token_to_receive_mint = next_account_info(account_info_iter)?;
+        if *initializers_token_to_receive_mint.key != takers_sending_token_account_info.mint {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+        if *initializers_token_to_receive_mint.owner != escrow_info.token_program {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let initializers_token_to_receive_mint_info =
+            token::unpack_mint(&initializers_token_to_receive_mint.try_borrow_data()?)?;
+        if !escrow_info.priced_in_decimals(initializers_token_to_receive_mint_info.decimals) {
+            return Err(EscrowError::DecimalsMismatch.log_and_convert());
+        }
+        let system_program_account = next_account_info(account_info_iter)?;
+        if !system_program::check_id(system_program_account.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // Every check passed, mark the escrow before anything moves
+        escrow_info.status = EscrowStatus::Settling;
+        escrow_info.prepared_taker = Pubkey::default();
+        escrow_info.prepared_deadline = 0;
+        Escrow::pack(
+            escrow_info.clone(),
+            &mut escrow_account.try_borrow_mut_data()?,
+        )?;
+
+        let transfer_to_initializer_ix = token::transfer_checked(
+            token_program.key,                      // Tell token program to transfer Y tokens
+            takers_sending_token_account.key,       // From Bob's Token Y account
+            initializers_token_to_receive_mint.key, // of the Token Y mint
+            initializers_token_to_receive_account.key, // To Alice's Token Y account
+            taker.key,                              // Authorized by Bob's main account
+            &[&taker.key],                          // Signed by Bob's main account
+            escrow_info.expected_amount,
+            initializers_token_to_receive_mint_info.decimals,
+        )?;
+
+        msg!("Calling the token program to transfer tokens to escrow's initializer...");
+        Self::invoke_in_order(
+            &transfer_to_initializer_ix,
+            &[
+                takers_sending_token_account.clone(),
+                initializers_token_to_receive_mint.clone(),
+                initializers_token_to_receive_account.clone(),
+                taker.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        msg!("Calling the system program to transfer lamports to the taker...");
+        Self::transfer_from_pda(
+            pda_account,
+            taker,
+            system_program_account,
+            escrow_info.remaining_amount,
+            escrow_account.key,
+            escrow_info.bump_seed,
+        )?;
+        // Anything else sent to the PDA since goes back to Alice, leaving it empty
+        let left_over = pda_account.lamports();
+        if left_over > 0 {
+            msg!("Calling the system program to return what is left on the PDA...");
+            Self::transfer_from_pda(
+                pda_account,
+                initializers_main_account,
+                system_program_account,
+                left_over,
+                escrow_account.key,
+                escrow_info.bump_seed,
+            )?;
+        }
+
+        EscrowEvent::Exchanged {
+            escrow: *escrow_account.key,
+            taker: *taker.key,
+            amount: amount_expected_by_taker,
+            reference: escrow_info.reference,
+        }
+        .emit()?;
+
+        Self::close_taken_escrow(
+            escrow_account,
+            &escrow_info,
+            initializers_main_account,
+            account_info_iter,
+            program_id,
+        )
+    }
+
+    // Runs every check of a full exchange, then logs its outcome with `sol_log_data`:
+    // the code `u64::from(ProgramError)` gives the error, 0 when the exchange would go through.
+    // The accounts only read once the escrow closes, the fee account and rent payer, aren't checked.
+    fn process_simulate_exchange(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let result = Self::process_exchange(accounts, amount, ExchangeKind::Simulate, program_id);
+        let code = result.clone().err().map_or(0, u64::from);
+        sol_log_data(&[&code.to_le_bytes()]);
+        result
+    }
+
+    fn process_prepare_exchange(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < PREPARE_EXCHANGE_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        let taker = next_account_info(account_info_iter)?;
+
+        if !taker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        // Someone else may hold the escrow, until they let their preparation lapse
+        let clock = Self::clock(account_info_iter.next())?;
+        if !escrow_info.is_open(clock.unix_timestamp) {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+        if clock.unix_timestamp > escrow_info.expire_at {
+            return Err(EscrowError::EscrowExpired.log_and_convert());
+        }
+        if *taker.key == escrow_info.initializer_pubkey {
+            return Err(EscrowError::SelfExchangeNotAllowed.log_and_convert());
+        }
+        // A private escrow can't be locked up by anyone but its taker
+        if !escrow_info.allows_taker(taker.key) {
+            return Err(EscrowError::UnauthorizedTaker.log_and_convert());
+        }
+        // `FinalizeExchange` only settles Token X
+        if escrow_info.mode == EscrowMode::NativeOffer {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+
+        // Bob gets a while to finalize, but never past the escrow's own deadline
+        escrow_info.status = EscrowStatus::Settling;
+        escrow_info.prepared_taker = *taker.key;
+        escrow_info.prepared_deadline = clock
+            .unix_timestamp
+            .saturating_add(config::PREPARED_EXCHANGE_DURATION)
+            .min(escrow_info.expire_at);
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    // Walks the accounts after the keeper and the token program seven at a time,
+    // one escrow per group, until there are none left. Escrows that can't be reaped
+    // are logged and left alone, so one taken or cancelled in the meantime doesn't hold up the rest.
+    fn process_reap_expired(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < REAP_EXPIRED_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Anyone may clean up, they only pay for the transaction, so they needn't sign
+        let _keeper = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let clock = Clock::get()?;
+        let mut reaped = 0;
+        let mut last_skipped = None;
+
+        while let Some(escrow_account) = account_info_iter.next() {
+            let pdas_temp_token_account = next_account_info(account_info_iter)?;
+            let pda_account = next_account_info(account_info_iter)?;
+            let initializers_main_account = next_account_info(account_info_iter)?;
+            let initializers_token_x_account = next_account_info(account_info_iter)?;
+            let rent_collector = next_account_info(account_info_iter)?;
+            let token_x_mint = next_account_info(account_info_iter)?;
+
+            let escrow_info =
+                match Self::reapable_escrow(escrow_account, clock.unix_timestamp, program_id) {
+                    Ok(escrow_info) => escrow_info,
+                    Err(error) => {
+                        msg!("Skipping escrow {}: {}", escrow_account.key, error);
+                        last_skipped = Some(error);
+                        continue;
+                    }
+                };
+
+            if escrow_info.initializer_pubkey != *initializers_main_account.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            // The group always has a rent payer slot, Alice's main account again when she paid
+            let rent_payer = if escrow_info.rent_payer == Pubkey::default() {
+                escrow_info.initializer_pubkey
+            } else {
+                escrow_info.rent_payer
+            };
+            if *rent_collector.key != rent_payer {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if *token_program.key != escrow_info.token_program {
+                return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+            }
+
+            // Alice didn't pick the refund account this time, so it had better be hers
+            if *initializers_token_x_account.owner != escrow_info.token_program {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            if token::unpack_account(&initializers_token_x_account.try_borrow_data()?)?.owner
+                != escrow_info.initializer_pubkey
+            {
+                return Err(EscrowError::InvalidOwner.log_and_convert());
+            }
+
+            let bump_seed = escrow_info.bump_seed;
+            let pda = Pubkey::create_program_address(
+                &[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ],
+                program_id,
+            )?;
+            let pdas_temp_token_account_info =
+                token::unpack_account(&pdas_temp_token_account.try_borrow_data()?)?;
+            let token_x_decimals = Self::mint_decimals(
+                token_x_mint,
+                &pdas_temp_token_account_info.mint,
+                token_program.key,
+            )?;
+
+            // Give every Token X in the temp account back to Alice
+            let transfer_to_initializer_ix = token::transfer_checked(
+                token_program.key,                   // Tell token program to transfer Token X
+                pdas_temp_token_account.key,         // From Alice's temp Token X account
+                token_x_mint.key,                    // of the Token X mint
+                initializers_token_x_account.key,    // Back to Alice's Token X account
+                &pda,                                // authorized by pda
+                &[&pda],                             // signed by pda
+                pdas_temp_token_account_info.amount, // for the whole balance
+                token_x_decimals,
+            )?;
+
+            msg!("Calling the token program to refund tokens to the initializer...");
+
+            Self::invoke_signed_in_order(
+                &transfer_to_initializer_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    token_x_mint.clone(),
+                    initializers_token_x_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+
+            let close_pdas_temp_acc_ix = token::close_account(
+                token_program.key,             // tell token program to close
+                pdas_temp_token_account.key,   // temp Token X account
+                initializers_main_account.key, // And the remaining balance should be sent to Alice
+                &pda,                          // authorized by pda
+                &[&pda],                       // signed by pda
+            )?;
+
+            msg!("Calling the token program to close pda's temp account...");
+
+            Self::invoke_signed_in_order(
+                &close_pdas_temp_acc_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    initializers_main_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+
+            msg!("Closing the escrow account...");
+
+            Self::close_account(escrow_account, rent_collector)?;
+            reaped += 1;
+        }
+
+        // A batch that reaped nothing at all says why the last escrow in it was skipped
+        match last_skipped {
+            Some(error) if reaped == 0 => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // The escrow's state, if anyone may reap it by now
+    fn reapable_escrow(
+        escrow_account: &AccountInfo,
+        unix_timestamp: i64,
+        program_id: &Pubkey,
+    ) -> Result<Escrow, EscrowError> {
+        // Taken or cancelled since the batch was put together
+        if *escrow_account.owner != *program_id {
+            return Err(EscrowError::InvalidState);
+        }
+        let escrow_info = escrow_account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| Escrow::unpack(&data).ok())
+            .ok_or(EscrowError::InvalidState)?;
+        // Until the deadline passes, the trade is still Alice's to keep open
+        if unix_timestamp <= escrow_info.expire_at {
+            return Err(EscrowError::EscrowNotExpired);
+        }
+        // and for a while after that, still hers alone to cancel
+        if unix_timestamp <= escrow_info.grace_ends_at() {
+            return Err(EscrowError::InGracePeriod);
+        }
+        // Only Alice could revoke the approval on her own account,
+        // and the lamports of a native offer need the system program to move
+        if escrow_info.mode != EscrowMode::Custodial {
+            return Err(EscrowError::InvalidState);
+        }
+        // The basket accounts go back to Alice as they are, which only her `CancelEscrow` does
+        if escrow_info.basket_len > 0 {
+            return Err(EscrowError::InvalidState);
+        }
+        Ok(escrow_info)
+    }
+
+    fn process_cancel_escrow(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < CANCEL_ESCROW_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice can call off her own trade
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Alice's temp Token X account, currently owned by the PDA
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        // Alice's original Token X account to get the refund
+        let initializers_token_x_account = next_account_info(account_info_iter)?;
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        // Nobody but the initializer stored in escrow account can cancel the trade
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        // We hand over Alice's tokens to whatever program this is,
+        // so it had better be the one the escrow was made with
+        if *token_program.key != escrow_info.token_program {
+            return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+        }
+        let pda_account = next_account_info(account_info_iter)?;
+        // Only tokens held in custody move, and every transfer of them names their mint
+        let token_x_mint = if escrow_info.mode == EscrowMode::Custodial {
+            Some(next_account_info(account_info_iter)?)
+        } else {
+            None
+        };
+
+        // Alice promised to keep the offer open until the lockup is over.
+        // Takers are still welcome in the meantime, only cancelling is locked.
+        let clock = Self::next_clock_if_passed(account_info_iter)?;
+        if clock.unix_timestamp < escrow_info.not_before {
+            return Err(EscrowError::LockupActive.log_and_convert());
+        }
+        // An exchange someone prepared doesn't hold her back: anyone may prepare one for free,
+        // and again each time it lapses, which would otherwise keep her tokens here for good
+
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // The PDA itself holds the lamports of a native offer, with the system program
+        // in place of Alice's Token X account to move them back to her main account
+        if escrow_info.mode == EscrowMode::NativeOffer {
+            let system_program_account = initializers_token_x_account;
+            if !system_program::check_id(system_program_account.key) {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            msg!("Calling the system program to refund lamports to the initializer...");
+            Self::transfer_from_pda(
+                pdas_temp_token_account,
+                initializer,
+                system_program_account,
+                pdas_temp_token_account.lamports(),
+                escrow_account.key,
+                escrow_info.bump_seed,
+            )?;
+
+            let rent_collector =
+                Self::rent_collector(&escrow_info, initializer, account_info_iter)?;
+
+            msg!("Closing the escrow account...");
+
+            return Self::close_account(escrow_account, rent_collector);
+        }
+
+        // Alice still holds the Token X's of a delegated escrow, taking the approval back is enough
+        if escrow_info.mode == EscrowMode::Delegated {
+            let revoke_ix = token::revoke(
+                token_program.key,           // Tell token program to revoke
+                pdas_temp_token_account.key, // the PDA's approval on Alice's Token X account
+                initializer.key,             // Alice own's this
+                &[&initializer.key],         // Alice will sign this
+            )?;
+
+            msg!("Calling the token program to revoke the PDA's delegation...");
+
+            Self::invoke_in_order(
+                &revoke_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    initializer.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+
+            let rent_collector =
+                Self::rent_collector(&escrow_info, initializer, account_info_iter)?;
+
+            msg!("Closing the escrow account...");
+
+            return Self::close_account(escrow_account, rent_collector);
+        }
+
+        // Recreate PDA with seed word, escrow account, the bump seed stored at init and programId
+        let bump_seed = escrow_info.bump_seed;
+        let pda = Pubkey::create_program_address(
+            &[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ],
+            program_id,
+        )?;
+
+        // Give every Token X in the temp account back to Alice
+        let pdas_temp_token_account_info =
+            token::unpack_account(&pdas_temp_token_account.try_borrow_data()?)?;
+        // Read up front, like every custodial escrow's
+        let token_x_mint = token_x_mint.ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let token_x_decimals = Self::mint_decimals(
+            token_x_mint,
+            &pdas_temp_token_account_info.mint,
+            token_program.key,
+        )?;
+        let transfer_to_initializer_ix = token::transfer_checked(
+            token_program.key,                   // Tell token program to transfer Token X
+            pdas_temp_token_account.key,         // From Alice's temp Token X account
+            token_x_mint.key,                    // of the Token X mint
+            initializers_token_x_account.key,    // Back to Alice's Token X account
+            &pda,                                // authorized by pda
+            &[&pda],                             // signed by pda
+            pdas_temp_token_account_info.amount, // for the whole balance
+            token_x_decimals,
+        )?;
+
+        msg!("Calling the token program to refund tokens to the initializer...");
+
+        Self::invoke_signed_in_order(
+            &transfer_to_initializer_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                token_x_mint.clone(),
+                initializers_token_x_account.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ]],
+        )?;
+
+        let close_pdas_temp_acc_ix = token::close_account(
+            token_program.key,           // tell token program to close
+            pdas_temp_token_account.key, // temp Token X account
+            initializer.key,             // And the remaining balance should be sent to Alice
+            &pda,                        // authorized by pda
+            &[&pda],                     // signed by pda
+        )?;
+
+        msg!("Calling the token program to close pda's temp account...");
+
+        Self::invoke_signed_in_order(
+            &close_pdas_temp_acc_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                initializer.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ]],
+        )?;
+
+        // Alice gets the accounts she bundled back as they are, tokens and all
+        for basket_temp_token_account_key in escrow_info.basket() {
+            let basket_temp_token_account = next_account_info(account_info_iter)?;
+            if *basket_temp_token_account.key != *basket_temp_token_account_key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            let owner_change_ix = token::set_authority(
+                token_program.key,             // Tell token program to move authority
+                basket_temp_token_account.key, // from the basket temp account
+                Some(initializer.key),         // back to Alice.
+                AuthorityType::AccountOwner,
+                &pda,    // The PDA owns this
+                &[&pda], // and signs for it
+            )?;
+
+            msg!("Calling the token program to hand a basket account back to the initializer...");
+
+            Self::invoke_signed_in_order(
+                &owner_change_ix,
+                &[
+                    basket_temp_token_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[
+                    config::ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[bump_seed],
+                ]],
+            )?;
+        }
+
+        let rent_collector = Self::rent_collector(&escrow_info, initializer, account_info_iter)?;
+
+        msg!("Closing the escrow account...");
+
+        Self::close_account(escrow_account, rent_collector)
+    }
+
+    fn process_update_expected_amount(
+        accounts: &[AccountInfo],
+        new_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < UPDATE_EXPECTED_AMOUNT_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice can change her own price
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Asking for nothing in return would just give the Token X's away
+        if new_amount == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        // A taker who prepared an exchange is promised the escrow as it stood
+        if escrow_info.is_prepared(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+
+        escrow_info.expected_amount = new_amount;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_extend_expiry(
+        accounts: &[AccountInfo],
+        new_expire_at: i64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < EXTEND_EXPIRY_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice can give takers more time
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        // Takers were promised the escrow until `expire_at`, so it can only ever move later
+        if new_expire_at <= escrow_info.expire_at {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+
+        escrow_info.expire_at = new_expire_at;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    // Only the admin may pause or resume exchanges. The program config is created the first time.
+    fn process_toggle_exchanges(
+        accounts: &[AccountInfo],
+        paused: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < TOGGLE_EXCHANGES_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_account_info(account_info_iter)?;
+
+        // Nobody's signature but the admin's counts here
+        if !admin.is_signer || *admin.key != config::ADMIN_PUBKEY {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let program_config_account = next_account_info(account_info_iter)?;
+        let (program_config_key, program_config_bump) =
+            Pubkey::find_program_address(&[config::PROGRAM_CONFIG_SEED], program_id);
+        if *program_config_account.key != program_config_key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let system_program_account = next_account_info(account_info_iter)?;
+        if !system_program::check_id(system_program_account.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        if program_config_account.data_is_empty() {
+            msg!("Calling the system program to create the program config...");
+            Self::create_pda_account(
+                admin,
+                program_config_account,
+                system_program_account,
+                ProgramConfig::LEN,
+                program_id,
+                &[config::PROGRAM_CONFIG_SEED, &[program_config_bump]],
+            )?;
+        }
+
+        let mut program_config_info =
+            ProgramConfig::unpack_unchecked(&program_config_account.try_borrow_data()?)?;
+        program_config_info.is_initialized = true;
+        program_config_info.exchanges_paused = paused;
+        program_config_info.bump_seed = program_config_bump;
+        ProgramConfig::pack(
+            program_config_info,
+            &mut program_config_account.try_borrow_mut_data()?,
+        )?;
+
+        Ok(())
+    }
+
+    fn process_transfer_initializer(
+        accounts: &[AccountInfo],
+        new_initializer: Pubkey,
+        update_receive_account: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < TRANSFER_INITIALIZER_ACCOUNTS + usize::from(update_receive_account) {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice can hand her trade over
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        // The Token X's of a delegated escrow stay in Alice's account, only she could revoke them
+        if escrow_info.mode == EscrowMode::Delegated {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+
+        // Unless asked otherwise, Token Y's keep going to the account the escrow was made with
+        if update_receive_account {
+            let token_to_receive_account = next_account_info(account_info_iter)?;
+            if escrow_info.accept_native {
+                // Lamports go straight to the new initializer's main account
+                if *token_to_receive_account.key != new_initializer {
+                    return Err(EscrowError::InvalidOwner.log_and_convert());
+                }
+            } else {
+                if *token_to_receive_account.owner != escrow_info.token_program {
+                    return Err(ProgramError::IncorrectProgramId);
+                }
+                let token_to_receive_account_info =
+                    token::unpack_account(&token_to_receive_account.try_borrow_data()?)?;
+                if token_to_receive_account_info.owner != new_initializer {
+                    return Err(EscrowError::InvalidOwner.log_and_convert());
+                }
+                if !escrow_info.accepts_mint(&token_to_receive_account_info.mint) {
+                    return Err(EscrowError::UnacceptedMint.log_and_convert());
+                }
+            }
+            escrow_info.initializer_token_to_receive_account_pubkey = *token_to_receive_account.key;
+        }
+
+        escrow_info.initializer_pubkey = new_initializer;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_set_receive_account(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < SET_RECEIVE_ACCOUNT_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice decides where she gets paid
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        // Lamports always go to Alice's main account, there is no token account to swap
+        if escrow_info.accept_native {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+
+        let token_to_receive_account = next_account_info(account_info_iter)?;
+        if *token_to_receive_account.owner != escrow_info.token_program {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let token_to_receive_account_info =
+            token::unpack_account(&token_to_receive_account.try_borrow_data()?)?;
+        if !escrow_info.accepts_mint(&token_to_receive_account_info.mint) {
+            return Err(EscrowError::UnacceptedMint.log_and_convert());
+        }
+
+        // Without an allow-list, takers were quoted `expected_amount` in the mint of the account
+        // Alice is paid in now, and that is the only mint she may move to
+        let current_token_to_receive_account = next_account_info(account_info_iter)?;
+        if *current_token_to_receive_account.key
+            != escrow_info.initializer_token_to_receive_account_pubkey
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow_info.accepts_any_mint() {
+            if *current_token_to_receive_account.owner != escrow_info.token_program {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            let current_token_to_receive_account_info =
+                token::unpack_account(&current_token_to_receive_account.try_borrow_data()?)?;
+            if token_to_receive_account_info.mint != current_token_to_receive_account_info.mint {
+                return Err(EscrowError::UnacceptedMint.log_and_convert());
+            }
+        }
+
+        escrow_info.initializer_token_to_receive_account_pubkey = *token_to_receive_account.key;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_migrate_state(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < MIGRATE_STATE_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Alice, or whoever may upgrade the program, paying for the bigger account
+        let authority = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Escrow state account, still laid out as an outdated version or the baseline one
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let baseline = escrow_account.data_len() == Escrow::BASELINE_LEN;
+        let mut escrow_info = if baseline {
+            Escrow::unpack_baseline(&escrow_account.try_borrow_data()?)?
+        } else {
+            Escrow::unpack_outdated(&escrow_account.try_borrow_data()?)?
+        };
+
+        if escrow_info.initializer_pubkey != *authority.key {
+            // Anyone else has to be the upgrade authority, as the program's data account records it
+            let program_data_account = next_account_info(account_info_iter)?;
+            if Self::upgrade_authority(program_data_account, program_id)? != Some(*authority.key) {
+                return Err(EscrowError::InitializerMismatch.log_and_convert());
+            }
+        }
+
+        // With the system program, the signer pays whatever rent the bigger account lacks.
+        // Without it, the escrow account must have been funded for its new size already.
+        if let Some(system_program_account) = account_info_iter.next() {
+            if !system_program::check_id(system_program_account.key) {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            let rent_shortfall = Rent::get()?
+                .minimum_balance(Escrow::LEN)
+                .saturating_sub(escrow_account.lamports());
+            if rent_shortfall > 0 {
+                let transfer_ix =
+                    system_instruction::transfer(authority.key, escrow_account.key, rent_shortfall);
+                msg!("Calling the system program to top up the escrow account's rent...");
+                Self::invoke_in_order(
+                    &transfer_ix,
+                    &[
+                        authority.clone(),
+                        escrow_account.clone(),
+                        system_program_account.clone(),
+                    ],
+                )?;
+            }
+        }
+
+        if baseline {
+            Self::adopt_baseline_escrow(
+                &mut escrow_info,
+                escrow_account,
+                account_info_iter,
+                program_id,
+            )?;
+        }
+
+        Self::grow_account(escrow_account, Escrow::LEN)?;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    // A baseline state only held the terms, and its temp account was under the PDA every baseline escrow shared.
+    // What is left in the temp account is what is left of the trade, the mint of Alice's Token Y account
+    // tells what `expected_amount` is counted in, and the temp account moves to the escrow's own PDA.
+    fn adopt_baseline_escrow<'a, 'b>(
+        escrow_info: &mut Escrow,
+        escrow_account: &AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        let token_to_receive_account = next_account_info(account_info_iter)?;
+        let token_to_receive_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let baseline_pda_account = next_account_info(account_info_iter)?;
+
+        if *token_program.key != escrow_info.token_program {
+            return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+        }
+        if *pdas_temp_token_account.key != escrow_info.temp_token_account_pubkey
+            || *token_to_receive_account.key
+                != escrow_info.initializer_token_to_receive_account_pubkey
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if *pdas_temp_token_account.owner != *token_program.key
+            || *token_to_receive_account.owner != *token_program.key
+            || *token_to_receive_mint.owner != *token_program.key
+        {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let (baseline_pda, baseline_bump) =
+            Self::find_pda(&[config::BASELINE_ESCROW_SEED], program_id)?;
+        if *baseline_pda_account.key != baseline_pda {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let pdas_temp_token_account_info =
+            token::unpack_account(&pdas_temp_token_account.try_borrow_data()?)?;
+        if pdas_temp_token_account_info.owner != baseline_pda {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let token_to_receive_account_info =
+            token::unpack_account(&token_to_receive_account.try_borrow_data()?)?;
+        if token_to_receive_account_info.mint != *token_to_receive_mint.key {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+
+        escrow_info.initializer_mint_decimals =
+            token::unpack_mint(&token_to_receive_mint.try_borrow_data()?)?.decimals;
+        escrow_info.remaining_amount = pdas_temp_token_account_info.amount;
+        escrow_info.offered_amount = pdas_temp_token_account_info.amount;
+        let (pda, bump_seed) = Self::find_pda(
+            &[config::ESCROW_SEED, escrow_account.key.as_ref()],
+            program_id,
+        )?;
+        escrow_info.bump_seed = bump_seed;
+
+        let owner_change_ix = token::set_authority(
+            token_program.key,
+            pdas_temp_token_account.key,
+            Some(&pda),
+            AuthorityType::AccountOwner,
+            &baseline_pda,
+            &[&baseline_pda],
+        )?;
+
+        msg!("Calling the token program to hand the temp account over to the escrow's PDA...");
+        Self::invoke_signed_in_order(
+            &owner_change_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                baseline_pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[config::BASELINE_ESCROW_SEED, &[baseline_bump]]],
+        )
+    }
+
+    fn process_deposit(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < DEPOSIT_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice tops up her own trade
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if amount == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+
+        // Alice's Token X account to take the tokens from
+        let initializers_token_x_account = next_account_info(account_info_iter)?;
+        // Alice's temp Token X account, currently owned by the PDA
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        // There is no temp account to top up. Alice approves more in her own account instead,
+        // or makes another native offer.
+        if escrow_info.mode != EscrowMode::Custodial {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+
+        // A taker who prepared an exchange is promised the escrow as it stood
+        if escrow_info.is_prepared(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != escrow_info.token_program {
+            return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+        }
+        let token_x_mint = next_account_info(account_info_iter)?;
+        let token_x_decimals = Self::mint_decimals(
+            token_x_mint,
+            &token::unpack_account(&pdas_temp_token_account.try_borrow_data()?)?.mint,
+            token_program.key,
+        )?;
+
+        // The temp account is the PDA's now, but anyone may still send tokens into it.
+        // Alice signs for her own account, so a plain `invoke` does it.
+        let transfer_to_temp_ix = token::transfer_checked(
+            token_program.key,                // Tell token program to transfer Token X
+            initializers_token_x_account.key, // From Alice's Token X account
+            token_x_mint.key,                 // of the Token X mint
+            pdas_temp_token_account.key,      // To the PDA's temp Token X account
+            initializer.key,                  // Authorized by Alice's main account
+            &[&initializer.key],              // Signed by Alice's main account
+            amount,
+            token_x_decimals,
+        )?;
+
+        msg!("Calling the token program to deposit tokens to the temp account...");
+
+        Self::invoke_in_order(
+            &transfer_to_temp_ix,
+            &[
+                initializers_token_x_account.clone(),
+                token_x_mint.clone(),
+                pdas_temp_token_account.clone(),
+                initializer.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        escrow_info.remaining_amount = escrow_info
+            .remaining_amount
+            .checked_add(amount)
+            .ok_or_else(|| EscrowError::AmountOverflow.log_and_convert())?;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_add_to_basket(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < ADD_TO_BASKET_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice adds to her own trade
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Escrow state account
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        // The basket goes along with a temp account the PDA took custody of
+        if escrow_info.mode != EscrowMode::Custodial {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+
+        // A settled escrow takes nothing more, and a prepared one is promised to its taker as it stood
+        if !escrow_info.is_open(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+        if escrow_info.basket().len() >= Escrow::MAX_BASKET_LEN {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+
+        // Alice's token account to bundle, still owned by her
+        let basket_temp_token_account = next_account_info(account_info_iter)?;
+        // Bundling an account twice would have the taker paid out of it twice
+        if escrow_info.temp_token_account_pubkey == *basket_temp_token_account.key
+            || escrow_info.basket().contains(basket_temp_token_account.key)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != escrow_info.token_program {
+            return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+        }
+        if basket_temp_token_account.owner != token_program.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let basket_temp_token_account_info =
+            token::unpack_account(&basket_temp_token_account.try_borrow_data()?)?;
+        if basket_temp_token_account_info.owner != *initializer.key {
+            return Err(EscrowError::InvalidOwner.log_and_convert());
+        }
+        // An empty account would add nothing to the trade but a transfer
+        if basket_temp_token_account_info.amount == 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        if basket_temp_token_account_info.is_frozen() {
+            return Err(EscrowError::AccountFrozen.log_and_convert());
+        }
+
+        let pda = Pubkey::create_program_address(
+            &[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[escrow_info.bump_seed],
+            ],
+            program_id,
+        )?;
+
+        // Hand the account over to the PDA, like `InitEscrow` does with the main one
+        let owner_change_ix = token::set_authority(
+            token_program.key,             // Tell token program to move authority
+            basket_temp_token_account.key, // from the basket temp account
+            Some(&pda),                    // to escrow's derived account.
+            AuthorityType::AccountOwner,
+            initializer.key,     // Alice own's this
+            &[&initializer.key], // Alice will sign this
+        )?;
+
+        msg!("Calling the token program to transfer account ownership ...");
+
+        Self::invoke_in_order(
+            &owner_change_ix,
+            &[
+                basket_temp_token_account.clone(),
+                initializer.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        escrow_info.basket_temp_token_accounts[usize::from(escrow_info.basket_len)] =
+            *basket_temp_token_account.key;
+        escrow_info.basket_len += 1;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_split_escrow(
+        accounts: &[AccountInfo],
+        amount: u64,
+        new_escrow_bump: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if accounts.len() < SPLIT_ESCROW_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Only Alice carves up her own trade
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Escrow state account to split
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(EscrowError::InitializerMismatch.log_and_convert());
+        }
+
+        // The Token X never left Alice's own account, there is nothing for the PDA to move,
+        // and a native offer is taken all at once, and so is a basket
+        if escrow_info.mode != EscrowMode::Custodial || escrow_info.basket_len > 0 {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+
+        let clock = Clock::get()?;
+        // A settled escrow has nothing left to split, and a prepared one is promised to its taker
+        if !escrow_info.is_open(clock.unix_timestamp) {
+            return Err(EscrowError::InvalidState.log_and_convert());
+        }
+
+        // Both halves must keep something to trade
+        if amount == 0 || amount >= escrow_info.remaining_amount {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+
+        // The new escrow asks for its exact share of the price, or else splitting would reprice the trade
+        if !math::divides_evenly(
+            amount,
+            escrow_info.expected_amount,
+            escrow_info.remaining_amount,
+        )
+        .map_err(EscrowError::log_and_convert)?
+        {
+            return Err(EscrowError::ExpectedAmountMismatch.log_and_convert());
+        }
+        let new_expected_amount = math::proportional(
+            amount,
+            escrow_info.expected_amount,
+            escrow_info.remaining_amount,
+        )
+        .map_err(EscrowError::log_and_convert)?;
+
+        // The PDA's temp Token X account to take the tokens from
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let pdas_temp_token_account_info =
+            token::unpack_account(&pdas_temp_token_account.try_borrow_data()?)?;
+
+        let pda_account = next_account_info(account_info_iter)?;
+        let bump_seed = escrow_info.bump_seed;
+        let pda = Pubkey::create_program_address(
+            &[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ],
+            program_id,
+        )?;
+        if *pda_account.key != pda {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let new_escrow_account = next_account_info(account_info_iter)?;
+        if !new_escrow_account.is_writable || new_escrow_account.key == escrow_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // The new escrow account is set up just like `InitEscrow` wants it
+        if *new_escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if new_escrow_account.data_len() < Escrow::LEN {
+            return Err(EscrowError::AccountDataTooSmall.log_and_convert());
+        }
+        let rent = Rent::get()?;
+        if !rent.is_exempt(new_escrow_account.lamports(), new_escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.log_and_convert());
+        }
+        if new_escrow_account
+            .try_borrow_data()?
+            .iter()
+            .any(|byte| *byte != 0)
+        {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        // Alice names the bump she expects, but only the canonical one may sign for the new escrow
+        let (new_pda, canonical_bump) = Self::find_pda(
+            &[config::ESCROW_SEED, new_escrow_account.key.as_ref()],
+            program_id,
+        )?;
+        if new_escrow_bump != canonical_bump {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        // Alice's new temp Token X account, to hand over to the new PDA
+        let new_temp_token_account = next_account_info(account_info_iter)?;
+        if new_temp_token_account.key == pdas_temp_token_account.key
+            || new_temp_token_account.key == new_escrow_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
         let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != escrow_info.token_program {
+            return Err(EscrowError::TokenProgramMismatch.log_and_convert());
+        }
+        if new_temp_token_account.owner != token_program.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let new_temp_token_account_info =
+            token::unpack_account(&new_temp_token_account.try_borrow_data()?)?;
+        if new_temp_token_account_info.owner != *initializer.key {
+            return Err(EscrowError::InvalidOwner.log_and_convert());
+        }
+        if new_temp_token_account_info.mint != pdas_temp_token_account_info.mint {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+        // It must hold exactly the share moved below, or the new escrow would hold tokens
+        // it doesn't account for, and its temp account couldn't be closed once taken
+        if new_temp_token_account_info.amount != 0 {
+            return Err(EscrowError::InvalidAmount.log_and_convert());
+        }
+        // and nobody else may move its tokens out or close it behind the new PDA's back
+        if new_temp_token_account_info.delegate.is_some()
+            || new_temp_token_account_info.close_authority.is_some()
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let token_x_mint = next_account_info(account_info_iter)?;
+        let token_x_decimals = Self::mint_decimals(
+            token_x_mint,
+            &pdas_temp_token_account_info.mint,
+            token_program.key,
+        )?;
 
-        // Instruction that transfers amount of token to initializer(Alice)
-        let transfer_to_initializer_ix = spl_token::instruction::transfer(
-            token_program.key,                // Tell token program to transfer Y tokens
-            takers_sending_token_account.key, // From Bob's Token Y account
-            initializers_token_to_receive_account.key, // To Alice's Token Y account
-            taker.key,                        // Authorized by Bob's main account
-            &[&taker.key],                    // Signed by Bob's main account
-            escrow_info.expected_amount,
+        // The new escrow is the old one for its share, starting afresh
+        let mut new_escrow_info = escrow_info.clone();
+        new_escrow_info.temp_token_account_pubkey = *new_temp_token_account.key;
+        new_escrow_info.bump_seed = new_escrow_bump;
+        new_escrow_info.expected_amount = new_expected_amount;
+        new_escrow_info.remaining_amount = amount;
+        new_escrow_info.offered_amount = amount;
+        new_escrow_info.status = EscrowStatus::Open;
+        new_escrow_info.prepared_taker = Pubkey::default();
+        new_escrow_info.prepared_deadline = 0;
+        new_escrow_info.created_at_slot = clock.slot;
+        // Alice pays the new escrow account herself, so its rent comes back to her
+        new_escrow_info.rent_payer = Pubkey::default();
+
+        escrow_info.remaining_amount -= amount;
+        // The share moves out of what Alice locked too, or the temp balance check would no longer apply.
+        // A migrated state with nothing on record keeps nothing on record.
+        escrow_info.offered_amount = escrow_info.offered_amount.saturating_sub(amount);
+        escrow_info.expected_amount -= new_expected_amount;
+        let reference = escrow_info.reference;
+
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+        Escrow::pack(
+            new_escrow_info,
+            &mut new_escrow_account.try_borrow_mut_data()?,
         )?;
 
-        msg!("Calling the token program to transfer tokens to escrow's initializer...");
+        // Hand the new temp account over to the new PDA, like `InitEscrow` does
+        let owner_change_ix = token::set_authority(
+            token_program.key,          // Tell token program to move authority
+            new_temp_token_account.key, // from the new temp token account
+            Some(&new_pda),             // to the new escrow's derived account.
+            AuthorityType::AccountOwner,
+            initializer.key,     // Alice own's this
+            &[&initializer.key], // Alice will sign this
+        )?;
 
-        // invoke token program to execute transfer ix
-        invoke(
-            &transfer_to_initializer_ix,
+        msg!("Calling the token program to transfer account ownership ...");
+
+        Self::invoke_in_order(
+            &owner_change_ix,
             &[
-                takers_sending_token_account.clone(),
-                initializers_token_to_receive_account.clone(),
-                taker.clone(),
+                new_temp_token_account.clone(),
+                initializer.clone(),
                 token_program.clone(),
             ],
         )?;
 
-        // Temp Token X account for Alice
-        let pda_account = next_account_info(account_info_iter)?;
-
-        //
-        let transfer_to_taker_ix = spl_token::instruction::transfer(
-            token_program.key,                   // Tell token program to transfer Token X
-            pdas_temp_token_account.key,         // From Alice's temp Token X account
-            takers_token_to_receive_account.key, // To Bob's Token X account
-            &pda,                                // authorized by Temp Token X account
-            &[&pda],                             // signed by Temp Token X account
-            pdas_temp_token_account_info.amount, // for this amount
+        // Then move the new escrow's share of Token X across
+        let transfer_to_new_temp_ix = token::transfer_checked(
+            token_program.key,           // Tell token program to transfer Token X
+            pdas_temp_token_account.key, // From the PDA's temp Token X account
+            token_x_mint.key,            // of the Token X mint
+            new_temp_token_account.key,  // To the new PDA's temp Token X account
+            &pda,                        // authorized by pda
+            &[&pda],                     // signed by pda
+            amount,
+            token_x_decimals,
         )?;
 
-        msg!("Calling the token program to transfer tokens to the taker...");
+        msg!("Calling the token program to move tokens to the new temp account...");
 
-        // Since the signer is PDA which has no private key,
-        // we have to use `invoke_signed` and give the seed and bump seed.
-        invoke_signed(
-            &transfer_to_taker_ix,
+        Self::invoke_signed_in_order(
+            &transfer_to_new_temp_ix,
             &[
-                token_program.clone(),
                 pdas_temp_token_account.clone(),
-                takers_token_to_receive_account.clone(),
+                token_x_mint.clone(),
+                new_temp_token_account.clone(),
                 pda_account.clone(),
+                token_program.clone(),
             ],
-            &[&[&b"escrow"[..], &[bump_seed]]], // this will be used to recreate the PDA
+            &[&[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ]],
         )?;
 
-        // Token X's are all sent. We don't need temp Token X account anymore.
-        // We should close it.
-        let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
-            token_program.key,             // tell token program to close
-            pdas_temp_token_account.key,   // temp Token X account
-            initializers_main_account.key, // And the remaining balance should be sent to Alice
-            &pda,                          // authorized by pda
-            &[&pda],                       // signed by pda
+        EscrowEvent::Initialized {
+            escrow: *new_escrow_account.key,
+            initializer: *initializer.key,
+            amount: new_expected_amount,
+            offered_amount: amount,
+            reference,
+        }
+        .emit()?;
+        Self::log_escrow_created(new_escrow_account.key, &new_pda, new_temp_token_account.key);
+
+        Ok(())
+    }
+
+    fn process_reclaim_orphan(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < RECLAIM_ORPHAN_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Whoever set up the escrow, Alice in our example
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // The token account left under the PDA's control
+        let orphaned_token_account = next_account_info(account_info_iter)?;
+        // Alice's Token X account to get the tokens back
+        let initializers_token_x_account = next_account_info(account_info_iter)?;
+        // The escrow account the PDA is derived from, holding an escrow state or not
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        // Only a state our program wrote can tell who the initializer is
+        let escrow_info = if *escrow_account.owner == *program_id {
+            Some(Escrow::unpack_unchecked(
+                &escrow_account.try_borrow_data()?,
+            )?)
+            .filter(|escrow_info| escrow_info.is_initialized)
+        } else {
+            None
+        };
+        match escrow_info {
+            Some(escrow_info) => {
+                if escrow_info.initializer_pubkey != *initializer.key {
+                    return Err(EscrowError::InitializerMismatch.log_and_convert());
+                }
+                // A live escrow still refers to its temp accounts, which are not orphans at all
+                if escrow_info.temp_token_account_pubkey == *orphaned_token_account.key
+                    || escrow_info.basket().contains(orphaned_token_account.key)
+                {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+            }
+            // Nothing records who set the PDA up, only the escrow account's keypair can tell
+            None => {
+                if !escrow_account.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+            }
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        if !token::is_token_program(token_program.key)
+            || orphaned_token_account.owner != token_program.key
+        {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let pda_account = next_account_info(account_info_iter)?;
+
+        // There may be no state to read the bump seed from, so we search it again
+        let (pda, bump_seed) = Self::find_pda(
+            &[config::ESCROW_SEED, escrow_account.key.as_ref()],
+            program_id,
         )?;
 
-        msg!("Calling the token program to close pda's temp account...");
+        let orphaned_token_account_info =
+            token::unpack_account(&orphaned_token_account.try_borrow_data()?)?;
+        if orphaned_token_account_info.owner != pda {
+            return Err(EscrowError::InvalidOwner.log_and_convert());
+        }
+        let mint = next_account_info(account_info_iter)?;
+        let decimals =
+            Self::mint_decimals(mint, &orphaned_token_account_info.mint, token_program.key)?;
 
-        // Closing the account requires signing from escrow account
-        invoke_signed(
-            &close_pdas_temp_acc_ix,
+        // Give every token left in the orphaned account back to Alice
+        let transfer_to_initializer_ix = token::transfer_checked(
+            token_program.key,                  // Tell token program to transfer the tokens
+            orphaned_token_account.key,         // From the orphaned token account
+            mint.key,                           // of its mint
+            initializers_token_x_account.key,   // Back to Alice's token account
+            &pda,                               // authorized by pda
+            &[&pda],                            // signed by pda
+            orphaned_token_account_info.amount, // for the whole balance
+            decimals,
+        )?;
+
+        msg!("Calling the token program to refund tokens to the initializer...");
+
+        Self::invoke_signed_in_order(
+            &transfer_to_initializer_ix,
             &[
+                orphaned_token_account.clone(),
+                mint.clone(),
+                initializers_token_x_account.clone(),
+                pda_account.clone(),
                 token_program.clone(),
-                pdas_temp_token_account.clone(),
-                takers_token_to_receive_account.clone(),
+            ],
+            &[&[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ]],
+        )?;
+
+        let close_orphaned_acc_ix = token::close_account(
+            token_program.key,          // tell token program to close
+            orphaned_token_account.key, // the orphaned token account
+            initializer.key,            // And the remaining balance should be sent to Alice
+            &pda,                       // authorized by pda
+            &[&pda],                    // signed by pda
+        )?;
+
+        msg!("Calling the token program to close the orphaned account...");
+
+        Self::invoke_signed_in_order(
+            &close_orphaned_acc_ix,
+            &[
+                orphaned_token_account.clone(),
+                initializer.clone(),
                 pda_account.clone(),
+                token_program.clone(),
             ],
-            &[&[&b"escrow"[..], &[bump_seed]]],
+            &[&[
+                config::ESCROW_SEED,
+                escrow_account.key.as_ref(),
+                &[bump_seed],
+            ]],
         )?;
 
-        msg!("Closing the escrow account...");
+        Ok(())
+    }
+
+    fn process_get_escrow_info(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if accounts.len() < GET_ESCROW_INFO_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let account_info_iter = &mut accounts.iter();
+        // Anyone may look, nobody has to sign
+        let escrow_account = next_account_info(account_info_iter)?;
+        if *escrow_account.owner != *program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // Unpacking first makes sure we only ever log a valid escrow state
+        let escrow_data = escrow_account.try_borrow_data()?;
+        Escrow::unpack(&escrow_data)?;
 
+        sol_log_data(&Escrow::split_fields(&escrow_data));
+
+        Ok(())
+    }
+
+    // Logs what keeps an escrow of the given configuration and the token accounts under its PDA
+    // rent exempt, from the rent sysvar of the cluster it runs on rather than a guess client side
+    fn process_get_rent(config_flags: u8) -> ProgramResult {
+        let known_flags =
+            EscrowInstruction::RENT_TEMP_TOKEN_ACCOUNT | EscrowInstruction::RENT_BASKET_LEN_MASK;
+        if config_flags & !known_flags != 0 {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+        let has_temp_token_account = config_flags & EscrowInstruction::RENT_TEMP_TOKEN_ACCOUNT != 0;
+        let basket_len = (config_flags & EscrowInstruction::RENT_BASKET_LEN_MASK)
+            >> EscrowInstruction::RENT_BASKET_LEN_MASK.trailing_zeros();
+        // Basket accounts ride along with a temp token account, never on their own
+        if usize::from(basket_len) > Escrow::MAX_BASKET_LEN
+            || (basket_len > 0 && !has_temp_token_account)
+        {
+            return Err(EscrowError::InvalidInstruction.log_and_convert());
+        }
+
+        let rent = Rent::get()?;
+        let token_accounts = u64::from(has_temp_token_account) + u64::from(basket_len);
+        let lamports = rent
+            .minimum_balance(spl_token::state::Account::LEN)
+            .checked_mul(token_accounts)
+            .and_then(|lamports| lamports.checked_add(rent.minimum_balance(Escrow::LEN)))
+            .ok_or_else(|| EscrowError::AmountOverflow.log_and_convert())?;
+
+        msg!("Rent exempt minimum: {} lamports", lamports);
+        sol_log_data(&[&lamports.to_le_bytes()]);
+
+        Ok(())
+    }
+
+    // Turns every taker away while the admin has exchanges paused, and hands back the accounts
+    // without the program config. It comes after every other account, but it is optional:
+    // until the admin first toggles exchanges it doesn't even exist, and clients from before it don't pass it.
+    // No other account we read is ours and this small, and the bump seed it keeps
+    // lets us check its address without searching for it.
+    fn check_exchanges_allowed<'a, 'b>(
+        accounts: &'b [AccountInfo<'a>],
+        program_id: &Pubkey,
+    ) -> Result<&'b [AccountInfo<'a>], ProgramError> {
+        let (program_config_account, other_accounts) = match accounts.split_last() {
+            Some((last, other_accounts))
+                if *last.owner == *program_id && last.data_len() == ProgramConfig::LEN =>
+            {
+                (last, other_accounts)
+            }
+            _ => return Ok(accounts),
+        };
+
+        let program_config_info =
+            ProgramConfig::unpack(&program_config_account.try_borrow_data()?)?;
+        let program_config_key = Pubkey::create_program_address(
+            &[
+                config::PROGRAM_CONFIG_SEED,
+                &[program_config_info.bump_seed],
+            ],
+            program_id,
+        )?;
+        if *program_config_account.key != program_config_key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if program_config_info.exchanges_paused {
+            return Err(EscrowError::ExchangesPaused.log_and_convert());
+        }
+        Ok(other_accounts)
+    }
+
+    // Has the memo program log `memo` for block explorers, signed by `signer`
+    fn record_memo<'a>(
+        signer: &AccountInfo<'a>,
+        memo_program_account: &AccountInfo<'a>,
+        memo: &[u8],
+    ) -> ProgramResult {
+        let memo_ix = memo::build_memo(memo, &[signer.key]);
+
+        msg!("Calling the memo program to record the trade note...");
+        Self::invoke_in_order(&memo_ix, &[signer.clone(), memo_program_account.clone()])
+    }
+
+    // The runtime finds the accounts of a cross-program invocation by key, so it takes them in any order.
+    // We still hand them over in the order of the instruction's own metas, with the invoked program last,
+    // which makes a missing account easy to spot. Debug builds, and so every test, check it on each call.
+    fn invoke_in_order(instruction: &Instruction, account_infos: &[AccountInfo]) -> ProgramResult {
+        debug_assert!(Self::cpi_accounts_in_order(instruction, account_infos));
+        invoke(instruction, account_infos)
+    }
+
+    // Same as `invoke_in_order`, for the calls the PDA signs
+    fn invoke_signed_in_order(
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        debug_assert!(Self::cpi_accounts_in_order(instruction, account_infos));
+        invoke_signed(instruction, account_infos, signers_seeds)
+    }
+
+    // An account listed twice in the metas, like an authority that also signs, is passed once
+    fn cpi_accounts_in_order(instruction: &Instruction, account_infos: &[AccountInfo]) -> bool {
+        let mut keys: Vec<&Pubkey> = Vec::with_capacity(instruction.accounts.len() + 1);
+        for meta in &instruction.accounts {
+            if !keys.contains(&&meta.pubkey) {
+                keys.push(&meta.pubkey);
+            }
+        }
+        keys.push(&instruction.program_id);
+
+        keys.len() == account_infos.len()
+            && keys
+                .iter()
+                .zip(account_infos)
+                .all(|(key, account_info)| *key == account_info.key)
+    }
+
+    // Creates the account at a PDA with `space` bytes, owned by `owner`. Anyone can work out the address
+    // and send it lamports first, which `create_account` refuses, so such an account is topped up
+    // to rent exemption, then allocated and assigned on its own.
+    fn create_pda_account<'a>(
+        payer: &AccountInfo<'a>,
+        new_account: &AccountInfo<'a>,
+        system_program_account: &AccountInfo<'a>,
+        space: usize,
+        owner: &Pubkey,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let minimum_balance = Rent::get()?.minimum_balance(space);
+        if new_account.lamports() == 0 {
+            let create_account_ix = system_instruction::create_account(
+                payer.key,
+                new_account.key,
+                minimum_balance,
+                space as u64,
+                owner,
+            );
+            return Self::invoke_signed_in_order(
+                &create_account_ix,
+                &[
+                    payer.clone(),
+                    new_account.clone(),
+                    system_program_account.clone(),
+                ],
+                &[signer_seeds],
+            );
+        }
+
+        let rent_shortfall = minimum_balance.saturating_sub(new_account.lamports());
+        if rent_shortfall > 0 {
+            let transfer_ix =
+                system_instruction::transfer(payer.key, new_account.key, rent_shortfall);
+            Self::invoke_in_order(
+                &transfer_ix,
+                &[
+                    payer.clone(),
+                    new_account.clone(),
+                    system_program_account.clone(),
+                ],
+            )?;
+        }
+        let allocate_ix = system_instruction::allocate(new_account.key, space as u64);
+        Self::invoke_signed_in_order(
+            &allocate_ix,
+            &[new_account.clone(), system_program_account.clone()],
+            &[signer_seeds],
+        )?;
+        let assign_ix = system_instruction::assign(new_account.key, owner);
+        Self::invoke_signed_in_order(
+            &assign_ix,
+            &[new_account.clone(), system_program_account.clone()],
+            &[signer_seeds],
+        )
+    }
+
+    // The upgrade authority a program's data account records, `None` once the program is immutable
+    fn upgrade_authority(
+        program_data_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Option<Pubkey>, ProgramError> {
+        let (program_data_key, _) =
+            Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+        if *program_data_account.key != program_data_key
+            || !bpf_loader_upgradeable::check_id(program_data_account.owner)
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // The program itself follows the state, which bincode leaves alone
+        let state_len = UpgradeableLoaderState::programdata_data_offset()
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        match limited_deserialize(&program_data_account.try_borrow_data()?, state_len as u64) {
+            Ok(UpgradeableLoaderState::ProgramData {
+                upgrade_authority_address,
+                ..
+            }) => Ok(upgrade_authority_address),
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    // `Pubkey::find_program_address` panics on seeds the runtime won't take,
+    // so they are checked first to fail with an error that tells why
+    fn find_pda(seeds: &[&[u8]], program_id: &Pubkey) -> Result<(Pubkey, u8), ProgramError> {
+        if !config::seeds_fit(seeds) {
+            return Err(EscrowError::SeedTooLong.log_and_convert());
+        }
+        Ok(Pubkey::find_program_address(seeds, program_id))
+    }
+
+    // `Clock::get()` is all we need, but some SDKs pass the Clock sysvar anyway.
+    // When they do, it had better be the real one.
+    fn clock(clock_account: Option<&AccountInfo>) -> Result<Clock, ProgramError> {
+        match clock_account {
+            Some(clock_account) => {
+                if *clock_account.key != sysvar::clock::id() {
+                    return Err(ProgramError::InvalidArgument);
+                }
+                Clock::from_account_info(clock_account)
+            }
+            None => Clock::get(),
+        }
+    }
+
+    // For an optional Clock sysvar with more accounts after it. It is told apart by its key,
+    // so that an account meant for what follows is never mistaken for it.
+    // The decimals of `mint`, which `TransferChecked` wants along with the mint itself.
+    // It must be the mint of the token account the tokens move out of.
+    fn mint_decimals(
+        mint: &AccountInfo,
+        expected_mint: &Pubkey,
+        token_program_id: &Pubkey,
+    ) -> Result<u8, ProgramError> {
+        if *mint.key != *expected_mint {
+            return Err(EscrowError::MintMismatch.log_and_convert());
+        }
+        if *mint.owner != *token_program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Ok(token::unpack_mint(&mint.try_borrow_data()?)?.decimals)
+    }
+
+    fn next_clock_if_passed(
+        account_info_iter: &mut std::slice::Iter<AccountInfo>,
+    ) -> Result<Clock, ProgramError> {
+        match account_info_iter.as_slice().first() {
+            Some(clock_account) if sysvar::clock::check_id(clock_account.key) => {
+                Self::clock(account_info_iter.next())
+            }
+            _ => Clock::get(),
+        }
+    }
+
+    // Moves `lamports` out of the PDA of a native offer. It is a system account,
+    // so only the system program can debit it, with the PDA signing.
+    fn transfer_from_pda<'a>(
+        pda_account: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        system_program_account: &AccountInfo<'a>,
+        lamports: u64,
+        escrow_key: &Pubkey,
+        bump_seed: u8,
+    ) -> ProgramResult {
+        let transfer_ix = system_instruction::transfer(pda_account.key, destination.key, lamports);
+        Self::invoke_signed_in_order(
+            &transfer_ix,
+            &[
+                pda_account.clone(),
+                destination.clone(),
+                system_program_account.clone(),
+            ],
+            &[&[config::ESCROW_SEED, escrow_key.as_ref(), &[bump_seed]]],
+        )
+    }
+
+    // Reallocates `account` to `new_len` bytes, which the lamports it holds by then must keep rent exempt.
+    // Writing the new bytes is up to the caller.
+    fn grow_account(account: &AccountInfo, new_len: usize) -> ProgramResult {
+        account.realloc(new_len, false)?;
+        if !Rent::get()?.is_exempt(account.lamports(), new_len) {
+            return Err(EscrowError::NotRentExempt.log_and_convert());
+        }
+        Ok(())
+    }
+
+    // Whoever paid the escrow's rent gets back what is left of it. That is Alice, unless the escrow
+    // recorded a rent payer, whose account then has to come next.
+    fn rent_collector<'a, 'b>(
+        escrow_info: &Escrow,
+        initializers_main_account: &'b AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    ) -> Result<&'b AccountInfo<'a>, ProgramError> {
+        if escrow_info.rent_payer == Pubkey::default() {
+            return Ok(initializers_main_account);
+        }
+        let rent_payer = next_account_info(account_info_iter)?;
+        if escrow_info.rent_payer != *rent_payer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(rent_payer)
+    }
+
+    // Moves every lamport of `target` to `destination` and empties `target`'s data,
+    // so the runtime deletes `target` at the end of the transaction.
+    fn close_account(target: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
         // A bit of Rust smart pointer knowledge!
         // -------------------------------------
         // How to assign `amount: u64` into `balance: Rc<RefCell<&mut u64>>`?
@@ -259,19 +3830,501 @@ impl Processor {
         // 3. deref one more time!              -> u64
         // So it should be: `**balance.borrow_mut() = amount;`
 
-        // Transfer lamports remaining in escrow's balance to Alice's balance
-        **initializers_main_account.lamports.borrow_mut() = initializers_main_account
+        // Transfer lamports remaining in target's balance to the destination's balance
+        **destination.lamports.borrow_mut() = destination
             .lamports()
-            .checked_add(escrow_account.lamports()) // this is cryptographically safe addition!
-            .ok_or(EscrowError::AmountOverflow)?; // Option to Result
+            .checked_add(target.lamports()) // this is cryptographically safe addition!
+            .ok_or_else(|| EscrowError::AmountOverflow.log_and_convert())?; // Option to Result
 
-        // Empty the escrow's balance
+        // Empty the target's balance
         // The Solana runtime will watch accounts will zero balance and delete them.
-        **escrow_account.lamports.borrow_mut() = 0;
+        **target.lamports.borrow_mut() = 0;
 
-        // Empty the escrow's data section
-        *escrow_account.try_borrow_mut_data()? = &mut [];
+        // Empty the target's data section
+        *target.try_borrow_mut_data()? = &mut [];
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instructions::ExchangeAccounts, test_utils::TestEnv};
+    use solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+    use solana_sdk::signature::Signer;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_close_account() {
+        let program_id = Pubkey::new_unique();
+        let target_key = Pubkey::new_unique();
+        let destination_key = Pubkey::new_unique();
+        let mut target_lamports = 2_039_280;
+        let mut destination_lamports = 1_000_000_000;
+        let mut target_data = [7u8; Escrow::LEN];
+        let mut destination_data: [u8; 0] = [];
+        let target = AccountInfo::new(
+            &target_key,
+            false,
+            true,
+            &mut target_lamports,
+            &mut target_data,
+            &program_id,
+            false,
+            0,
+        );
+        let destination = AccountInfo::new(
+            &destination_key,
+            true,
+            true,
+            &mut destination_lamports,
+            &mut destination_data,
+            &program_id,
+            false,
+            0,
+        );
+
+        Processor::close_account(&target, &destination).unwrap();
+
+        assert_eq!(destination.lamports(), 1_000_000_000 + 2_039_280);
+        assert_eq!(target.lamports(), 0);
+        assert_eq!(target.data_len(), 0);
+    }
+
+    #[test]
+    fn test_close_account_overflow() {
+        let program_id = Pubkey::new_unique();
+        let target_key = Pubkey::new_unique();
+        let destination_key = Pubkey::new_unique();
+        let mut target_lamports = 1;
+        let mut destination_lamports = u64::MAX;
+        let mut target_data: [u8; 0] = [];
+        let mut destination_data: [u8; 0] = [];
+        let target = AccountInfo::new(
+            &target_key,
+            false,
+            true,
+            &mut target_lamports,
+            &mut target_data,
+            &program_id,
+            false,
+            0,
+        );
+        let destination = AccountInfo::new(
+            &destination_key,
+            true,
+            true,
+            &mut destination_lamports,
+            &mut destination_data,
+            &program_id,
+            false,
+            0,
+        );
+
+        assert_eq!(
+            Processor::close_account(&target, &destination),
+            Err(EscrowError::AmountOverflow.into())
+        );
+        // Nothing moves when the sum doesn't fit
+        assert_eq!(target.lamports(), 1);
+        assert_eq!(destination.lamports(), u64::MAX);
+    }
+
+    #[test]
+    fn test_cpi_accounts_in_order() {
+        let keys = [
+            Pubkey::new_unique(), // source
+            Pubkey::new_unique(), // delegate, or destination
+            Pubkey::new_unique(), // authority
+            spl_token::id(),
+        ];
+        let mut lamports = [0u64; 4];
+        let mut data = [[0u8; 0]; 4];
+        let account_infos: Vec<AccountInfo> = keys
+            .iter()
+            .zip(lamports.iter_mut())
+            .zip(data.iter_mut())
+            .map(|((key, lamports), data)| {
+                AccountInfo::new(key, false, true, lamports, data, key, false, 0)
+            })
+            .collect();
+        // The authority is listed twice in the metas, once more as the signer
+        let approve_ix =
+            token::approve(&keys[3], &keys[0], &keys[1], &keys[2], &[&keys[2]], 10).unwrap();
+
+        assert!(Processor::cpi_accounts_in_order(
+            &approve_ix,
+            &account_infos
+        ));
+        // The program goes last, not first
+        let program_first = [
+            account_infos[3].clone(),
+            account_infos[0].clone(),
+            account_infos[1].clone(),
+            account_infos[2].clone(),
+        ];
+        assert!(!Processor::cpi_accounts_in_order(
+            &approve_ix,
+            &program_first
+        ));
+        // and every account of the instruction must be there
+        assert!(!Processor::cpi_accounts_in_order(
+            &approve_ix,
+            &[
+                account_infos[0].clone(),
+                account_infos[1].clone(),
+                account_infos[3].clone()
+            ]
+        ));
+
+        // Closing takes the account, its destination and the authority, nothing an approval adds
+        let close_ix =
+            token::close_account(&keys[3], &keys[0], &keys[1], &keys[2], &[&keys[2]]).unwrap();
+        assert!(Processor::cpi_accounts_in_order(&close_ix, &account_infos));
+        let other_key = Pubkey::new_unique();
+        let mut other_lamports = 0;
+        let mut other_data = [0u8; 0];
+        let other_account = AccountInfo::new(
+            &other_key,
+            false,
+            true,
+            &mut other_lamports,
+            &mut other_data,
+            &other_key,
+            false,
+            0,
+        );
+        assert!(!Processor::cpi_accounts_in_order(
+            &close_ix,
+            &[
+                account_infos[0].clone(),
+                other_account,
+                account_infos[1].clone(),
+                account_infos[2].clone(),
+                account_infos[3].clone()
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_find_pda_seed_limits() {
+        let program_id = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+
+        // As many seeds as the bump seed leaves room for, each as long as it may be
+        let longest_seed = [7; solana_program::pubkey::MAX_SEED_LEN];
+        let mut seeds = vec![&longest_seed[..]; solana_program::pubkey::MAX_SEEDS - 1];
+        assert_eq!(
+            Processor::find_pda(&seeds, &program_id),
+            Ok(Pubkey::find_program_address(&seeds, &program_id))
+        );
+
+        // One seed more leaves no room for the bump seed
+        seeds.push(key.as_ref());
+        assert_eq!(
+            Processor::find_pda(&seeds, &program_id),
+            Err(ProgramError::Custom(EscrowError::SeedTooLong as u32))
+        );
+
+        // and neither does a seed a byte too long
+        let too_long_seed = [7; solana_program::pubkey::MAX_SEED_LEN + 1];
+        assert_eq!(
+            Processor::find_pda(&[config::ESCROW_SEED, &too_long_seed], &program_id),
+            Err(ProgramError::Custom(EscrowError::SeedTooLong as u32))
+        );
+
+        // The escrow's PDA is derived just as before
+        assert_eq!(
+            Processor::find_pda(&[config::ESCROW_SEED, key.as_ref()], &program_id),
+            Ok(Pubkey::find_program_address(
+                &[config::ESCROW_SEED, key.as_ref()],
+                &program_id
+            ))
+        );
+    }
+
+    #[test]
+    fn test_too_few_accounts() {
+        let program_id = Pubkey::new_unique();
+        let keys: Vec<Pubkey> = (0..10).map(|_| Pubkey::new_unique()).collect();
+        let mut lamports = [0u64; 10];
+        let mut data = [[0u8; 0]; 10];
+        let account_infos: Vec<AccountInfo> = keys
+            .iter()
+            .zip(lamports.iter_mut())
+            .zip(data.iter_mut())
+            .map(|((key, lamports), data)| {
+                AccountInfo::new(key, false, true, lamports, data, &program_id, false, 0)
+            })
+            .collect();
+
+        // Every instruction with the fewest accounts it reads
+        let instructions = [
+            (
+                EscrowInstruction::InitEscrow {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        accept_native: true,
+                        ..InitEscrowParams::default()
+                    },
+                },
+                INIT_ESCROW_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::Exchange {
+                    amount: 10,
+                    min_amount_out: 20,
+                    memo: vec![],
+                },
+                EXCHANGE_ACCOUNTS,
+            ),
+            (EscrowInstruction::CancelEscrow, CANCEL_ESCROW_ACCOUNTS),
+            (
+                EscrowInstruction::PartialExchange { amount: 5 },
+                EXCHANGE_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::UpdateExpectedAmount { new_amount: 30 },
+                UPDATE_EXPECTED_AMOUNT_ACCOUNTS,
+            ),
+            (EscrowInstruction::GetEscrowInfo, GET_ESCROW_INFO_ACCOUNTS),
+            (
+                EscrowInstruction::TransferInitializer {
+                    new_initializer: Pubkey::new_unique(),
+                    update_receive_account: false,
+                },
+                TRANSFER_INITIALIZER_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::TransferInitializer {
+                    new_initializer: Pubkey::new_unique(),
+                    update_receive_account: true,
+                },
+                TRANSFER_INITIALIZER_ACCOUNTS + 1,
+            ),
+            (EscrowInstruction::Deposit { amount: 5 }, DEPOSIT_ACCOUNTS),
+            (
+                EscrowInstruction::InitEscrowDelegated {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        accept_native: true,
+                        ..InitEscrowParams::default()
+                    },
+                    offered_amount: 10,
+                },
+                INIT_ESCROW_DELEGATED_ACCOUNTS,
+            ),
+            (EscrowInstruction::ReclaimOrphan, RECLAIM_ORPHAN_ACCOUNTS),
+            (EscrowInstruction::ReapExpired, REAP_EXPIRED_ACCOUNTS),
+            (
+                EscrowInstruction::PrepareExchange,
+                PREPARE_EXCHANGE_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::FinalizeExchange { amount: 10 },
+                EXCHANGE_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::SplitEscrow {
+                    amount: 5,
+                    new_escrow_bump: 254,
+                },
+                SPLIT_ESCROW_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::SimulateExchange { amount: 10 },
+                EXCHANGE_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::SetReceiveAccount,
+                SET_RECEIVE_ACCOUNT_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::InitEscrowPda {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        accept_native: true,
+                        ..InitEscrowParams::default()
+                    },
+                },
+                INIT_ESCROW_PDA_ACCOUNTS,
+            ),
+            (EscrowInstruction::MigrateState, MIGRATE_STATE_ACCOUNTS),
+            (
+                EscrowInstruction::InitEscrowNative {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        ..InitEscrowParams::default()
+                    },
+                    offered_lamports: 1_000_000_000,
+                },
+                INIT_ESCROW_NATIVE_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::ExchangeNativeOffer { amount: 10 },
+                EXCHANGE_NATIVE_OFFER_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::ExtendExpiry {
+                    new_expire_at: 1_800_000_000,
+                },
+                EXTEND_EXPIRY_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::Exchange {
+                    amount: 10,
+                    min_amount_out: 20,
+                    memo: b"trade #42".to_vec(),
+                },
+                EXCHANGE_WITH_MEMO_ACCOUNTS,
+            ),
+            (
+                EscrowInstruction::ToggleExchanges { paused: true },
+                TOGGLE_EXCHANGES_ACCOUNTS,
+            ),
+            (EscrowInstruction::AddToBasket, ADD_TO_BASKET_ACCOUNTS),
+            (
+                EscrowInstruction::InitEscrowManaged {
+                    params: InitEscrowParams {
+                        amount: 20,
+                        expire_at: i64::MAX,
+                        accept_native: true,
+                        ..InitEscrowParams::default()
+                    },
+                    offered_amount: 10,
+                },
+                INIT_ESCROW_MANAGED_ACCOUNTS,
+            ),
+        ];
+
+        // None of the accounts signs, so most handlers reading them one by one
+        // would stop at their signer check instead
+        for (instruction, expected_accounts) in instructions {
+            assert_eq!(
+                Processor::process(
+                    &program_id,
+                    &account_infos[..expected_accounts - 1],
+                    &instruction.pack()
+                ),
+                Err(ProgramError::NotEnoughAccountKeys),
+                "{:?}",
+                instruction
+            );
+        }
+    }
+
+    struct CapturedLogs(Arc<Mutex<Vec<String>>>);
+
+    impl SyscallStubs for CapturedLogs {
+        fn sol_log(&self, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    // The syscall stubs are global, so tests capturing logs take turns
+    static CAPTURING_LOGS: Mutex<()> = Mutex::new(());
+
+    fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+        let _turn = CAPTURING_LOGS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let logs = Arc::new(Mutex::new(vec![]));
+        let previous_stubs = set_syscall_stubs(Box::new(CapturedLogs(logs.clone())));
+        f();
+        set_syscall_stubs(previous_stubs);
+
+        let mut captured = vec![];
+        captured.append(&mut logs.lock().unwrap());
+        captured
+    }
+
+    #[test]
+    fn test_log_escrow_created() {
+        let escrow = Pubkey::new_unique();
+        let pda = Pubkey::new_unique();
+        let temp_token_account = Pubkey::new_unique();
+
+        let logs =
+            capture_logs(|| Processor::log_escrow_created(&escrow, &pda, &temp_token_account));
+
+        let expected = format!(
+            "Escrow created: {}, PDA: {}, temp token account: {}",
+            escrow, pda, temp_token_account
+        );
+        assert!(logs.contains(&expected));
+    }
+
+    #[test]
+    fn test_log_and_convert() {
+        let mut error = None;
+        let logs = capture_logs(|| error = Some(EscrowError::SlippageExceeded.log_and_convert()));
+
+        assert_eq!(
+            error,
+            Some(ProgramError::Custom(EscrowError::SlippageExceeded as u32))
+        );
+        assert!(logs.contains(&"Slippage Exceeded".to_string()));
+    }
+
+    // The scenario from `test_utils` is ready for Bob to take as it comes
+    #[tokio::test]
+    async fn test_escrow_scenario() {
+        let mut env = TestEnv::new().await;
+        let scenario = env.escrow_scenario(10, 20).await;
+
+        let escrow = env.get_account(&scenario.escrow).await.unwrap();
+        let escrow_info = Escrow::unpack(&escrow.data).unwrap();
+        assert_eq!(escrow_info.initializer_pubkey, scenario.alice.pubkey());
+        assert_eq!(escrow_info.expected_amount, 20);
+        assert_eq!(escrow_info.remaining_amount, 10);
+        let temp_token_account = env.get_account(&scenario.temp_token_account).await.unwrap();
+        let temp_token_account = token::unpack_account(&temp_token_account.data).unwrap();
+        assert_eq!(temp_token_account.mint, scenario.mint_x);
+        assert_eq!(temp_token_account.owner, scenario.pda);
+        assert_eq!(
+            env.token_balance(&scenario.takers_sending_token_account)
+                .await,
+            20
+        );
+
+        let exchange_ix = EscrowInstruction::exchange(
+            &env.program_id,
+            &ExchangeAccounts {
+                taker: scenario.bob.pubkey(),
+                takers_sending_token_account: scenario.takers_sending_token_account,
+                takers_token_to_receive_account: scenario.takers_token_to_receive_account,
+                pdas_temp_token_account: scenario.temp_token_account,
+                initializers_main_account: scenario.alice.pubkey(),
+                initializers_token_to_receive_account: scenario
+                    .initializer_token_to_receive_account,
+                initializers_token_to_receive_mint: scenario.mint_y,
+                escrow_account: scenario.escrow,
+                token_program: spl_token::id(),
+                pda: scenario.pda,
+                pdas_temp_token_mint: scenario.mint_x,
+                ..ExchangeAccounts::default()
+            },
+            10,
+            // A full exchange takes the whole escrow, so accept nothing less
+            10,
+        );
+        env.process(&[exchange_ix], &[&scenario.bob]).await.unwrap();
+
+        assert_eq!(
+            env.token_balance(&scenario.takers_token_to_receive_account)
+                .await,
+            10
+        );
+        assert_eq!(
+            env.token_balance(&scenario.initializer_token_to_receive_account)
+                .await,
+            20
+        );
+        assert!(env.get_account(&scenario.escrow).await.is_none());
+    }
+}
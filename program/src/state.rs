@@ -1,16 +1,85 @@
+use crate::error::EscrowError;
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+#[cfg(feature = "borsh-state")]
+use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
 };
 
+/// Where Alice's offer waits for a taker
+#[cfg_attr(feature = "borsh-state", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EscrowMode {
+    /// Moved into a temp token account owned by the PDA
+    Custodial,
+    /// Left in Alice's own token account, with the PDA approved as a delegate
+    Delegated,
+    /// Lamports rather than Token X, held by the PDA itself as a plain system account
+    NativeOffer,
+}
+
+impl Default for EscrowMode {
+    fn default() -> Self {
+        Self::Custodial
+    }
+}
+
+/// Where an escrow is in its life
+#[cfg_attr(feature = "borsh-state", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EscrowStatus {
+    /// Waiting for takers
+    Open,
+    /// An exchange is moving tokens in or out of it
+    Settling,
+    /// Fully taken or cancelled. The account is closed at that point, so it never gets stored.
+    Closed,
+}
+
+impl Default for EscrowStatus {
+    fn default() -> Self {
+        Self::Open
+    }
+}
+
+// With `borsh-state`, the Borsh encoding is byte-for-byte the same as the `Pack` one,
+// as long as the fields below are kept in the very order they are packed in.
+#[cfg_attr(feature = "borsh-state", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Escrow {
+    pub version: u8, // layout version of the state, see `Escrow::VERSION`
     pub is_initialized: bool,
     pub initializer_pubkey: Pubkey,        // Alice's pubkey
     pub temp_token_account_pubkey: Pubkey, // Alice's Token X account pubkey
     pub initializer_token_to_receive_account_pubkey: Pubkey, // Alice's Token Y account pubkey
     pub expected_amount: u64,
+    pub bump_seed: u8,                 // PDA's bump seed, found once at init
+    pub expire_at: i64,                // unix timestamp after which the trade can't be taken
+    pub remaining_amount: u64,         // Token X still left in the temp account for takers
+    pub fee_account: Pubkey,           // Marketplace's account collecting the protocol fee
+    pub fee_lamports: u64,             // Protocol fee taken out of the escrow's lamports
+    pub not_before: i64,               // unix timestamp before which Alice can't cancel
+    pub accepted_mints: [Pubkey; 3],   // Token Y mints Bob may pay with, unused are default
+    pub initializer_mint_decimals: u8, // decimals of the mint of Alice's Token Y
+    pub token_program: Pubkey,         // SPL Token or Token-2022, whichever Alice used
+    pub mode: EscrowMode,              // where Alice's Token X, or lamports, wait for a taker
+    pub accept_native: bool,           // Alice is paid in lamports rather than Token Y
+    pub status: EscrowStatus,          // only an Open escrow can be exchanged
+    pub rent_payer: Pubkey,            // gets the escrow's lamports back, default for Alice
+    pub created_at_slot: u64,          // slot the escrow was initialized in
+    pub prepared_taker: Pubkey,        // taker who prepared an exchange, default for none
+    pub prepared_deadline: i64,        // unix timestamp until which only they may finalize it
+    pub taker_fee_bps: u16,            // marketplace's cut of the Token X taken, in basis points
+    pub fee_token_account: Pubkey,     // marketplace's Token X account collecting the taker fee
+    pub reference: [u8; 32],           // opaque off-chain reference Alice tags the trade with
+    pub dust_threshold: u64,           // leftover Token X at or below this settles the escrow
+    pub allowed_taker: Pubkey,         // only taker who may fill the escrow, default for anyone
+    pub grace_seconds: i64,            // after expire_at, only Alice may close the escrow this long
+    pub offered_amount: u64,           // Token X, or lamports, Alice locked at init
+    pub basket_len: u8,                // how many of the basket's temp accounts are in use
+    pub basket_temp_token_accounts: [Pubkey; 2], // temp accounts of other Token X's, under the PDA
 }
 
 // Sealed is just a Sized of Solana version
@@ -22,24 +91,258 @@ impl IsInitialized for Escrow {
     }
 }
 
+impl Escrow {
+    /// Layout version written by `process_init_escrow`. Every change to the layout bumps it,
+    /// so that the version byte alone tells how a state is laid out.
+    /// A zeroed, never initialized state reads as version 0.
+    pub const VERSION: u8 = 15;
+
+    /// Byte size of the state each outdated version was written with, from version 1 on,
+    /// next to the field it ended with. `MigrateState` grows those accounts to `LEN`.
+    pub const OUTDATED_LENS: [usize; 14] = [
+        268, // 1: `initializer_mint_decimals`
+        300, // 2: `token_program`
+        301, // 3: `mode`
+        302, // 4: `accept_native`
+        303, // 5: `status`
+        335, // 6: `rent_payer`
+        343, // 7: `created_at_slot`
+        383, // 8: `prepared_deadline`
+        417, // 9: `fee_token_account`
+        449, // 10: `reference`
+        457, // 11: `dust_threshold`
+        489, // 12: `allowed_taker`
+        497, // 13: `grace_seconds`
+        505, // 14: `offered_amount`
+    ];
+
+    /// The first version to record `token_program`, back when the legacy token program was the only one
+    pub const TOKEN_PROGRAM_VERSION: u8 = 2;
+
+    /// Byte size of the baseline state, from before the version byte: `is_initialized`, the three keys
+    /// and `expected_amount`, in the order the current state packs them after its version byte.
+    /// No versioned layout is as short, so the size alone tells it apart.
+    pub const BASELINE_LEN: usize = 105;
+
+    /// How many temp accounts a basket may hold on top of the main one,
+    /// each with a Token X of its own that the taker gets all of
+    pub const MAX_BASKET_LEN: usize = 2;
+
+    /// Byte size of every field in the packed state, in the order they are packed
+    pub const FIELD_SIZES: [usize; 31] = [
+        1, 1, 32, 32, 32, 8, 1, 8, 8, 32, 8, 8, 96, 1, 32, 1, 1, 1, 32, 8, 32, 8, 2, 32, 32, 8, 32,
+        8, 8, 1, 64,
+    ];
+
+    /// Sum of `FIELD_SIZES`, which `Pack::LEN` (and so `Escrow::get_packed_len()`) must equal
+    pub const PACKED_FIELDS_LEN: usize = {
+        let mut len = 0;
+        let mut i = 0;
+        while i < Self::FIELD_SIZES.len() {
+            len += Self::FIELD_SIZES[i];
+            i += 1;
+        }
+        len
+    };
+
+    /// Reads a state of any outdated version as the current one. Every field added since was appended,
+    /// and their zeroed bytes read as their defaults: e.g. a version 12 state has no grace period,
+    /// neither it nor a version 13 one has the amount Alice locked on record, and none has a basket.
+    /// The one exception is `token_program`, which a version 1 state made with the legacy one has no room for.
+    pub fn unpack_outdated(src: &[u8]) -> Result<Self, ProgramError> {
+        let version = match src.first() {
+            Some(&version) if version > 0 && version < Self::VERSION => version,
+            _ => return Err(EscrowError::UnsupportedStateVersion.log_and_convert()),
+        };
+        if src.len() != Self::OUTDATED_LENS[usize::from(version) - 1] {
+            return Err(EscrowError::UnsupportedStateVersion.log_and_convert());
+        }
+        let mut migrated = [0u8; Self::LEN];
+        migrated[..src.len()].copy_from_slice(src);
+        migrated[0] = Self::VERSION;
+        let mut escrow = Self::unpack(&migrated)?;
+        if version < Self::TOKEN_PROGRAM_VERSION {
+            escrow.token_program = spl_token::id();
+        }
+        Ok(escrow)
+    }
+
+    /// Reads a baseline state as the current one. A baseline escrow was taken whole and never expired,
+    /// and it had the legacy token program hold its Token X. The rest, like what is left in its temp account,
+    /// the state never recorded, so `MigrateState` fills it in from the escrow's accounts.
+    pub fn unpack_baseline(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() != Self::BASELINE_LEN {
+            return Err(EscrowError::UnsupportedStateVersion.log_and_convert());
+        }
+        let mut migrated = [0u8; Self::LEN];
+        migrated[0] = Self::VERSION;
+        migrated[1..=Self::BASELINE_LEN].copy_from_slice(src);
+        let mut escrow = Self::unpack(&migrated)?;
+        escrow.expire_at = i64::MAX;
+        escrow.token_program = spl_token::id();
+        Ok(escrow)
+    }
+
+    /// Splits a packed escrow state into the bytes of each of its fields
+    pub fn split_fields(src: &[u8]) -> Vec<&[u8]> {
+        let mut rest = src;
+        Self::FIELD_SIZES
+            .iter()
+            .map(|size| {
+                let (field, tail) = rest.split_at(*size);
+                rest = tail;
+                field
+            })
+            .collect()
+    }
+
+    /// Whether a taker prepared an exchange that is still theirs alone to finalize at `now`
+    pub fn is_prepared(&self, now: i64) -> bool {
+        self.status == EscrowStatus::Settling
+            && self.prepared_taker != Pubkey::default()
+            && now <= self.prepared_deadline
+    }
+
+    /// Whether the escrow waits for takers at `now`. A prepared exchange left to lapse opens it up again.
+    pub fn is_open(&self, now: i64) -> bool {
+        match self.status {
+            EscrowStatus::Open => true,
+            EscrowStatus::Settling => {
+                self.prepared_taker != Pubkey::default() && now > self.prepared_deadline
+            }
+            EscrowStatus::Closed => false,
+        }
+    }
+
+    /// Whether Bob may pay with Token Y's of `mint`. An empty allow-list accepts any mint here,
+    /// leaving it to the mint of Alice's Token Y account alone.
+    pub fn accepts_mint(&self, mint: &Pubkey) -> bool {
+        self.accepts_any_mint()
+            || (*mint != Pubkey::default() && self.accepted_mints.contains(mint))
+    }
+
+    /// Whether the allow-list is empty, so that the mint of Alice's Token Y account is the only one
+    pub fn accepts_any_mint(&self) -> bool {
+        self.accepted_mints
+            .iter()
+            .all(|accepted| *accepted == Pubkey::default())
+    }
+
+    /// The unix timestamp until which only Alice may close her expired escrow, before keepers may reap it
+    pub fn grace_ends_at(&self) -> i64 {
+        self.expire_at.saturating_add(self.grace_seconds)
+    }
+
+    /// Whether `expected_amount` is counted in units of a mint with `decimals`. It is a raw amount,
+    /// so it only keeps its meaning while the mint of Alice's Token Y has the decimals it had at init.
+    pub fn priced_in_decimals(&self, decimals: u8) -> bool {
+        self.initializer_mint_decimals == decimals
+    }
+
+    /// Whether `taker` may fill the escrow. Only a private one, naming its taker, turns anyone away.
+    pub fn allows_taker(&self, taker: &Pubkey) -> bool {
+        self.allowed_taker == Pubkey::default() || self.allowed_taker == *taker
+    }
+
+    /// The temp accounts Alice added to her offer, besides the main one
+    pub fn basket(&self) -> &[Pubkey] {
+        &self.basket_temp_token_accounts[..usize::from(self.basket_len)]
+    }
+}
+
+#[cfg(feature = "borsh-state")]
+impl Escrow {
+    pub fn try_from_slice_borsh(src: &[u8]) -> Result<Self, ProgramError> {
+        Self::try_from_slice(src).map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    pub fn to_vec_borsh(&self) -> Result<Vec<u8>, ProgramError> {
+        Ok(self.try_to_vec()?)
+    }
+}
+
+// Adding a field to `FIELD_SIZES` without growing `LEN`, or the other way round, fails the build
+const _: () = assert!(Escrow::LEN == Escrow::PACKED_FIELDS_LEN);
+// and so does bumping `VERSION` without recording the size of the layout it replaces
+const _: () = assert!(Escrow::OUTDATED_LENS.len() + 1 == Escrow::VERSION as usize);
+
 impl Pack for Escrow {
-    const LEN: usize = 105;
+    const LEN: usize = 570;
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
         let src = array_ref![src, 0, Escrow::LEN];
         let (
+            version,
             is_initialized,
             initializer_pubkey,
             temp_token_account_pubkey,
             initializer_token_to_receive_account_pubkey,
             expected_amount,
-        ) = array_refs![src, 1, 32, 32, 32, 8];
+            bump_seed,
+            expire_at,
+            remaining_amount,
+            fee_account,
+            fee_lamports,
+            not_before,
+            accepted_mints,
+            initializer_mint_decimals,
+            token_program,
+            mode,
+            accept_native,
+            status,
+            rent_payer,
+            created_at_slot,
+            prepared_taker,
+            prepared_deadline,
+            taker_fee_bps,
+            fee_token_account,
+            reference,
+            dust_threshold,
+            allowed_taker,
+            grace_seconds,
+            offered_amount,
+            basket_len,
+            basket_temp_token_accounts,
+        ) = array_refs![
+            src, 1, 1, 32, 32, 32, 8, 1, 8, 8, 32, 8, 8, 96, 1, 32, 1, 1, 1, 32, 8, 32, 8, 2, 32,
+            32, 8, 32, 8, 8, 1, 64
+        ];
         let is_initialized = match is_initialized {
             [0] => false,
             [1] => true,
             _ => return Err(ProgramError::InvalidAccountData),
         };
+        let accept_native = match accept_native {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let status = match status {
+            [0] => EscrowStatus::Open,
+            [1] => EscrowStatus::Settling,
+            [2] => EscrowStatus::Closed,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let mode = match mode {
+            [0] => EscrowMode::Custodial,
+            [1] => EscrowMode::Delegated,
+            [2] => EscrowMode::NativeOffer,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let version = match (version[0], is_initialized) {
+            (0, false) => 0,
+            (Escrow::VERSION, _) => Escrow::VERSION,
+            _ => return Err(EscrowError::UnsupportedStateVersion.log_and_convert()),
+        };
+        let (accepted_mint_0, accepted_mint_1, accepted_mint_2) =
+            array_refs![accepted_mints, 32, 32, 32];
+        if usize::from(basket_len[0]) > Escrow::MAX_BASKET_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (basket_temp_token_account_0, basket_temp_token_account_1) =
+            array_refs![basket_temp_token_accounts, 32, 32];
 
         Ok(Escrow {
+            version,
             is_initialized,
             initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
             temp_token_account_pubkey: Pubkey::new_from_array(*temp_token_account_pubkey),
@@ -47,32 +350,680 @@ impl Pack for Escrow {
                 *initializer_token_to_receive_account_pubkey,
             ),
             expected_amount: u64::from_le_bytes(*expected_amount),
+            bump_seed: bump_seed[0],
+            expire_at: i64::from_le_bytes(*expire_at),
+            remaining_amount: u64::from_le_bytes(*remaining_amount),
+            fee_account: Pubkey::new_from_array(*fee_account),
+            fee_lamports: u64::from_le_bytes(*fee_lamports),
+            not_before: i64::from_le_bytes(*not_before),
+            accepted_mints: [
+                Pubkey::new_from_array(*accepted_mint_0),
+                Pubkey::new_from_array(*accepted_mint_1),
+                Pubkey::new_from_array(*accepted_mint_2),
+            ],
+            initializer_mint_decimals: initializer_mint_decimals[0],
+            token_program: Pubkey::new_from_array(*token_program),
+            mode,
+            accept_native,
+            status,
+            rent_payer: Pubkey::new_from_array(*rent_payer),
+            created_at_slot: u64::from_le_bytes(*created_at_slot),
+            prepared_taker: Pubkey::new_from_array(*prepared_taker),
+            prepared_deadline: i64::from_le_bytes(*prepared_deadline),
+            taker_fee_bps: u16::from_le_bytes(*taker_fee_bps),
+            fee_token_account: Pubkey::new_from_array(*fee_token_account),
+            reference: *reference,
+            dust_threshold: u64::from_le_bytes(*dust_threshold),
+            allowed_taker: Pubkey::new_from_array(*allowed_taker),
+            grace_seconds: i64::from_le_bytes(*grace_seconds),
+            offered_amount: u64::from_le_bytes(*offered_amount),
+            basket_len: basket_len[0],
+            basket_temp_token_accounts: [
+                Pubkey::new_from_array(*basket_temp_token_account_0),
+                Pubkey::new_from_array(*basket_temp_token_account_1),
+            ],
         })
     }
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let dst = array_mut_ref![dst, 0, Escrow::LEN];
         let (
+            version_dst,
             is_initialized_dst,
             initializer_pubkey_dst,
             temp_token_account_pubkey_dst,
             initializer_token_to_receive_account_pubkey_dst,
             expected_amount_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8];
+            bump_seed_dst,
+            expire_at_dst,
+            remaining_amount_dst,
+            fee_account_dst,
+            fee_lamports_dst,
+            not_before_dst,
+            accepted_mints_dst,
+            initializer_mint_decimals_dst,
+            token_program_dst,
+            mode_dst,
+            accept_native_dst,
+            status_dst,
+            rent_payer_dst,
+            created_at_slot_dst,
+            prepared_taker_dst,
+            prepared_deadline_dst,
+            taker_fee_bps_dst,
+            fee_token_account_dst,
+            reference_dst,
+            dust_threshold_dst,
+            allowed_taker_dst,
+            grace_seconds_dst,
+            offered_amount_dst,
+            basket_len_dst,
+            basket_temp_token_accounts_dst,
+        ) = mut_array_refs![
+            dst, 1, 1, 32, 32, 32, 8, 1, 8, 8, 32, 8, 8, 96, 1, 32, 1, 1, 1, 32, 8, 32, 8, 2, 32,
+            32, 8, 32, 8, 8, 1, 64
+        ];
 
         let Escrow {
+            version,
             is_initialized,
             initializer_pubkey,
             temp_token_account_pubkey,
             initializer_token_to_receive_account_pubkey,
             expected_amount,
+            bump_seed,
+            expire_at,
+            remaining_amount,
+            fee_account,
+            fee_lamports,
+            not_before,
+            accepted_mints,
+            initializer_mint_decimals,
+            token_program,
+            mode,
+            accept_native,
+            status,
+            rent_payer,
+            created_at_slot,
+            prepared_taker,
+            prepared_deadline,
+            taker_fee_bps,
+            fee_token_account,
+            reference,
+            dust_threshold,
+            allowed_taker,
+            grace_seconds,
+            offered_amount,
+            basket_len,
+            basket_temp_token_accounts,
         } = self;
 
+        version_dst[0] = *version;
         is_initialized_dst[0] = *is_initialized as u8;
         initializer_pubkey_dst.copy_from_slice(initializer_pubkey.as_ref());
         temp_token_account_pubkey_dst.copy_from_slice(temp_token_account_pubkey.as_ref());
         initializer_token_to_receive_account_pubkey_dst
             .copy_from_slice(initializer_token_to_receive_account_pubkey.as_ref());
         *expected_amount_dst = expected_amount.to_le_bytes();
+        bump_seed_dst[0] = *bump_seed;
+        *expire_at_dst = expire_at.to_le_bytes();
+        *remaining_amount_dst = remaining_amount.to_le_bytes();
+        fee_account_dst.copy_from_slice(fee_account.as_ref());
+        *fee_lamports_dst = fee_lamports.to_le_bytes();
+        *not_before_dst = not_before.to_le_bytes();
+        for (mint_dst, mint) in accepted_mints_dst.chunks_exact_mut(32).zip(accepted_mints) {
+            mint_dst.copy_from_slice(mint.as_ref());
+        }
+        initializer_mint_decimals_dst[0] = *initializer_mint_decimals;
+        token_program_dst.copy_from_slice(token_program.as_ref());
+        mode_dst[0] = *mode as u8;
+        accept_native_dst[0] = *accept_native as u8;
+        status_dst[0] = *status as u8;
+        rent_payer_dst.copy_from_slice(rent_payer.as_ref());
+        *created_at_slot_dst = created_at_slot.to_le_bytes();
+        prepared_taker_dst.copy_from_slice(prepared_taker.as_ref());
+        *prepared_deadline_dst = prepared_deadline.to_le_bytes();
+        *taker_fee_bps_dst = taker_fee_bps.to_le_bytes();
+        fee_token_account_dst.copy_from_slice(fee_token_account.as_ref());
+        *reference_dst = *reference;
+        *dust_threshold_dst = dust_threshold.to_le_bytes();
+        allowed_taker_dst.copy_from_slice(allowed_taker.as_ref());
+        *grace_seconds_dst = grace_seconds.to_le_bytes();
+        *offered_amount_dst = offered_amount.to_le_bytes();
+        basket_len_dst[0] = *basket_len;
+        for (account_dst, account) in basket_temp_token_accounts_dst
+            .chunks_exact_mut(32)
+            .zip(basket_temp_token_accounts)
+        {
+            account_dst.copy_from_slice(account.as_ref());
+        }
+    }
+}
+
+/// The switches that apply to every escrow at once, kept in the PDA at `config::PROGRAM_CONFIG_SEED`
+#[cfg_attr(feature = "borsh-state", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProgramConfig {
+    pub is_initialized: bool,
+    pub exchanges_paused: bool, // Set by the admin to turn every taker away
+    pub bump_seed: u8, // Kept so that takers tell the program config apart without searching for it
+}
+
+impl Sealed for ProgramConfig {}
+
+impl IsInitialized for ProgramConfig {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for ProgramConfig {
+    const LEN: usize = 3;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, ProgramConfig::LEN];
+        let (is_initialized, exchanges_paused, bump_seed) = array_refs![src, 1, 1, 1];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let exchanges_paused = match exchanges_paused {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        Ok(ProgramConfig {
+            is_initialized,
+            exchanges_paused,
+            bump_seed: bump_seed[0],
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, ProgramConfig::LEN];
+        let (is_initialized_dst, exchanges_paused_dst, bump_seed_dst) =
+            mut_array_refs![dst, 1, 1, 1];
+        is_initialized_dst[0] = self.is_initialized as u8;
+        exchanges_paused_dst[0] = self.exchanges_paused as u8;
+        bump_seed_dst[0] = self.bump_seed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    const BUMP_SEED_OFFSET: usize = 1 + 1 + 32 + 32 + 32 + 8;
+
+    fn sample_escrow() -> Escrow {
+        Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            temp_token_account_pubkey: Pubkey::new_unique(),
+            initializer_token_to_receive_account_pubkey: Pubkey::new_unique(),
+            expected_amount: 42,
+            bump_seed: 254,
+            expire_at: 1_700_000_000,
+            remaining_amount: 7,
+            fee_account: Pubkey::new_unique(),
+            fee_lamports: 5000,
+            not_before: 1_600_000_000,
+            accepted_mints: [Pubkey::new_unique(), Pubkey::default(), Pubkey::default()],
+            initializer_mint_decimals: 6,
+            token_program: spl_token::id(),
+            mode: EscrowMode::Delegated,
+            accept_native: true,
+            status: EscrowStatus::Settling,
+            rent_payer: Pubkey::new_unique(),
+            created_at_slot: 123_456,
+            prepared_taker: Pubkey::new_unique(),
+            prepared_deadline: 1_700_000_600,
+            taker_fee_bps: 250,
+            fee_token_account: Pubkey::new_unique(),
+            reference: [7; 32],
+            dust_threshold: 3,
+            allowed_taker: Pubkey::new_unique(),
+            grace_seconds: 3600,
+            offered_amount: 10,
+            basket_len: 1,
+            basket_temp_token_accounts: [Pubkey::new_unique(), Pubkey::default()],
+        }
+    }
+
+    #[test]
+    fn test_tampered_bump_seed_does_not_recreate_pda() {
+        let program_id = Pubkey::new_unique();
+        let escrow_account = Pubkey::new_unique();
+        let seeds: &[&[u8]] = &[config::ESCROW_SEED, escrow_account.as_ref()];
+        let (pda, bump_seed) = Pubkey::find_program_address(seeds, &program_id);
+
+        let escrow = Escrow {
+            bump_seed,
+            ..sample_escrow()
+        };
+        let mut data = [0u8; Escrow::LEN];
+        Escrow::pack(escrow, &mut data).unwrap();
+
+        // Untouched state recreates the very same PDA
+        let stored = Escrow::unpack(&data).unwrap();
+        assert_eq!(
+            Pubkey::create_program_address(&[seeds[0], seeds[1], &[stored.bump_seed]], &program_id),
+            Ok(pda)
+        );
+
+        // `find_program_address` searches downwards from 255, so every bump above the
+        // canonical one lands on the curve and must be rejected outright...
+        for tampered_bump in (bump_seed..=u8::MAX).skip(1) {
+            data[BUMP_SEED_OFFSET] = tampered_bump;
+            let tampered = Escrow::unpack(&data).unwrap();
+            assert!(Pubkey::create_program_address(
+                &[seeds[0], seeds[1], &[tampered.bump_seed]],
+                &program_id
+            )
+            .is_err());
+        }
+
+        // ...and any bump below it can never recreate the same PDA.
+        for tampered_bump in 0..bump_seed {
+            data[BUMP_SEED_OFFSET] = tampered_bump;
+            let tampered = Escrow::unpack(&data).unwrap();
+            assert_ne!(
+                Pubkey::create_program_address(
+                    &[seeds[0], seeds[1], &[tampered.bump_seed]],
+                    &program_id
+                ),
+                Ok(pda)
+            );
+        }
+    }
+
+    #[test]
+    fn test_pack_unpack_round_trip() {
+        // Every field differs from its default, so a field that is skipped or misplaced shows up
+        let escrow = Escrow {
+            bump_seed: 253,
+            expire_at: -1_700_000_000,
+            accepted_mints: [
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+            ],
+            initializer_mint_decimals: 9,
+            token_program: spl_token_2022::id(),
+            ..sample_escrow()
+        };
+        let mut packed = [0u8; Escrow::LEN];
+        Escrow::pack(escrow.clone(), &mut packed).unwrap();
+
+        let unpacked = Escrow::unpack(&packed).unwrap();
+        assert_eq!(unpacked.version, escrow.version);
+        assert_eq!(unpacked.is_initialized, escrow.is_initialized);
+        assert_eq!(unpacked.initializer_pubkey, escrow.initializer_pubkey);
+        assert_eq!(
+            unpacked.temp_token_account_pubkey,
+            escrow.temp_token_account_pubkey
+        );
+        assert_eq!(
+            unpacked.initializer_token_to_receive_account_pubkey,
+            escrow.initializer_token_to_receive_account_pubkey
+        );
+        assert_eq!(unpacked.expected_amount, escrow.expected_amount);
+        assert_eq!(unpacked.bump_seed, escrow.bump_seed);
+        assert_eq!(unpacked.expire_at, escrow.expire_at);
+        assert_eq!(unpacked.remaining_amount, escrow.remaining_amount);
+        assert_eq!(unpacked.fee_account, escrow.fee_account);
+        assert_eq!(unpacked.fee_lamports, escrow.fee_lamports);
+        assert_eq!(unpacked.not_before, escrow.not_before);
+        assert_eq!(unpacked.accepted_mints, escrow.accepted_mints);
+        assert_eq!(
+            unpacked.initializer_mint_decimals,
+            escrow.initializer_mint_decimals
+        );
+        assert_eq!(unpacked.token_program, escrow.token_program);
+        assert_eq!(unpacked.mode, escrow.mode);
+        assert_eq!(unpacked.accept_native, escrow.accept_native);
+        assert_eq!(unpacked.status, escrow.status);
+        assert_eq!(unpacked.rent_payer, escrow.rent_payer);
+        assert_eq!(unpacked.created_at_slot, escrow.created_at_slot);
+        assert_eq!(unpacked.prepared_taker, escrow.prepared_taker);
+        assert_eq!(unpacked.prepared_deadline, escrow.prepared_deadline);
+        assert_eq!(unpacked.taker_fee_bps, escrow.taker_fee_bps);
+        assert_eq!(unpacked.fee_token_account, escrow.fee_token_account);
+        assert_eq!(unpacked.reference, escrow.reference);
+        assert_eq!(unpacked.dust_threshold, escrow.dust_threshold);
+        assert_eq!(unpacked.allowed_taker, escrow.allowed_taker);
+        assert_eq!(unpacked.grace_seconds, escrow.grace_seconds);
+        assert_eq!(unpacked.offered_amount, escrow.offered_amount);
+        assert_eq!(unpacked.basket_len, escrow.basket_len);
+        assert_eq!(
+            unpacked.basket_temp_token_accounts,
+            escrow.basket_temp_token_accounts
+        );
+        assert_eq!(unpacked.basket(), &escrow.basket_temp_token_accounts[..1]);
+        assert_eq!(unpacked, escrow);
+    }
+
+    #[cfg(feature = "borsh-state")]
+    #[test]
+    fn test_borsh_and_pack_encodings_match() {
+        let escrow = sample_escrow();
+        let mut packed = [0u8; Escrow::LEN];
+        Escrow::pack_into_slice(&escrow, &mut packed);
+
+        let encoded = escrow.to_vec_borsh().unwrap();
+        assert_eq!(encoded, packed);
+        assert_eq!(Escrow::try_from_slice_borsh(&packed).unwrap(), escrow);
+        assert_eq!(Escrow::unpack(&encoded).unwrap(), escrow);
+    }
+
+    #[test]
+    fn test_split_fields() {
+        let escrow = Escrow {
+            expire_at: -1,
+            ..sample_escrow()
+        };
+        let mut packed = [0u8; Escrow::LEN];
+        Escrow::pack_into_slice(&escrow, &mut packed);
+
+        let fields = Escrow::split_fields(&packed);
+
+        assert_eq!(fields.len(), Escrow::FIELD_SIZES.len());
+        assert_eq!(fields[0], [Escrow::VERSION]);
+        assert_eq!(fields[1], [1]);
+        assert_eq!(fields[2], escrow.initializer_pubkey.as_ref());
+        assert_eq!(fields[3], escrow.temp_token_account_pubkey.as_ref());
+        assert_eq!(
+            fields[4],
+            escrow.initializer_token_to_receive_account_pubkey.as_ref()
+        );
+        assert_eq!(fields[5], 42u64.to_le_bytes());
+        assert_eq!(fields[6], [254]);
+        assert_eq!(fields[7], (-1i64).to_le_bytes());
+        assert_eq!(fields[8], 7u64.to_le_bytes());
+        assert_eq!(fields[9], escrow.fee_account.as_ref());
+        assert_eq!(fields[10], 5000u64.to_le_bytes());
+        assert_eq!(fields[11], 1_600_000_000i64.to_le_bytes());
+        assert_eq!(&fields[12][..32], escrow.accepted_mints[0].as_ref());
+        assert_eq!(&fields[12][32..], [0; 64]);
+        assert_eq!(fields[13], [6]);
+        assert_eq!(fields[14], spl_token::id().as_ref());
+        assert_eq!(fields[15], [1]);
+        assert_eq!(fields[16], [1]);
+        assert_eq!(fields[17], [1]);
+        assert_eq!(fields[18], escrow.rent_payer.as_ref());
+        assert_eq!(fields[19], 123_456u64.to_le_bytes());
+        assert_eq!(fields[20], escrow.prepared_taker.as_ref());
+        assert_eq!(fields[21], 1_700_000_600i64.to_le_bytes());
+        assert_eq!(fields[22], 250u16.to_le_bytes());
+        assert_eq!(fields[23], escrow.fee_token_account.as_ref());
+        assert_eq!(fields[24], [7; 32]);
+        assert_eq!(fields[25], 3u64.to_le_bytes());
+        assert_eq!(fields[26], escrow.allowed_taker.as_ref());
+        assert_eq!(fields[27], 3600i64.to_le_bytes());
+        assert_eq!(fields[28], 10u64.to_le_bytes());
+        assert_eq!(fields[29], [1]);
+        assert_eq!(
+            &fields[30][..32],
+            escrow.basket_temp_token_accounts[0].as_ref()
+        );
+        assert_eq!(&fields[30][32..], [0; 32]);
+    }
+
+    #[test]
+    fn test_field_sizes() {
+        use std::mem::size_of;
+
+        assert_eq!(
+            Escrow::FIELD_SIZES,
+            [
+                size_of::<u8>(),           // version
+                size_of::<bool>(),         // is_initialized
+                size_of::<Pubkey>(),       // initializer_pubkey
+                size_of::<Pubkey>(),       // temp_token_account_pubkey
+                size_of::<Pubkey>(),       // initializer_token_to_receive_account_pubkey
+                size_of::<u64>(),          // expected_amount
+                size_of::<u8>(),           // bump_seed
+                size_of::<i64>(),          // expire_at
+                size_of::<u64>(),          // remaining_amount
+                size_of::<Pubkey>(),       // fee_account
+                size_of::<u64>(),          // fee_lamports
+                size_of::<i64>(),          // not_before
+                size_of::<[Pubkey; 3]>(),  // accepted_mints
+                size_of::<u8>(),           // initializer_mint_decimals
+                size_of::<Pubkey>(),       // token_program
+                size_of::<EscrowMode>(),   // mode
+                size_of::<bool>(),         // accept_native
+                size_of::<EscrowStatus>(), // status
+                size_of::<Pubkey>(),       // rent_payer
+                size_of::<u64>(),          // created_at_slot
+                size_of::<Pubkey>(),       // prepared_taker
+                size_of::<i64>(),          // prepared_deadline
+                size_of::<u16>(),          // taker_fee_bps
+                size_of::<Pubkey>(),       // fee_token_account
+                size_of::<[u8; 32]>(),     // reference
+                size_of::<u64>(),          // dust_threshold
+                size_of::<Pubkey>(),       // allowed_taker
+                size_of::<i64>(),          // grace_seconds
+                size_of::<u64>(),          // offered_amount
+                size_of::<u8>(),           // basket_len
+                size_of::<[Pubkey; 2]>(),  // basket_temp_token_accounts
+            ]
+        );
+        assert_eq!(Escrow::PACKED_FIELDS_LEN, Escrow::LEN);
+        assert_eq!(Escrow::get_packed_len(), Escrow::LEN);
+    }
+
+    #[test]
+    fn test_prepared_exchange_lapses() {
+        let mut escrow = Escrow::default();
+        assert!(escrow.is_open(100));
+        assert!(!escrow.is_prepared(100));
+
+        // In the middle of an exchange, nobody prepared it
+        escrow.status = EscrowStatus::Settling;
+        assert!(!escrow.is_open(100));
+        assert!(!escrow.is_prepared(100));
+
+        escrow.prepared_taker = Pubkey::new_unique();
+        escrow.prepared_deadline = 100;
+        assert!(escrow.is_prepared(100));
+        assert!(!escrow.is_open(100));
+        assert!(!escrow.is_prepared(101));
+        assert!(escrow.is_open(101));
+    }
+
+    #[test]
+    fn test_basket_len_is_bounded() {
+        let escrow = Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            basket_len: Escrow::MAX_BASKET_LEN as u8,
+            basket_temp_token_accounts: [Pubkey::new_unique(), Pubkey::new_unique()],
+            ..Escrow::default()
+        };
+        let mut packed = [0u8; Escrow::LEN];
+        Escrow::pack(escrow.clone(), &mut packed).unwrap();
+        assert_eq!(
+            Escrow::unpack(&packed).unwrap().basket(),
+            escrow.basket_temp_token_accounts
+        );
+
+        // A basket can't claim more temp accounts than there is room for
+        packed[Escrow::OUTDATED_LENS[Escrow::OUTDATED_LENS.len() - 1]] =
+            Escrow::MAX_BASKET_LEN as u8 + 1;
+        assert_eq!(
+            Escrow::unpack(&packed),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_unsupported_state_version() {
+        let escrow = Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            ..Escrow::default()
+        };
+        let mut packed = [0u8; Escrow::LEN];
+        Escrow::pack(escrow, &mut packed).unwrap();
+
+        packed[0] = 0xFF;
+        assert_eq!(
+            Escrow::unpack(&packed),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+        // An initialized state can't claim to predate versioning either
+        packed[0] = 0;
+        assert_eq!(
+            Escrow::unpack(&packed),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+        // nor an older one to be laid out like the current one
+        for version in 1..Escrow::VERSION {
+            packed[0] = version;
+            assert_eq!(
+                Escrow::unpack(&packed),
+                Err(EscrowError::UnsupportedStateVersion.into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_unpack_outdated() {
+        let mut escrow = Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            expected_amount: 20,
+            expire_at: 1_700_000_000,
+            allowed_taker: Pubkey::new_unique(),
+            ..Escrow::default()
+        };
+        let mut packed = [0u8; Escrow::LEN];
+
+        // A version 14 state is the current one cut short before the basket
+        escrow.grace_seconds = 3600;
+        escrow.offered_amount = 10;
+        Escrow::pack(escrow.clone(), &mut packed).unwrap();
+        let mut v14 = packed[..Escrow::OUTDATED_LENS[13]].to_vec();
+        v14[0] = 14;
+        assert_eq!(Escrow::unpack_outdated(&v14).unwrap(), escrow);
+        // a version 13 one before `offered_amount`
+        escrow.offered_amount = 0;
+        let mut v13 = packed[..Escrow::OUTDATED_LENS[12]].to_vec();
+        v13[0] = 13;
+        assert_eq!(Escrow::unpack_outdated(&v13).unwrap(), escrow);
+        // a version 12 one before `grace_seconds`
+        escrow.grace_seconds = 0;
+        let mut v12 = packed[..Escrow::OUTDATED_LENS[11]].to_vec();
+        v12[0] = 12;
+        assert_eq!(Escrow::unpack_outdated(&v12).unwrap(), escrow);
+        // and a version 1 one even before `token_program`, back when only the legacy one was supported
+        let mut v1 = packed[..Escrow::OUTDATED_LENS[0]].to_vec();
+        v1[0] = 1;
+        let v1 = Escrow::unpack_outdated(&v1).unwrap();
+        assert_eq!(v1.token_program, spl_token::id());
+        assert_eq!(v1.allowed_taker, Pubkey::default());
+        // Every version in between has a layout of its own
+        for (version, len) in (1..Escrow::VERSION).zip(Escrow::OUTDATED_LENS) {
+            let mut outdated = packed[..len].to_vec();
+            outdated[0] = version;
+            assert!(Escrow::unpack_outdated(&outdated).is_ok());
+        }
+
+        // Anything else is not an outdated state
+        assert_eq!(
+            Escrow::unpack_outdated(&packed),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+        // nor one laid out like another version
+        v13[0] = 12;
+        assert_eq!(
+            Escrow::unpack_outdated(&v13),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+        v13[0] = Escrow::VERSION;
+        assert_eq!(
+            Escrow::unpack_outdated(&v13),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+        v13[0] = 0;
+        assert_eq!(
+            Escrow::unpack_outdated(&v13),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+        assert_eq!(
+            Escrow::unpack_outdated(&[]),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+    }
+
+    #[test]
+    fn test_unpack_baseline() {
+        let escrow = Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            temp_token_account_pubkey: Pubkey::new_unique(),
+            initializer_token_to_receive_account_pubkey: Pubkey::new_unique(),
+            expected_amount: 20,
+            expire_at: i64::MAX,
+            token_program: spl_token::id(),
+            ..Escrow::default()
+        };
+        let mut packed = [0u8; Escrow::LEN];
+        Escrow::pack(escrow.clone(), &mut packed).unwrap();
+
+        // The baseline state is the current one up to `expected_amount`, without the version byte
+        let baseline = &packed[1..=Escrow::BASELINE_LEN];
+        assert_eq!(Escrow::unpack_baseline(baseline).unwrap(), escrow);
+
+        assert_eq!(
+            Escrow::unpack_baseline(&packed[..Escrow::BASELINE_LEN]),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            Escrow::unpack_baseline(&packed[..Escrow::OUTDATED_LENS[0]]),
+            Err(EscrowError::UnsupportedStateVersion.into())
+        );
+    }
+
+    #[test]
+    fn test_zeroed_state_is_uninitialized() {
+        let escrow = Escrow::unpack_unchecked(&[0u8; Escrow::LEN]).unwrap();
+        assert_eq!(escrow, Escrow::default());
+        assert!(!escrow.is_initialized);
+        // and `unpack` refuses to hand out a state nobody has written yet
+        assert_eq!(
+            Escrow::unpack(&[0u8; Escrow::LEN]),
+            Err(ProgramError::UninitializedAccount)
+        );
+    }
+
+    #[test]
+    fn test_program_config_round_trip() {
+        let program_config = ProgramConfig {
+            is_initialized: true,
+            exchanges_paused: true,
+            bump_seed: 254,
+        };
+        let mut packed = [0u8; ProgramConfig::LEN];
+        ProgramConfig::pack(program_config, &mut packed).unwrap();
+        assert_eq!(packed, [1, 1, 254]);
+        assert_eq!(ProgramConfig::unpack(&packed).unwrap(), program_config);
+
+        // Both flags are strict bools
+        packed[1] = 2;
+        assert_eq!(
+            ProgramConfig::unpack(&packed),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            ProgramConfig::unpack_unchecked(&[0; ProgramConfig::LEN]).unwrap(),
+            ProgramConfig::default()
+        );
     }
 }
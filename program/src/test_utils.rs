@@ -0,0 +1,392 @@
+// Runs the program in a `ProgramTest` bank for tests that need real token accounts.
+// Every helper sends its own transaction, paid and signed by the bank's payer.
+//
+// The processor's unit tests use it as `crate::test_utils`, and tests/escrow.rs includes
+// this very file, so it only names the program as `solana_escrow_program`.
+// Neither of them needs every helper.
+#![allow(dead_code)]
+
+use solana_escrow_program::{
+    config,
+    instructions::{EscrowInstruction, InitEscrowParams},
+    processor::Processor,
+    state::Escrow,
+    token,
+};
+use solana_program::{
+    clock::Clock, instruction::Instruction, program_pack::Pack, pubkey::Pubkey, system_instruction,
+};
+use solana_program_test::{processor, BanksClient, ProgramTest};
+use solana_sdk::{
+    account::Account,
+    hash::Hash,
+    instruction::InstructionError,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+    transport::TransportError,
+};
+use spl_token_2022::{
+    extension::{transfer_fee::instruction::initialize_transfer_fee_config, ExtensionType},
+    state::Mint,
+};
+
+pub struct TestEnv {
+    pub banks_client: BanksClient,
+    pub payer: Keypair,
+    pub recent_blockhash: Hash,
+    pub program_id: Pubkey,
+    // The token program mints and token accounts are created with
+    pub token_program: Pubkey,
+}
+
+// Everything an escrow of Token X for Token Y is made of, once Alice has made it
+// and Bob holds enough Token Y to take it
+pub struct EscrowScenario {
+    pub alice: Keypair,
+    pub bob: Keypair,
+    pub mint_x: Pubkey,
+    pub mint_y: Pubkey,
+    pub escrow: Pubkey,
+    pub pda: Pubkey,
+    pub temp_token_account: Pubkey,
+    pub initializer_token_to_receive_account: Pubkey,
+    pub takers_sending_token_account: Pubkey,
+    pub takers_token_to_receive_account: Pubkey,
+}
+
+pub fn program_test(program_id: &Pubkey) -> ProgramTest {
+    ProgramTest::new(
+        "solana_escrow_program",
+        *program_id,
+        processor!(Processor::process),
+    )
+}
+
+// `ProgramTest` only loads the legacy token program by itself
+pub fn add_token_2022(program_test: &mut ProgramTest) {
+    program_test.add_program(
+        "spl_token_2022",
+        spl_token_2022::id(),
+        processor!(spl_token_2022::processor::Processor::process),
+    );
+}
+
+pub fn instruction_error(error: TransportError) -> InstructionError {
+    match error.unwrap() {
+        TransactionError::InstructionError(_, error) => error,
+        error => panic!("unexpected transaction error: {:?}", error),
+    }
+}
+
+impl TestEnv {
+    pub async fn new() -> Self {
+        let program_id = Pubkey::new_unique();
+        Self::start(program_id, program_test(&program_id)).await
+    }
+
+    // Mints and token accounts are Token-2022 ones, see `TestEnv::create_mint`
+    pub async fn new_token_2022() -> Self {
+        let program_id = Pubkey::new_unique();
+        let mut program_test = program_test(&program_id);
+        add_token_2022(&mut program_test);
+        let mut env = Self::start(program_id, program_test).await;
+        env.token_program = spl_token_2022::id();
+        env
+    }
+
+    // For tests that need to plant accounts before the bank starts
+    pub async fn start(program_id: Pubkey, program_test: ProgramTest) -> Self {
+        let (banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        Self {
+            banks_client,
+            payer,
+            recent_blockhash,
+            program_id,
+            token_program: spl_token::id(),
+        }
+    }
+
+    // The payer always signs and pays for the transaction
+    pub async fn process(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), TransportError> {
+        let mut transaction = Transaction::new_with_payer(instructions, Some(&self.payer.pubkey()));
+        let mut all_signers = vec![&self.payer];
+        all_signers.extend_from_slice(signers);
+        transaction.sign(&all_signers, self.recent_blockhash);
+
+        self.banks_client.process_transaction(transaction).await
+    }
+
+    pub async fn get_account(&mut self, pubkey: &Pubkey) -> Option<Account> {
+        self.banks_client.get_account(*pubkey).await.unwrap()
+    }
+
+    pub async fn minimum_balance(&mut self, len: usize) -> u64 {
+        self.banks_client
+            .get_rent()
+            .await
+            .unwrap()
+            .minimum_balance(len)
+    }
+
+    // Creates an empty account of `space` bytes owned by `owner`
+    pub async fn create_account(&mut self, owner: &Pubkey, space: usize) -> Keypair {
+        let account = Keypair::new();
+        let lamports = self.minimum_balance(space).await;
+        let create_account_ix = system_instruction::create_account(
+            &self.payer.pubkey(),
+            &account.pubkey(),
+            lamports,
+            space as u64,
+            owner,
+        );
+        self.process(&[create_account_ix], &[&account])
+            .await
+            .unwrap();
+
+        account
+    }
+
+    pub async fn create_escrow_account(&mut self) -> Pubkey {
+        let program_id = self.program_id;
+        self.create_account(&program_id, Escrow::LEN).await.pubkey()
+    }
+
+    // Creates a new mint whose mint and freeze authority is the payer.
+    // A Token-2022 mint charges a transfer fee, of nothing, which is enough for Token-2022
+    // to refuse moving its tokens with anything but `TransferChecked`.
+    pub async fn create_mint(&mut self) -> Pubkey {
+        let mint = Keypair::new();
+        let extensions = if self.token_program == spl_token_2022::id() {
+            vec![ExtensionType::TransferFeeConfig]
+        } else {
+            vec![]
+        };
+        let space = ExtensionType::get_account_len::<Mint>(&extensions);
+        let lamports = self.minimum_balance(space).await;
+        let mut instructions = vec![system_instruction::create_account(
+            &self.payer.pubkey(),
+            &mint.pubkey(),
+            lamports,
+            space as u64,
+            &self.token_program,
+        )];
+        // Extensions are set up before the mint itself
+        if !extensions.is_empty() {
+            instructions.push(
+                initialize_transfer_fee_config(
+                    &self.token_program,
+                    &mint.pubkey(),
+                    None,
+                    None,
+                    0,
+                    0,
+                )
+                .unwrap(),
+            );
+        }
+        instructions.push(
+            spl_token_2022::instruction::initialize_mint(
+                &self.token_program,
+                &mint.pubkey(),
+                &self.payer.pubkey(),
+                Some(&self.payer.pubkey()),
+                0,
+            )
+            .unwrap(),
+        );
+        self.process(&instructions, &[&mint]).await.unwrap();
+
+        mint.pubkey()
+    }
+
+    pub async fn create_token_account(&mut self, mint: &Pubkey, owner: &Pubkey) -> Pubkey {
+        let account = Keypair::new();
+        self.create_token_account_at(&account, mint, owner).await;
+        account.pubkey()
+    }
+
+    // For tests that need to know the address before the bank starts.
+    // The account has room for whatever extensions the mint requires of it.
+    pub async fn create_token_account_at(
+        &mut self,
+        account: &Keypair,
+        mint: &Pubkey,
+        owner: &Pubkey,
+    ) {
+        let mint_account = self.get_account(mint).await.unwrap();
+        let space = token::account_len(&mint_account.data).unwrap();
+        let lamports = self.minimum_balance(space).await;
+        self.process(
+            &[
+                system_instruction::create_account(
+                    &self.payer.pubkey(),
+                    &account.pubkey(),
+                    lamports,
+                    space as u64,
+                    &self.token_program,
+                ),
+                token::initialize_account(&self.token_program, &account.pubkey(), mint, owner)
+                    .unwrap(),
+            ],
+            &[account],
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn mint_to(&mut self, mint: &Pubkey, account: &Pubkey, amount: u64) {
+        let mint_to_ix = spl_token_2022::instruction::mint_to(
+            &self.token_program,
+            mint,
+            account,
+            &self.payer.pubkey(),
+            &[],
+            amount,
+        )
+        .unwrap();
+        self.process(&[mint_to_ix], &[]).await.unwrap();
+    }
+
+    // Bob gets a funded Token Y account to send from and an empty Token X account to receive into
+    pub async fn create_taker_accounts(
+        &mut self,
+        taker: &Pubkey,
+        mint_x: &Pubkey,
+        mint_y: &Pubkey,
+        amount: u64,
+    ) -> (Pubkey, Pubkey) {
+        let takers_sending_token_account = self.create_token_account(mint_y, taker).await;
+        self.mint_to(mint_y, &takers_sending_token_account, amount)
+            .await;
+        let takers_token_to_receive_account = self.create_token_account(mint_x, taker).await;
+
+        (
+            takers_sending_token_account,
+            takers_token_to_receive_account,
+        )
+    }
+
+    pub async fn freeze_account(&mut self, mint: &Pubkey, account: &Pubkey) {
+        let freeze_account_ix = spl_token_2022::instruction::freeze_account(
+            &self.token_program,
+            account,
+            mint,
+            &self.payer.pubkey(),
+            &[],
+        )
+        .unwrap();
+        self.process(&[freeze_account_ix], &[]).await.unwrap();
+    }
+
+    pub async fn unix_timestamp(&mut self) -> i64 {
+        self.banks_client
+            .get_sysvar::<Clock>()
+            .await
+            .unwrap()
+            .unix_timestamp
+    }
+
+    pub async fn slot(&mut self) -> u64 {
+        self.banks_client.get_sysvar::<Clock>().await.unwrap().slot
+    }
+
+    pub async fn token_balance(&mut self, account: &Pubkey) -> u64 {
+        let account = self.get_account(account).await.unwrap();
+        token::unpack_account(&account.data).unwrap().amount
+    }
+
+    pub async fn transfer_lamports(&mut self, to: &Pubkey, lamports: u64) {
+        let transfer_ix = system_instruction::transfer(&self.payer.pubkey(), to, lamports);
+        self.process(&[transfer_ix], &[]).await.unwrap();
+    }
+
+    // Alice asks for `expected_amount` of the mint of her Token Y account, in exchange for
+    // all of her temp account. The escrow never expires, charges no fees and is open to anyone.
+    pub fn init_escrow_tx(
+        &self,
+        initializer: &Keypair,
+        temp_token_account: &Pubkey,
+        token_to_receive_account: &Pubkey,
+        token_to_receive_mint: &Pubkey,
+        escrow_account: &Pubkey,
+        expected_amount: u64,
+    ) -> Transaction {
+        let init_escrow_ix = EscrowInstruction::init_escrow(
+            &self.program_id,
+            &initializer.pubkey(),
+            temp_token_account,
+            token_to_receive_account,
+            escrow_account,
+            &self.token_program,
+            token_to_receive_mint,
+            InitEscrowParams {
+                amount: expected_amount,
+                expire_at: i64::MAX,
+                ..InitEscrowParams::default()
+            },
+            None,
+        );
+        let mut transaction =
+            Transaction::new_with_payer(&[init_escrow_ix], Some(&self.payer.pubkey()));
+        transaction.sign(&[&self.payer, initializer], self.recent_blockhash);
+
+        transaction
+    }
+
+    // Alice locks `offered_amount` Token X asking for `expected_amount` Token Y,
+    // and Bob gets exactly that much Token Y to take it with
+    pub async fn escrow_scenario(
+        &mut self,
+        offered_amount: u64,
+        expected_amount: u64,
+    ) -> EscrowScenario {
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let mint_x = self.create_mint().await;
+        let mint_y = self.create_mint().await;
+
+        let temp_token_account = self.create_token_account(&mint_x, &alice.pubkey()).await;
+        self.mint_to(&mint_x, &temp_token_account, offered_amount)
+            .await;
+        let initializer_token_to_receive_account =
+            self.create_token_account(&mint_y, &alice.pubkey()).await;
+        let escrow = self.create_escrow_account().await;
+        let (pda, _bump_seed) =
+            Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &self.program_id);
+
+        let init_escrow_tx = self.init_escrow_tx(
+            &alice,
+            &temp_token_account,
+            &initializer_token_to_receive_account,
+            &mint_y,
+            &escrow,
+            expected_amount,
+        );
+        self.banks_client
+            .process_transaction(init_escrow_tx)
+            .await
+            .unwrap();
+
+        let (takers_sending_token_account, takers_token_to_receive_account) = self
+            .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, expected_amount)
+            .await;
+
+        EscrowScenario {
+            alice,
+            bob,
+            mint_x,
+            mint_y,
+            escrow,
+            pda,
+            temp_token_account,
+            initializer_token_to_receive_account,
+            takers_sending_token_account,
+            takers_token_to_receive_account,
+        }
+    }
+}
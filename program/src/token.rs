@@ -0,0 +1,191 @@
+// The escrow works with both the legacy SPL Token program and Token-2022.
+// `spl_token_2022`'s builders take either program id, and transfers always go through
+// `TransferChecked`, which Token-2022 requires once a mint has transfer fees or hooks.
+
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack},
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+pub use spl_token_2022::instruction::{
+    approve, close_account, initialize_account, revoke, set_authority, transfer_checked,
+    AuthorityType,
+};
+use spl_token_2022::{
+    extension::{ExtensionType, StateWithExtensions},
+    state::{Account as TokenAccount, Mint},
+};
+
+/// The Associated Token Account program
+pub mod associated_token {
+    solana_program::declare_id!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+}
+
+/// Whether `program_id` is one of the token programs the escrow accepts
+pub fn is_token_program(program_id: &Pubkey) -> bool {
+    spl_token_2022::check_spl_token_program_account(program_id).is_ok()
+}
+
+/// Unpacks the base state of a token account, ignoring any Token-2022 extensions after it
+pub fn unpack_account(data: &[u8]) -> Result<TokenAccount, ProgramError> {
+    let base = data
+        .get(..TokenAccount::LEN)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    TokenAccount::unpack(base)
+}
+
+/// Whether `data` holds an initialized token account, rather than zeroed or closed space
+pub fn is_initialized_account(data: &[u8]) -> bool {
+    data.get(..TokenAccount::LEN)
+        .and_then(|base| TokenAccount::unpack_unchecked(base).ok())
+        .map_or(false, |account| account.is_initialized())
+}
+
+/// Unpacks the base state of a mint, ignoring any Token-2022 extensions after it
+pub fn unpack_mint(data: &[u8]) -> Result<Mint, ProgramError> {
+    let base = data
+        .get(..Mint::LEN)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    Mint::unpack(base)
+}
+
+/// The space a token account of the mint in `mint_data` needs, with room for
+/// the extensions the mint's own extensions require of its accounts
+pub fn account_len(mint_data: &[u8]) -> Result<usize, ProgramError> {
+    let mint_extensions = StateWithExtensions::<Mint>::unpack(mint_data)?.get_extension_types()?;
+    let account_extensions = ExtensionType::get_required_init_account_extensions(&mint_extensions);
+    Ok(ExtensionType::get_account_len::<TokenAccount>(
+        &account_extensions,
+    ))
+}
+
+/// The address of the associated token account of `wallet` for `mint`
+pub fn associated_token_address(
+    wallet_pubkey: &Pubkey,
+    mint_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            wallet_pubkey.as_ref(),
+            token_program_id.as_ref(),
+            mint_pubkey.as_ref(),
+        ],
+        &associated_token::id(),
+    )
+    .0
+}
+
+/// Same as `spl_associated_token_account::create_associated_token_account`,
+/// without pulling in the whole crate for a single instruction
+pub fn create_associated_token_account(
+    funding_pubkey: &Pubkey,
+    wallet_pubkey: &Pubkey,
+    mint_pubkey: &Pubkey,
+    token_program_id: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: associated_token::id(),
+        accounts: vec![
+            AccountMeta::new(*funding_pubkey, true),
+            AccountMeta::new(
+                associated_token_address(wallet_pubkey, mint_pubkey, token_program_id),
+                false,
+            ),
+            AccountMeta::new_readonly(*wallet_pubkey, false),
+            AccountMeta::new_readonly(*mint_pubkey, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(*token_program_id, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_token_program() {
+        assert!(is_token_program(&spl_token::id()));
+        assert!(is_token_program(&spl_token_2022::id()));
+        assert!(!is_token_program(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn test_unpack_account_with_extensions() {
+        let account = TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+            amount: 10,
+            state: spl_token_2022::state::AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        // Token-2022 puts the account type and extensions after the base state
+        let mut data = vec![0xAB; TokenAccount::LEN + 64];
+        account.pack_into_slice(&mut data);
+
+        assert_eq!(unpack_account(&data).unwrap(), account);
+        assert!(unpack_account(&data[..TokenAccount::LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn test_is_initialized_account() {
+        let mut data = vec![0; TokenAccount::LEN];
+        assert!(!is_initialized_account(&data));
+        assert!(!is_initialized_account(&[]));
+
+        let account = TokenAccount {
+            state: spl_token_2022::state::AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        account.pack_into_slice(&mut data);
+        assert!(is_initialized_account(&data));
+    }
+
+    #[test]
+    fn test_account_len() {
+        let mut mint_data = vec![0; Mint::LEN];
+        Mint {
+            is_initialized: true,
+            ..Mint::default()
+        }
+        .pack_into_slice(&mut mint_data);
+        assert_eq!(account_len(&mint_data).unwrap(), TokenAccount::LEN);
+        assert!(account_len(&[0; Mint::LEN]).is_err());
+    }
+
+    #[test]
+    fn test_transfer_checked_for_either_program() {
+        for token_program_id in [spl_token::id(), spl_token_2022::id()] {
+            let instruction = transfer_checked(
+                &token_program_id,
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &[],
+                10,
+                6,
+            )
+            .unwrap();
+            assert_eq!(instruction.program_id, token_program_id);
+        }
+        assert_eq!(
+            transfer_checked(
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &[],
+                10,
+                6,
+            ),
+            Err(ProgramError::IncorrectProgramId)
+        );
+    }
+}
@@ -0,0 +1,7296 @@
+use {
+    borsh::BorshDeserialize,
+    solana_escrow_program::{
+        config,
+        error::EscrowError,
+        events::EscrowEvent,
+        instructions::{EscrowInstruction, ExchangeAccounts, InitEscrowParams},
+        memo,
+        state::{Escrow, EscrowMode, EscrowStatus, ProgramConfig},
+        token,
+    },
+    solana_program::{
+        account_info::AccountInfo,
+        bpf_loader_upgradeable,
+        entrypoint::ProgramResult,
+        instruction::{AccountMeta, Instruction},
+        program_option::COption,
+        program_pack::Pack,
+        program_stubs::{set_syscall_stubs, SyscallStubs},
+        pubkey::Pubkey,
+        rent::Rent,
+        system_instruction, system_program, sysvar,
+    },
+    solana_program_test::{processor, ProgramTest},
+    solana_sdk::{
+        account::Account,
+        instruction::InstructionError,
+        signature::{Keypair, Signer},
+        transaction::TransactionError,
+        transport::TransportError,
+    },
+    spl_token::state::{Account as TokenAccount, AccountState, Mint},
+    spl_token_2022::{
+        extension::{ExtensionType, StateWithExtensions},
+        state::Mint as Token2022Mint,
+    },
+    std::sync::{Arc, Mutex},
+};
+
+#[path = "../src/test_utils.rs"]
+mod test_utils;
+
+use test_utils::{add_token_2022, instruction_error, program_test, TestEnv};
+
+// What Alice puts up for trade when calling `TestEnv::init_escrow`
+#[derive(Clone, Copy)]
+struct EscrowTerms {
+    offered_amount: u64,
+    expected_amount: u64,
+    expire_at: i64,
+    fee_lamports: u64,
+    fee_account: Pubkey,
+    not_before: i64,
+    accepted_mints: [Pubkey; 3],
+    accept_native: bool,
+    taker_fee_bps: u16,
+    fee_token_account: Pubkey,
+    reference: [u8; 32],
+    dust_threshold: u64,
+    allowed_taker: Pubkey,
+    grace_seconds: i64,
+    rent_payer: Option<Pubkey>,
+}
+
+impl EscrowTerms {
+    // Never expires, charges no fees, can be cancelled right away, is open to any taker
+    // and only takes the mint of Alice's Token Y account. Alice gets the rent back
+    // and keepers may reap the escrow as soon as it expires.
+    fn new(offered_amount: u64, expected_amount: u64) -> Self {
+        Self {
+            offered_amount,
+            expected_amount,
+            expire_at: i64::MAX,
+            fee_lamports: 0,
+            fee_account: Pubkey::default(),
+            not_before: 0,
+            accepted_mints: [Pubkey::default(); 3],
+            accept_native: false,
+            taker_fee_bps: 0,
+            fee_token_account: Pubkey::default(),
+            reference: [0; 32],
+            dust_threshold: 0,
+            allowed_taker: Pubkey::default(),
+            grace_seconds: 0,
+            rent_payer: None,
+        }
+    }
+
+    // The terms as every `InitEscrow*` instruction carries them
+    fn params(&self) -> InitEscrowParams {
+        InitEscrowParams {
+            amount: self.expected_amount,
+            expire_at: self.expire_at,
+            fee_lamports: self.fee_lamports,
+            fee_account: self.fee_account,
+            not_before: self.not_before,
+            accepted_mints: self.accepted_mints,
+            accept_native: self.accept_native,
+            taker_fee_bps: self.taker_fee_bps,
+            fee_token_account: self.fee_token_account,
+            reference: self.reference,
+            dust_threshold: self.dust_threshold,
+            allowed_taker: self.allowed_taker,
+            grace_seconds: self.grace_seconds,
+        }
+    }
+}
+
+// Accounts of a single escrow created by `TestEnv::init_escrow`
+struct EscrowAccounts {
+    escrow: Pubkey,
+    temp_token_account: Pubkey,
+    initializer_token_to_receive_account: Pubkey,
+    initializer_token_to_receive_mint: Pubkey,
+    // The mint of the temp token account, Token X
+    temp_token_mint: Pubkey,
+    pda: Pubkey,
+    fee_account: Option<Pubkey>,
+    fee_token_account: Option<Pubkey>,
+    // Alice's own Token X account, for a custodial escrow to sweep its dust back into
+    initializer_token_x_account: Option<Pubkey>,
+    rent_payer: Option<Pubkey>,
+    token_program: Pubkey,
+}
+
+impl EscrowAccounts {
+    // Everything a taker passes to take the escrow, as far as it is known once the escrow is made
+    fn exchange_accounts(
+        &self,
+        taker: &Pubkey,
+        takers_sending_token_account: &Pubkey,
+        takers_token_to_receive_account: &Pubkey,
+        initializer: &Pubkey,
+    ) -> ExchangeAccounts {
+        ExchangeAccounts {
+            taker: *taker,
+            takers_sending_token_account: *takers_sending_token_account,
+            takers_token_to_receive_account: *takers_token_to_receive_account,
+            pdas_temp_token_account: self.temp_token_account,
+            initializers_main_account: *initializer,
+            initializers_token_to_receive_account: self.initializer_token_to_receive_account,
+            initializers_token_to_receive_mint: self.initializer_token_to_receive_mint,
+            escrow_account: self.escrow,
+            token_program: self.token_program,
+            pda: self.pda,
+            pdas_temp_token_mint: self.temp_token_mint,
+            create_initializers_token_to_receive_account: false,
+            fee_token_account: self.fee_token_account,
+            basket_accounts: vec![],
+            initializers_token_x_account: self.initializer_token_x_account,
+            fee_account: self.fee_account,
+            rent_payer: self.rent_payer,
+        }
+    }
+}
+
+// Plants the program's data account as `UpgradeableLoaderState::ProgramData` lays it out,
+// deployed at slot 0 with `upgrade_authority`. `processor!` programs don't have one of their own.
+fn add_program_data(
+    program_test: &mut ProgramTest,
+    program_id: &Pubkey,
+    upgrade_authority: &Pubkey,
+) -> Pubkey {
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    let mut program_data_bytes = vec![3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    program_data_bytes.extend_from_slice(upgrade_authority.as_ref());
+    program_test.add_account(
+        program_data,
+        Account {
+            lamports: 1_000_000_000,
+            data: program_data_bytes,
+            owner: bpf_loader_upgradeable::id(),
+            ..Account::default()
+        },
+    );
+    program_data
+}
+
+// Plants an account holding a packed state, which `ProgramTest` itself only learns to do in later releases
+trait AddPackableAccount {
+    fn add_packable_account<T: Pack>(
+        &mut self,
+        pubkey: Pubkey,
+        lamports: u64,
+        data: &T,
+        owner: &Pubkey,
+    );
+}
+
+impl AddPackableAccount for ProgramTest {
+    fn add_packable_account<T: Pack>(
+        &mut self,
+        pubkey: Pubkey,
+        lamports: u64,
+        data: &T,
+        owner: &Pubkey,
+    ) {
+        let mut account_data = vec![0; T::get_packed_len()];
+        data.pack_into_slice(&mut account_data);
+        self.add_account(
+            pubkey,
+            Account {
+                lamports,
+                data: account_data,
+                owner: *owner,
+                ..Account::default()
+            },
+        );
+    }
+}
+
+// Helpers for the escrows these tests make on their own terms
+impl TestEnv {
+    // Alice locks `offered_amount` of token X and asks for `expected_amount` of token Y
+    async fn init_escrow(
+        &mut self,
+        initializer: &Keypair,
+        mint_x: &Pubkey,
+        mint_y: &Pubkey,
+        terms: EscrowTerms,
+    ) -> EscrowAccounts {
+        let temp_token_account = self
+            .create_token_account(mint_x, &initializer.pubkey())
+            .await;
+        self.mint_to(mint_x, &temp_token_account, terms.offered_amount)
+            .await;
+        // Lamports are paid straight into Alice's main account
+        let initializer_token_to_receive_account = if terms.accept_native {
+            initializer.pubkey()
+        } else {
+            self.create_token_account(mint_y, &initializer.pubkey())
+                .await
+        };
+
+        // Dust left in the escrow goes back here
+        let initializer_token_x_account = if terms.dust_threshold > 0 {
+            Some(
+                self.create_token_account(mint_x, &initializer.pubkey())
+                    .await,
+            )
+        } else {
+            None
+        };
+
+        let escrow = self.create_escrow_account().await;
+        let init_escrow_ix = init_escrow_instruction(
+            &self.program_id,
+            &initializer.pubkey(),
+            &temp_token_account,
+            &initializer_token_to_receive_account,
+            mint_y,
+            &escrow,
+            &self.token_program,
+            &terms,
+        );
+        self.process(&[init_escrow_ix], &[initializer])
+            .await
+            .unwrap();
+
+        let (pda, _bump_seed) =
+            Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &self.program_id);
+
+        EscrowAccounts {
+            escrow,
+            temp_token_account,
+            initializer_token_to_receive_account,
+            initializer_token_to_receive_mint: *mint_y,
+            temp_token_mint: *mint_x,
+            pda,
+            fee_account: Some(terms.fee_account).filter(|_| terms.fee_lamports > 0),
+            fee_token_account: Some(terms.fee_token_account).filter(|_| terms.taker_fee_bps > 0),
+            initializer_token_x_account,
+            rent_payer: terms.rent_payer,
+            token_program: self.token_program,
+        }
+    }
+
+    // Alice keeps `balance` of token X in her own account and approves the PDA for `offered_amount` of it
+    async fn init_escrow_delegated(
+        &mut self,
+        initializer: &Keypair,
+        mint_x: &Pubkey,
+        mint_y: &Pubkey,
+        terms: EscrowTerms,
+        balance: u64,
+    ) -> EscrowAccounts {
+        let initializers_token_x_account = self
+            .create_token_account(mint_x, &initializer.pubkey())
+            .await;
+        self.mint_to(mint_x, &initializers_token_x_account, balance)
+            .await;
+        let initializer_token_to_receive_account = self
+            .create_token_account(mint_y, &initializer.pubkey())
+            .await;
+
+        let escrow = self.create_escrow_account().await;
+        let init_escrow_ix = init_escrow_delegated_instruction(
+            &self.program_id,
+            &initializer.pubkey(),
+            &initializers_token_x_account,
+            &initializer_token_to_receive_account,
+            mint_y,
+            &escrow,
+            &self.token_program,
+            &terms,
+        );
+        self.process(&[init_escrow_ix], &[initializer])
+            .await
+            .unwrap();
+
+        let (pda, _bump_seed) =
+            Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &self.program_id);
+
+        EscrowAccounts {
+            escrow,
+            temp_token_account: initializers_token_x_account,
+            initializer_token_to_receive_account,
+            initializer_token_to_receive_mint: *mint_y,
+            temp_token_mint: *mint_x,
+            pda,
+            fee_account: Some(terms.fee_account).filter(|_| terms.fee_lamports > 0),
+            fee_token_account: Some(terms.fee_token_account).filter(|_| terms.taker_fee_bps > 0),
+            // Any dust stays in Alice's own account, which is the one the PDA draws from
+            initializer_token_x_account: None,
+            rent_payer: terms.rent_payer,
+            token_program: self.token_program,
+        }
+    }
+
+    // Alice offers `offered_amount` lamports, held by the PDA, and asks for `expected_amount` of token Y
+    async fn init_escrow_native(
+        &mut self,
+        initializer: &Keypair,
+        mint_y: &Pubkey,
+        terms: EscrowTerms,
+    ) -> EscrowAccounts {
+        let initializer_token_to_receive_account = self
+            .create_token_account(mint_y, &initializer.pubkey())
+            .await;
+        let escrow = self.create_escrow_account().await;
+        let (pda, _bump_seed) =
+            Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &self.program_id);
+
+        let init_escrow_ix = init_escrow_native_instruction(
+            &self.program_id,
+            &initializer.pubkey(),
+            &pda,
+            &initializer_token_to_receive_account,
+            mint_y,
+            &escrow,
+            &self.token_program,
+            &terms,
+        );
+        self.process(&[init_escrow_ix], &[initializer])
+            .await
+            .unwrap();
+
+        EscrowAccounts {
+            escrow,
+            // The PDA is where the offer waits
+            temp_token_account: pda,
+            initializer_token_to_receive_account,
+            initializer_token_to_receive_mint: *mint_y,
+            // Lamports have no mint
+            temp_token_mint: Pubkey::default(),
+            pda,
+            fee_account: Some(terms.fee_account).filter(|_| terms.fee_lamports > 0),
+            fee_token_account: None,
+            initializer_token_x_account: None,
+            rent_payer: terms.rent_payer,
+            token_program: self.token_program,
+        }
+    }
+
+    // What is left behind when the temp account reaches the PDA but the escrow state is never written
+    async fn create_orphaned_token_account(
+        &mut self,
+        initializer: &Keypair,
+        mint: &Pubkey,
+        escrow_account: &Pubkey,
+        amount: u64,
+    ) -> Pubkey {
+        let orphaned_token_account = self.create_token_account(mint, &initializer.pubkey()).await;
+        self.mint_to(mint, &orphaned_token_account, amount).await;
+
+        let (pda, _bump_seed) = Pubkey::find_program_address(
+            &[config::ESCROW_SEED, escrow_account.as_ref()],
+            &self.program_id,
+        );
+        let owner_change_ix = token::set_authority(
+            &self.token_program,
+            &orphaned_token_account,
+            Some(&pda),
+            token::AuthorityType::AccountOwner,
+            &initializer.pubkey(),
+            &[],
+        )
+        .unwrap();
+        self.process(&[owner_change_ix], &[initializer])
+            .await
+            .unwrap();
+
+        orphaned_token_account
+    }
+}
+
+// The syscall stubs are global, so tests capturing log data take turns
+static CAPTURING_LOGS: Mutex<()> = Mutex::new(());
+
+// Runs `instructions` like `TestEnv::process`, also returning what the program logged
+// through `sol_log_data`. The lock only keeps other tests from swapping the stubs out meanwhile.
+#[allow(clippy::await_holding_lock)]
+async fn process_capturing_log_data(
+    env: &mut TestEnv,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> (Result<(), TransportError>, Vec<Vec<u8>>) {
+    let _turn = CAPTURING_LOGS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let log_data = Arc::new(Mutex::new(vec![]));
+    let bank_stubs: &'static dyn SyscallStubs =
+        Box::leak(set_syscall_stubs(Box::new(DefaultStubs)));
+    set_syscall_stubs(Box::new(ForwardedStubs {
+        stubs: bank_stubs,
+        log_data: Some(log_data.clone()),
+    }));
+    let result = env.process(instructions, signers).await;
+    set_syscall_stubs(Box::new(ForwardedStubs {
+        stubs: bank_stubs,
+        log_data: None,
+    }));
+
+    let mut captured = vec![];
+    captured.append(&mut log_data.lock().unwrap());
+    (result, captured)
+}
+
+// Stands in for the bank's stubs only while they are being taken out
+struct DefaultStubs;
+
+impl SyscallStubs for DefaultStubs {}
+
+// Hands every syscall on to the stubs of the `ProgramTest` bank,
+// keeping a copy of what is logged with `sol_log_data` when asked to
+struct ForwardedStubs {
+    stubs: &'static dyn SyscallStubs,
+    log_data: Option<Arc<Mutex<Vec<Vec<u8>>>>>,
+}
+
+impl SyscallStubs for ForwardedStubs {
+    fn sol_log(&self, message: &str) {
+        self.stubs.sol_log(message)
+    }
+    fn sol_log_compute_units(&self) {
+        self.stubs.sol_log_compute_units()
+    }
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        self.stubs
+            .sol_invoke_signed(instruction, account_infos, signers_seeds)
+    }
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.stubs.sol_get_clock_sysvar(var_addr)
+    }
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.stubs.sol_get_epoch_schedule_sysvar(var_addr)
+    }
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.stubs.sol_get_fees_sysvar(var_addr)
+    }
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.stubs.sol_get_rent_sysvar(var_addr)
+    }
+    fn sol_log_data(&self, data: &[&[u8]]) {
+        if let Some(log_data) = &self.log_data {
+            log_data.lock().unwrap().push(data.concat());
+        }
+        self.stubs.sol_log_data(data)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn init_escrow_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    temp_token_account: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    token_to_receive_mint: &Pubkey,
+    escrow_account: &Pubkey,
+    token_program: &Pubkey,
+    terms: &EscrowTerms,
+) -> Instruction {
+    EscrowInstruction::init_escrow(
+        program_id,
+        initializer,
+        temp_token_account,
+        token_to_receive_account,
+        escrow_account,
+        token_program,
+        token_to_receive_mint,
+        terms.params(),
+        terms.rent_payer.as_ref(),
+    )
+}
+
+// Same accounts as `InitEscrow`, with Alice's own Token X account in place of the temp one
+#[allow(clippy::too_many_arguments)]
+fn init_escrow_delegated_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_x_account: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    token_to_receive_mint: &Pubkey,
+    escrow_account: &Pubkey,
+    token_program: &Pubkey,
+    terms: &EscrowTerms,
+) -> Instruction {
+    let mut instruction = init_escrow_instruction(
+        program_id,
+        initializer,
+        initializers_token_x_account,
+        token_to_receive_account,
+        token_to_receive_mint,
+        escrow_account,
+        token_program,
+        terms,
+    );
+    instruction.data = EscrowInstruction::InitEscrowDelegated {
+        params: terms.params(),
+        offered_amount: terms.offered_amount,
+    }
+    .pack();
+    // The token program needs the delegate's account, right after the mint
+    let (pda, _bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow_account.as_ref()], program_id);
+    let pda_index = if terms.accept_native { 5 } else { 6 };
+    instruction
+        .accounts
+        .insert(pda_index, AccountMeta::new_readonly(pda, false));
+    instruction
+}
+
+fn exchange_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    takers_token_to_receive_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    amount: u64,
+) -> Instruction {
+    EscrowInstruction::exchange(
+        program_id,
+        &escrow_accounts.exchange_accounts(
+            taker,
+            takers_sending_token_account,
+            takers_token_to_receive_account,
+            initializer,
+        ),
+        amount,
+        // A full exchange takes the whole escrow, so accept nothing less
+        amount,
+    )
+}
+
+fn partial_exchange_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    takers_token_to_receive_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    amount: u64,
+) -> Instruction {
+    // Same accounts as a full exchange, only the data differs
+    let mut instruction = exchange_instruction(
+        program_id,
+        taker,
+        takers_sending_token_account,
+        takers_token_to_receive_account,
+        initializer,
+        escrow_accounts,
+        amount,
+    );
+    instruction.data = EscrowInstruction::PartialExchange { amount }.pack();
+    instruction
+}
+
+fn update_expected_amount_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_account: &Pubkey,
+    new_amount: u64,
+) -> Instruction {
+    let mut data = vec![4];
+    data.extend_from_slice(&new_amount.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+        ],
+        data,
+    }
+}
+
+fn extend_expiry_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_account: &Pubkey,
+    new_expire_at: i64,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+        ],
+        data: EscrowInstruction::ExtendExpiry { new_expire_at }.pack(),
+    }
+}
+
+fn cancel_escrow_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_x_account: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+) -> Instruction {
+    let mut instruction = Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*initializer, true),
+            AccountMeta::new(escrow_accounts.temp_token_account, false),
+            AccountMeta::new(*initializers_token_x_account, false),
+            AccountMeta::new(escrow_accounts.escrow, false),
+            AccountMeta::new_readonly(escrow_accounts.token_program, false),
+            AccountMeta::new_readonly(escrow_accounts.pda, false),
+        ],
+        data: EscrowInstruction::CancelEscrow.pack(),
+    };
+    // Lamports have no mint to name
+    if escrow_accounts.temp_token_mint != Pubkey::default() {
+        instruction.accounts.push(AccountMeta::new_readonly(
+            escrow_accounts.temp_token_mint,
+            false,
+        ));
+    }
+    if let Some(rent_payer) = escrow_accounts.rent_payer {
+        instruction
+            .accounts
+            .push(AccountMeta::new(rent_payer, false));
+    }
+    instruction
+}
+
+// The whole trade from start to end: Alice locks 10 Token X asking 20 Token Y, Bob takes it
+#[tokio::test]
+async fn test_init_escrow_and_exchange() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+
+    // The temp account is the PDA's now, holding everything Alice offered
+    let temp_token_account = env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .unwrap();
+    let temp_token_account = TokenAccount::unpack(&temp_token_account.data).unwrap();
+    assert_eq!(temp_token_account.owner, escrow_accounts.pda);
+    assert_eq!(temp_token_account.amount, 10);
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.initializer_pubkey, alice.pubkey());
+    assert_eq!(escrow.expected_amount, 20);
+    assert_eq!(escrow.remaining_amount, 10);
+    // The amount Alice locked is kept for auditing, exactly what was minted into the temp account
+    assert_eq!(escrow.offered_amount, temp_token_account.amount);
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 0);
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+
+    // Alice gets back the rent of both accounts she paid for
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, token_account_rent + escrow_rent);
+}
+
+#[tokio::test]
+async fn test_exchange_logs_transferred_amounts() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let (result, log_data) = process_capturing_log_data(&mut env, &[exchange_ix], &[&bob]).await;
+    result.unwrap();
+
+    // Decode the event the way an indexer would, skipping whatever else was logged
+    let transferred = log_data
+        .iter()
+        .filter_map(|data| EscrowEvent::try_from_slice(data).ok())
+        .find(|event| {
+            matches!(event, EscrowEvent::AmountsTransferred { escrow, .. } if *escrow == escrow_accounts.escrow)
+        });
+    assert_eq!(
+        transferred,
+        Some(EscrowEvent::AmountsTransferred {
+            escrow: escrow_accounts.escrow,
+            token_x_amount: 10,
+            token_y_amount: 20,
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_get_rent_matches_runtime() {
+    let mut env = TestEnv::new().await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+
+    for (config_flags, expected) in [
+        (0, escrow_rent),
+        (
+            EscrowInstruction::RENT_TEMP_TOKEN_ACCOUNT,
+            escrow_rent + token_account_rent,
+        ),
+        // A full basket of two on top of the temp token account
+        (
+            EscrowInstruction::RENT_TEMP_TOKEN_ACCOUNT | 2 << 1,
+            escrow_rent + 3 * token_account_rent,
+        ),
+    ] {
+        let get_rent_ix = Instruction::new_with_bytes(
+            env.program_id,
+            &EscrowInstruction::GetRent { config_flags }.pack(),
+            vec![],
+        );
+        let (result, log_data) = process_capturing_log_data(&mut env, &[get_rent_ix], &[]).await;
+        result.unwrap();
+        assert_eq!(log_data, vec![expected.to_le_bytes().to_vec()]);
+    }
+
+    // Unknown flags, an oversized basket, and a basket with no temp token account
+    for config_flags in [0b1000, EscrowInstruction::RENT_BASKET_LEN_MASK | 1, 1 << 1] {
+        let get_rent_ix = Instruction::new_with_bytes(
+            env.program_id,
+            &EscrowInstruction::GetRent { config_flags }.pack(),
+            vec![],
+        );
+        let error = env.process(&[get_rent_ix], &[]).await.unwrap_err();
+        assert_eq!(
+            instruction_error(error),
+            InstructionError::Custom(EscrowError::InvalidInstruction as u32)
+        );
+    }
+}
+
+fn reclaim_orphan_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    orphaned_token_account: &Pubkey,
+    mint: &Pubkey,
+    initializers_token_x_account: &Pubkey,
+    escrow_account: &Pubkey,
+    escrow_account_signs: bool,
+) -> Instruction {
+    let (pda, _bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow_account.as_ref()], program_id);
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*initializer, true),
+            AccountMeta::new(*orphaned_token_account, false),
+            AccountMeta::new(*initializers_token_x_account, false),
+            AccountMeta::new_readonly(*escrow_account, escrow_account_signs),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(pda, false),
+            AccountMeta::new_readonly(*mint, false),
+        ],
+        data: EscrowInstruction::ReclaimOrphan.pack(),
+    }
+}
+
+#[tokio::test]
+async fn test_multiple_escrows_coexist() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let first = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let second = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(30, 40))
+        .await;
+
+    // Every escrow hands its temp account to its own PDA
+    assert_ne!(first.pda, second.pda);
+    for escrow_accounts in [&first, &second] {
+        let temp_token_account = env
+            .get_account(&escrow_accounts.temp_token_account)
+            .await
+            .unwrap();
+        let temp_token_account = TokenAccount::unpack(&temp_token_account.data).unwrap();
+        assert_eq!(temp_token_account.owner, escrow_accounts.pda);
+
+        let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+        let escrow = Escrow::unpack(&escrow.data).unwrap();
+        let (_pda, bump_seed) = Pubkey::find_program_address(
+            &[config::ESCROW_SEED, escrow_accounts.escrow.as_ref()],
+            &env.program_id,
+        );
+        assert_eq!(escrow.bump_seed, bump_seed);
+    }
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &first,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&first.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&first.escrow).await.is_none());
+    assert!(env.get_account(&first.temp_token_account).await.is_none());
+
+    // The other escrow is left untouched
+    assert_eq!(env.token_balance(&second.temp_token_account).await, 30);
+    let escrow = env.get_account(&second.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.temp_token_account_pubkey, second.temp_token_account);
+    assert_eq!(escrow.expected_amount, 40);
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_temp_account_not_owned_by_token_program() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_y = env.create_mint().await;
+
+    // Looks like a token account, but belongs to some other program
+    let fake_temp_token_account = env
+        .create_account(&Pubkey::new_unique(), TokenAccount::LEN)
+        .await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &fake_temp_token_account.pubkey(),
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::IncorrectProgramId
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_temp_account_owned_by_someone_else() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Alice tries to put Carol's tokens in escrow
+    let temp_token_account = env.create_token_account(&mint_x, &carol.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidOwner as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_before_expiry() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let expire_at = env.unix_timestamp().await + 3600;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_after_expiry() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let expire_at = env.unix_timestamp().await - 1;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::EscrowExpired as u32)
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+// Alice gives Bob more time on an escrow that has already expired
+#[tokio::test]
+async fn test_extend_expiry() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let expire_at = env.unix_timestamp().await - 1;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Nobody but Alice can extend it
+    let extend_ix = extend_expiry_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &escrow_accounts.escrow,
+        i64::MAX,
+    );
+    let error = env.process(&[extend_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InitializerMismatch as u32)
+    );
+
+    // and only ever to a later deadline
+    for new_expire_at in [expire_at - 1, expire_at] {
+        let extend_ix = extend_expiry_instruction(
+            &env.program_id,
+            &alice.pubkey(),
+            &escrow_accounts.escrow,
+            new_expire_at,
+        );
+        let error = env.process(&[extend_ix], &[&alice]).await.unwrap_err();
+        assert_eq!(
+            instruction_error(error),
+            InstructionError::Custom(EscrowError::InvalidAmount as u32)
+        );
+    }
+
+    let new_expire_at = env.unix_timestamp().await + 3600;
+    let extend_ix = extend_expiry_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts.escrow,
+        new_expire_at,
+    );
+    env.process(&[extend_ix], &[&alice]).await.unwrap();
+
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.expire_at, new_expire_at);
+
+    // The exchange that would have expired goes through now
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_wrong_sending_mint() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let mint_z = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    // Bob tries to pay with token Z instead of token Y
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_z, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::MintMismatch as u32)
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_wrong_receiving_mint() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let mint_z = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    // Bob wants to receive token Z although the escrow holds token X
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_z, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::MintMismatch as u32)
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_aliased_token_accounts() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, _) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // The temp account holds Token X, so it passes for Bob's Token X account on every other check
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &escrow_accounts.temp_token_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_some());
+}
+
+#[tokio::test]
+async fn test_partial_exchanges_fill_the_escrow() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Bob takes 4 of 10 Token X, paying 8 of 20 Token Y
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        4,
+    );
+    env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(env.token_balance(&takers_token_to_receive_account).await, 4);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        8
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        6
+    );
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.remaining_amount, 6);
+    assert_eq!(escrow.expected_amount, 12);
+    // What Alice locked stays on record
+    assert_eq!(escrow.offered_amount, 10);
+
+    // Then he takes the remaining 6 Token X for 12 Token Y, which settles the escrow
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        6,
+    );
+    env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 0);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+}
+
+// The fill settling the escrow closes the temp account, whose rent goes to Alice alone
+#[tokio::test]
+async fn test_last_partial_exchange_refunds_temp_account_rent() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    // Paying for the escrow account keeps its rent apart from the temp account's
+    let sponsor = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                rent_payer: Some(sponsor.pubkey()),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let temp_token_account = env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .unwrap();
+
+    for amount in [4, 6] {
+        let partial_exchange_ix = partial_exchange_instruction(
+            &env.program_id,
+            &bob.pubkey(),
+            &takers_sending_token_account,
+            &takers_token_to_receive_account,
+            &alice.pubkey(),
+            &escrow_accounts,
+            amount,
+        );
+        env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+    }
+
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, temp_token_account.lamports);
+    // Bob got all of the Token X the closed account held
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_partial_exchange_rejects_inexact_share() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // 1 of 3 Token X would be worth 10/3 Token Y
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(3, 10))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 10)
+        .await;
+
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        1,
+    );
+    let error = env
+        .process(&[partial_exchange_ix], &[&bob])
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::ExpectedAmountMismatch as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_pays_protocol_fee() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let marketplace = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let fee_lamports = 5_000;
+    env.transfer_lamports(&marketplace.pubkey(), 1_000_000_000)
+        .await;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                fee_lamports,
+                fee_account: marketplace.pubkey(),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    // Alice funds the fee on top of the escrow's rent
+    env.transfer_lamports(&escrow_accounts.escrow, fee_lamports)
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    let marketplace_lamports = env
+        .get_account(&marketplace.pubkey())
+        .await
+        .unwrap()
+        .lamports;
+    assert_eq!(marketplace_lamports, 1_000_000_000 + fee_lamports);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_undersized_escrow_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let program_id = env.program_id;
+    let escrow = env.create_account(&program_id, Escrow::LEN - 1).await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow.pubkey(),
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::AccountDataTooSmall as u32)
+    );
+}
+
+// The client created the escrow account but never assigned it to the program
+#[tokio::test]
+async fn test_init_escrow_rejects_system_owned_escrow_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_account(&system_program::id(), Escrow::LEN).await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow.pubkey(),
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::IncorrectProgramId
+    );
+    // Alice still holds her temp account
+    let temp_token_account = env.get_account(&temp_token_account).await.unwrap();
+    let temp_token_account = TokenAccount::unpack(&temp_token_account.data).unwrap();
+    assert_eq!(temp_token_account.owner, alice.pubkey());
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_rent_exempt_undersized_escrow_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+
+    // Allocated for an older, shorter layout but funded for the current one,
+    // so only the size check can catch it
+    let escrow = Keypair::new();
+    let lamports = env.minimum_balance(Escrow::LEN).await;
+    let create_account_ix = system_instruction::create_account(
+        &env.payer.pubkey(),
+        &escrow.pubkey(),
+        lamports,
+        (Escrow::LEN - 1) as u64,
+        &env.program_id,
+    );
+    env.process(&[create_account_ix], &[&escrow]).await.unwrap();
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow.pubkey(),
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::AccountDataTooSmall as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_temp_account_not_owned_by_pda() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // An escrow whose temp account was never handed over to the PDA
+    let escrow = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: alice.pubkey(),
+            amount: 10,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: Pubkey::new_unique(),
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+}
+
+// An escrow nobody took from or deposited into, whose temp account holds less than Alice locked
+#[tokio::test]
+async fn test_exchange_rejects_temp_account_short_of_offered_amount() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    let escrow = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: pda,
+            amount: 7,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            offered_amount: 10,
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: Pubkey::new_unique(),
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        7,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidState as u32)
+    );
+}
+
+// Alice's Token Y account is rent paid and owned by the token program, but was never initialized
+#[tokio::test]
+async fn test_exchange_rejects_uninitialized_receive_account() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_y = Pubkey::new_unique();
+
+    let escrow = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let takers_sending_token_account = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: pda,
+            amount: 10,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_account(
+        initializer_token_to_receive_account,
+        Account {
+            lamports: 1_000_000_000,
+            data: vec![0; TokenAccount::LEN],
+            owner: spl_token::id(),
+            ..Account::default()
+        },
+    );
+    program_test.add_packable_account(
+        takers_sending_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: mint_y,
+            owner: bob.pubkey(),
+            amount: 20,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            offered_amount: 10,
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: mint_y,
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::ReceiveAccountUninitialized as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_underfunded_taker() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    // Bob is one Token Y short
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 19)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InsufficientFunds as u32)
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 19);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_foreign_owned_escrow_account() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // A perfectly valid looking escrow state, but owned by some other program
+    let escrow = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: pda,
+            amount: 10,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            ..Escrow::default()
+        },
+        &Pubkey::new_unique(),
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: Pubkey::new_unique(),
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::IncorrectProgramId
+    );
+}
+
+#[tokio::test]
+async fn test_update_expected_amount() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    // Bob only brings what the old price asked for
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Nobody but Alice can reprice the escrow
+    let update_ix = update_expected_amount_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &escrow_accounts.escrow,
+        1,
+    );
+    let error = env.process(&[update_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InitializerMismatch as u32)
+    );
+
+    // And she can't ask for nothing
+    let update_ix = update_expected_amount_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts.escrow,
+        0,
+    );
+    let error = env.process(&[update_ix], &[&alice]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidAmount as u32)
+    );
+
+    let update_ix = update_expected_amount_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts.escrow,
+        30,
+    );
+    env.process(&[update_ix], &[&alice]).await.unwrap();
+
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.expected_amount, 30);
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InsufficientFunds as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_zero_amount() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 0),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidAmount as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_empty_temp_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Alice forgot to move any Token X into her temp account
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(0, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidAmount as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_get_escrow_info_is_read_only() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let escrow_before = env.get_account(&escrow_accounts.escrow).await.unwrap();
+
+    // No signer other than the fee payer, and the escrow account is read-only
+    let get_escrow_info_ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![AccountMeta::new_readonly(escrow_accounts.escrow, false)],
+        data: EscrowInstruction::GetEscrowInfo.pack(),
+    };
+    env.process(&[get_escrow_info_ix], &[]).await.unwrap();
+
+    let escrow_after = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    assert_eq!(escrow_after, escrow_before);
+    // The logged fields are the packed state split at field boundaries
+    let fields = Escrow::split_fields(&escrow_after.data);
+    let escrow = Escrow::unpack(&escrow_after.data).unwrap();
+    assert_eq!(fields[2], escrow.initializer_pubkey.as_ref());
+    assert_eq!(fields[2], alice.pubkey().as_ref());
+    assert_eq!(fields[5], 20u64.to_le_bytes());
+    assert_eq!(fields[19], escrow.created_at_slot.to_le_bytes());
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_initializer_as_taker() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&alice.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::SelfExchangeNotAllowed as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_cancel_escrow_during_lockup() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let not_before = env.unix_timestamp().await + 3600;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                not_before,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    let cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    let error = env
+        .process(&[cancel_escrow_ix], &[&alice])
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::LockupActive as u32)
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_cancel_escrow_after_lockup() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let not_before = env.unix_timestamp().await - 1;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                not_before,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    let cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+    // Alice gets back the rent of both the escrow and the temp account
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, token_account_rent + escrow_rent);
+}
+
+#[tokio::test]
+async fn test_exchange_during_lockup() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // The lockup only keeps Alice from cancelling, Bob can take the trade anytime
+    let not_before = env.unix_timestamp().await + 3600;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                not_before,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_without_rent_sysvar_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    // The program reads the rent straight from the runtime
+    assert!(init_escrow_ix
+        .accounts
+        .iter()
+        .all(|account| account.pubkey != sysvar::rent::id()));
+    env.process(&[init_escrow_ix], &[&alice]).await.unwrap();
+
+    let escrow = env.get_account(&escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert!(escrow.is_initialized);
+    assert_eq!(escrow.expected_amount, 20);
+}
+
+#[tokio::test]
+async fn test_exchange_with_accepted_mint() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let other_stablecoin = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                accepted_mints: [mint_y, other_stablecoin, Pubkey::default()],
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_unaccepted_mint() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let unlisted_mint = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                accepted_mints: [mint_y, Pubkey::default(), Pubkey::default()],
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    // Bob tries to pay with a mint Alice never listed
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &unlisted_mint, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::UnacceptedMint as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_mismatched_decimals() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // An escrow priced for a 6 decimals Token Y, while Alice's Token Y mint has none
+    let mint_x = Pubkey::new_unique();
+    let mint_y = Pubkey::new_unique();
+    for mint in [mint_x, mint_y] {
+        program_test.add_packable_account(
+            mint,
+            1_000_000_000,
+            &Mint {
+                decimals: 0,
+                is_initialized: true,
+                ..Mint::default()
+            },
+            &spl_token::id(),
+        );
+    }
+    let escrow = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let takers_sending_token_account = Pubkey::new_unique();
+    let takers_token_to_receive_account = Pubkey::new_unique();
+    for (account, mint, owner, amount) in [
+        (temp_token_account, mint_x, pda, 10),
+        (
+            initializer_token_to_receive_account,
+            mint_y,
+            alice.pubkey(),
+            0,
+        ),
+        (takers_sending_token_account, mint_y, bob.pubkey(), 20),
+        (takers_token_to_receive_account, mint_x, bob.pubkey(), 0),
+    ] {
+        program_test.add_packable_account(
+            account,
+            1_000_000_000,
+            &TokenAccount {
+                mint,
+                owner,
+                amount,
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            },
+            &spl_token::id(),
+        );
+    }
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            initializer_mint_decimals: 6,
+            token_program: spl_token::id(),
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: mint_y,
+        temp_token_mint: mint_x,
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::DecimalsMismatch as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_fee_above_escrow_balance() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let marketplace = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Alice never funds the fee, so the escrow only holds its rent
+    let fee_lamports = env.minimum_balance(Escrow::LEN).await + 1;
+    env.transfer_lamports(&marketplace.pubkey(), 1_000_000_000)
+        .await;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                fee_lamports,
+                fee_account: marketplace.pubkey(),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::AmountOverflow as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_duplicate_accounts() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+
+    // Alice passes her temp account as the account to receive into as well
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &temp_token_account,
+        &mint_x,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+    let escrow = env.get_account(&escrow).await.unwrap();
+    assert!(
+        !Escrow::unpack_unchecked(&escrow.data)
+            .unwrap()
+            .is_initialized
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_after_transfer_initializer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+
+    // Alice hands the trade to Carol, but keeps receiving the Token Y's herself
+    let transfer_initializer_ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(alice.pubkey(), true),
+            AccountMeta::new(escrow_accounts.escrow, false),
+        ],
+        data: EscrowInstruction::TransferInitializer {
+            new_initializer: carol.pubkey(),
+            update_receive_account: false,
+        }
+        .pack(),
+    };
+    env.process(&[transfer_initializer_ix], &[&alice])
+        .await
+        .unwrap();
+
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.initializer_pubkey, carol.pubkey());
+    assert_eq!(
+        escrow.initializer_token_to_receive_account_pubkey,
+        escrow_accounts.initializer_token_to_receive_account
+    );
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Alice is no longer the initializer Bob has to name
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &carol.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&carol.pubkey()).await.is_some());
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_spoofed_token_program() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let mut exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    // Bob slips in a program of his own in place of the token program
+    exchange_ix.accounts[7].pubkey = Pubkey::new_unique();
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::TokenProgramMismatch as u32)
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+// Run with `cargo test --features escrow-amount-cap`
+#[cfg(feature = "escrow-amount-cap")]
+#[tokio::test]
+async fn test_init_escrow_rejects_amount_above_cap() {
+    use solana_escrow_program::config::MAX_ESCROW_AMOUNT;
+
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, MAX_ESCROW_AMOUNT + 1),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::AmountTooLarge as u32)
+    );
+}
+
+// Run with `cargo test --features min-escrow-duration`
+#[cfg(feature = "min-escrow-duration")]
+#[tokio::test]
+async fn test_init_escrow_rejects_expiry_too_close() {
+    let min_duration = config::MIN_ESCROW_DURATION.unwrap();
+
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    // Expiring a second short of the minimum, measured from the clock the escrow is created at
+    let now = env.unix_timestamp().await;
+    let terms = EscrowTerms {
+        expire_at: now + min_duration - 1,
+        ..EscrowTerms::new(10, 20)
+    };
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &terms,
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::DurationTooShort as u32)
+    );
+
+    // The clock may have moved on since, so leave some room
+    let terms = EscrowTerms {
+        expire_at: now + min_duration + 3600,
+        ..EscrowTerms::new(10, 20)
+    };
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &terms,
+    );
+    env.process(&[init_escrow_ix], &[&alice]).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_deposit_grows_the_escrow() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &initializers_token_x_account, 5).await;
+
+    let deposit_ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(alice.pubkey(), true),
+            AccountMeta::new(initializers_token_x_account, false),
+            AccountMeta::new(escrow_accounts.temp_token_account, false),
+            AccountMeta::new(escrow_accounts.escrow, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(mint_x, false),
+        ],
+        data: EscrowInstruction::Deposit { amount: 5 }.pack(),
+    };
+    env.process(&[deposit_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        15
+    );
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 0);
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.remaining_amount, 15);
+
+    // Bob now takes the larger amount for the same price
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        15,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        15
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+fn add_to_basket_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    basket_temp_token_account: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(escrow_accounts.escrow, false),
+            AccountMeta::new(*basket_temp_token_account, false),
+            AccountMeta::new_readonly(escrow_accounts.token_program, false),
+        ],
+        data: EscrowInstruction::AddToBasket.pack(),
+    }
+}
+
+// Alice bundles 7 Token Z with her 10 Token X, and Bob gets both for 20 Token Y in one go
+#[tokio::test]
+async fn test_basket_exchange_settles_atomically() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let mint_z = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let basket_temp_token_account = env.create_token_account(&mint_z, &alice.pubkey()).await;
+    env.mint_to(&mint_z, &basket_temp_token_account, 7).await;
+
+    let add_to_basket_ix = add_to_basket_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts,
+        &basket_temp_token_account,
+    );
+    env.process(&[add_to_basket_ix], &[&alice]).await.unwrap();
+
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.basket(), [basket_temp_token_account]);
+    let basket_account = env.get_account(&basket_temp_token_account).await.unwrap();
+    let basket_account = TokenAccount::unpack(&basket_account.data).unwrap();
+    assert_eq!(basket_account.owner, escrow_accounts.pda);
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let takers_token_z_account = env.create_token_account(&mint_z, &bob.pubkey()).await;
+
+    // A basket is only ever taken whole
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        5,
+    );
+    let error = env
+        .process(&[partial_exchange_ix], &[&bob])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidInstruction as u32)
+    );
+    // and Bob can't leave the basket behind
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    // The accounts run out where the basket's should be, unless the fee vault moves up into their place
+    let expected_error = if config::MAKER_REBATE_LAMPORTS != 0 {
+        InstructionError::InvalidAccountData
+    } else {
+        InstructionError::NotEnoughAccountKeys
+    };
+    assert_eq!(instruction_error(error), expected_error);
+    assert_eq!(env.token_balance(&basket_temp_token_account).await, 7);
+
+    // Every basket account goes into Bob's account for its own mint, just like the temp account does
+    let exchange_ix = EscrowInstruction::exchange(
+        &env.program_id,
+        &ExchangeAccounts {
+            basket_accounts: vec![(
+                basket_temp_token_account,
+                takers_token_to_receive_account,
+                mint_z,
+            )],
+            ..escrow_accounts.exchange_accounts(
+                &bob.pubkey(),
+                &takers_sending_token_account,
+                &takers_token_to_receive_account,
+                &alice.pubkey(),
+            )
+        },
+        10,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::MintMismatch as u32)
+    );
+    assert_eq!(env.token_balance(&basket_temp_token_account).await, 7);
+
+    let exchange_ix = EscrowInstruction::exchange(
+        &env.program_id,
+        &ExchangeAccounts {
+            basket_accounts: vec![(basket_temp_token_account, takers_token_z_account, mint_z)],
+            ..escrow_accounts.exchange_accounts(
+                &bob.pubkey(),
+                &takers_sending_token_account,
+                &takers_token_to_receive_account,
+                &alice.pubkey(),
+            )
+        },
+        10,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(env.token_balance(&takers_token_z_account).await, 7);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    // Both temp accounts are closed along with the escrow, their rent going back to Alice
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+    assert!(env.get_account(&basket_temp_token_account).await.is_none());
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, 2 * token_account_rent + escrow_rent);
+}
+
+#[tokio::test]
+async fn test_cancel_escrow_hands_basket_back() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let mint_z = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let basket_temp_token_account = env.create_token_account(&mint_z, &alice.pubkey()).await;
+    env.mint_to(&mint_z, &basket_temp_token_account, 7).await;
+    let add_to_basket_ix = add_to_basket_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts,
+        &basket_temp_token_account,
+    );
+    env.process(&[add_to_basket_ix], &[&alice]).await.unwrap();
+
+    // The main temp account is in the escrow already
+    let add_to_basket_ix = add_to_basket_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts,
+        &escrow_accounts.temp_token_account,
+    );
+    let error = env
+        .process(&[add_to_basket_ix], &[&alice])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+
+    // The basket accounts follow right after the mint, the Clock is optional
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let mut cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    cancel_escrow_ix
+        .accounts
+        .push(AccountMeta::new(basket_temp_token_account, false));
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    // Alice has her basket account back, tokens and all
+    let basket_account = env.get_account(&basket_temp_token_account).await.unwrap();
+    let basket_account = TokenAccount::unpack(&basket_account.data).unwrap();
+    assert_eq!(basket_account.owner, alice.pubkey());
+    assert_eq!(basket_account.amount, 7);
+}
+
+#[tokio::test]
+async fn test_init_escrow_reuses_closed_escrow_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let escrow = Keypair::new();
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    for round in 0..2 {
+        // Every round allocates the very same escrow address from scratch.
+        // The lamports differ by the round so the two transactions don't share a signature.
+        let lamports = env.minimum_balance(Escrow::LEN).await + round;
+        let create_escrow_account_ix = system_instruction::create_account(
+            &env.payer.pubkey(),
+            &escrow.pubkey(),
+            lamports,
+            Escrow::LEN as u64,
+            &env.program_id,
+        );
+        env.process(&[create_escrow_account_ix], &[&escrow])
+            .await
+            .unwrap();
+
+        let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+        env.mint_to(&mint_x, &temp_token_account, 10).await;
+        let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+        let init_escrow_ix = init_escrow_instruction(
+            &env.program_id,
+            &alice.pubkey(),
+            &temp_token_account,
+            &token_to_receive_account,
+            &mint_y,
+            &escrow.pubkey(),
+            &env.token_program,
+            &EscrowTerms::new(10, 20),
+        );
+        env.process(&[init_escrow_ix], &[&alice]).await.unwrap();
+
+        let escrow_state = env.get_account(&escrow.pubkey()).await.unwrap();
+        let escrow_state = Escrow::unpack(&escrow_state.data).unwrap();
+        assert_eq!(escrow_state.temp_token_account_pubkey, temp_token_account);
+
+        let (pda, _bump_seed) = Pubkey::find_program_address(
+            &[config::ESCROW_SEED, escrow.pubkey().as_ref()],
+            &env.program_id,
+        );
+        let escrow_accounts = EscrowAccounts {
+            escrow: escrow.pubkey(),
+            temp_token_account,
+            initializer_token_to_receive_account: token_to_receive_account,
+            initializer_token_to_receive_mint: mint_y,
+            temp_token_mint: mint_x,
+            pda,
+            fee_account: None,
+            fee_token_account: None,
+            initializer_token_x_account: None,
+            rent_payer: None,
+            token_program: spl_token::id(),
+        };
+        let cancel_escrow_ix = cancel_escrow_instruction(
+            &env.program_id,
+            &alice.pubkey(),
+            &initializers_token_x_account,
+            &escrow_accounts,
+        );
+        env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+        assert!(env.get_account(&escrow.pubkey()).await.is_none());
+    }
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 20);
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_leftover_data() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+
+    // Not a valid escrow state, but not zeroed either
+    let escrow = Pubkey::new_unique();
+    program_test.add_account(
+        escrow,
+        Account {
+            lamports: 1_000_000_000,
+            data: vec![0xAB; Escrow::LEN],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::AccountAlreadyInitialized
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_with_token_2022() {
+    // Both mints charge a transfer fee, so Token-2022 refuses a plain `Transfer` of them
+    let mut env = TestEnv::new_token_2022().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let mint = env.get_account(&mint_x).await.unwrap();
+    assert_eq!(mint.owner, spl_token_2022::id());
+    assert_eq!(
+        StateWithExtensions::<Token2022Mint>::unpack(&mint.data)
+            .unwrap()
+            .get_extension_types(),
+        vec![ExtensionType::TransferFeeConfig]
+    );
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.token_program, spl_token_2022::id());
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // The escrow settles with the token program it was made with, and no other
+    let mut exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    exchange_ix.accounts[7].pubkey = spl_token::id();
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::TokenProgramMismatch as u32)
+    );
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_other_token_program() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    add_token_2022(&mut program_test);
+    let mut env = TestEnv::start(program_id, program_test).await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Made with SPL Token...
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // ...taken with Token-2022
+    let mut exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    exchange_ix.accounts[7].pubkey = spl_token_2022::id();
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::TokenProgramMismatch as u32)
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_delegated_escrow() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Alice offers only 10 of the 15 Token X's she holds
+    let escrow_accounts = env
+        .init_escrow_delegated(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20), 15)
+        .await;
+    let initializers_token_x_account = escrow_accounts.temp_token_account;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+
+    // Alice's account is still hers, with the rest of her tokens and the approval used up
+    let initializers_token_x_account = TokenAccount::unpack(
+        &env.get_account(&initializers_token_x_account)
+            .await
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert_eq!(initializers_token_x_account.owner, alice.pubkey());
+    assert_eq!(initializers_token_x_account.amount, 5);
+    assert!(initializers_token_x_account.delegate.is_none());
+}
+
+#[tokio::test]
+async fn test_partial_exchange_settling_delegated_escrow_revokes_delegation() {
+    let mut env = TestEnv::new_token_2022().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow_delegated(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                dust_threshold: 1,
+                ..EscrowTerms::new(10, 20)
+            },
+            15,
+        )
+        .await;
+    let initializers_token_x_account = escrow_accounts.temp_token_account;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Bob takes 9 of 10 Token X, leaving a single one of the approval unused
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        9,
+    );
+    env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(env.token_balance(&takers_token_to_receive_account).await, 9);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+
+    // The dust stays with Alice, and the PDA no longer has any say over it
+    let initializers_token_x_account = token::unpack_account(
+        &env.get_account(&initializers_token_x_account)
+            .await
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert_eq!(initializers_token_x_account.amount, 6);
+    assert_eq!(initializers_token_x_account.delegate, COption::None);
+    assert_eq!(initializers_token_x_account.delegated_amount, 0);
+}
+
+#[tokio::test]
+async fn test_cancel_delegated_escrow_revokes_delegation() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow_delegated(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20), 15)
+        .await;
+    let initializers_token_x_account = escrow_accounts.temp_token_account;
+
+    let delegated_account = TokenAccount::unpack(
+        &env.get_account(&initializers_token_x_account)
+            .await
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert_eq!(
+        delegated_account.delegate,
+        COption::Some(escrow_accounts.pda)
+    );
+    assert_eq!(delegated_account.delegated_amount, 10);
+
+    // Nothing to refund, the tokens never left Alice's account, so no mint to name either
+    let mut cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    cancel_escrow_ix.accounts.pop();
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    let revoked_account = TokenAccount::unpack(
+        &env.get_account(&initializers_token_x_account)
+            .await
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert!(revoked_account.delegate.is_none());
+    assert_eq!(revoked_account.delegated_amount, 0);
+    assert_eq!(revoked_account.amount, 15);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_reclaim_orphaned_temp_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+
+    let program_id = env.program_id;
+    let escrow_account = env.create_account(&program_id, Escrow::LEN).await;
+    let orphaned_token_account = env
+        .create_orphaned_token_account(&alice, &mint_x, &escrow_account.pubkey(), 10)
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    let reclaim_orphan_ix = reclaim_orphan_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &orphaned_token_account,
+        &mint_x,
+        &initializers_token_x_account,
+        &escrow_account.pubkey(),
+        true,
+    );
+    env.process(&[reclaim_orphan_ix], &[&alice, &escrow_account])
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&orphaned_token_account).await.is_none());
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, token_account_rent);
+}
+
+#[tokio::test]
+async fn test_reclaim_orphan_requires_escrow_account_signature() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mallory = Keypair::new();
+    let mint_x = env.create_mint().await;
+
+    let program_id = env.program_id;
+    let escrow_account = env.create_account(&program_id, Escrow::LEN).await;
+    let orphaned_token_account = env
+        .create_orphaned_token_account(&alice, &mint_x, &escrow_account.pubkey(), 10)
+        .await;
+
+    // Without a state to tell the initializer, anyone could claim to be Alice
+    let mallorys_token_x_account = env.create_token_account(&mint_x, &mallory.pubkey()).await;
+    let reclaim_orphan_ix = reclaim_orphan_instruction(
+        &env.program_id,
+        &mallory.pubkey(),
+        &orphaned_token_account,
+        &mint_x,
+        &mallorys_token_x_account,
+        &escrow_account.pubkey(),
+        false,
+    );
+    let error = env
+        .process(&[reclaim_orphan_ix], &[&mallory])
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::MissingRequiredSignature
+    );
+    assert_eq!(env.token_balance(&orphaned_token_account).await, 10);
+}
+
+#[tokio::test]
+async fn test_reclaim_orphan_rejects_live_temp_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    // The escrow still refers to its temp account, only `CancelEscrow` may give it back
+    let reclaim_orphan_ix = reclaim_orphan_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts.temp_token_account,
+        &mint_x,
+        &initializers_token_x_account,
+        &escrow_accounts.escrow,
+        false,
+    );
+    let error = env
+        .process(&[reclaim_orphan_ix], &[&alice])
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_and_exchange_derive_pda_from_escrow_seed() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+
+    // Init hands the temp account to the PDA of the exported seed...
+    let (pda, bump_seed) = Pubkey::find_program_address(
+        &[config::ESCROW_SEED, escrow_accounts.escrow.as_ref()],
+        &env.program_id,
+    );
+    let temp_token_account = env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .unwrap();
+    let temp_token_account = TokenAccount::unpack(&temp_token_account.data).unwrap();
+    assert_eq!(temp_token_account.owner, pda);
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.bump_seed, bump_seed);
+
+    // ...and exchange can only sign for it by recreating that very same PDA
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+}
+
+fn exchange_native_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_token_to_receive_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    amount: u64,
+) -> Instruction {
+    // Bob pays from his main account, so he has no sending token account
+    EscrowInstruction::exchange_native(
+        program_id,
+        &escrow_accounts.exchange_accounts(
+            taker,
+            &Pubkey::default(),
+            takers_token_to_receive_account,
+            initializer,
+        ),
+        amount,
+        amount,
+    )
+}
+
+#[tokio::test]
+async fn test_exchange_for_native_sol() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let price = 1_000_000_000;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &Pubkey::default(),
+            EscrowTerms {
+                accept_native: true,
+                ..EscrowTerms::new(1, price)
+            },
+        )
+        .await;
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert!(escrow.accept_native);
+    assert_eq!(
+        escrow.initializer_token_to_receive_account_pubkey,
+        alice.pubkey()
+    );
+
+    let takers_token_to_receive_account = env.create_token_account(&mint_x, &bob.pubkey()).await;
+    env.transfer_lamports(&bob.pubkey(), 2 * price).await;
+
+    let exchange_ix = exchange_native_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        1,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(env.token_balance(&takers_token_to_receive_account).await, 1);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+    // Alice gets the price on top of the rent of the accounts she paid for
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(
+        alice_account.lamports,
+        price + token_account_rent + escrow_rent
+    );
+    let bob_account = env.get_account(&bob.pubkey()).await.unwrap();
+    assert_eq!(bob_account.lamports, price);
+}
+
+#[tokio::test]
+async fn test_exchange_for_native_sol_rejects_insufficient_lamports() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let price = 1_000_000_000;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &Pubkey::default(),
+            EscrowTerms {
+                accept_native: true,
+                ..EscrowTerms::new(1, price)
+            },
+        )
+        .await;
+
+    let takers_token_to_receive_account = env.create_token_account(&mint_x, &bob.pubkey()).await;
+    env.transfer_lamports(&bob.pubkey(), price - 1).await;
+
+    let exchange_ix = exchange_native_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        1,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InsufficientFunds as u32)
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_closed_escrow() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (bobs_sending_token_account, bobs_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &bobs_sending_token_account,
+        &bobs_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    // Carol comes too late, the runtime deleted the drained escrow account, our program no longer owns it
+    let (carols_sending_token_account, carols_token_to_receive_account) = env
+        .create_taker_accounts(&carol.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &carol.pubkey(),
+        &carols_sending_token_account,
+        &carols_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&carol]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::IncorrectProgramId
+    );
+    assert_eq!(env.token_balance(&carols_sending_token_account).await, 20);
+    assert_eq!(env.token_balance(&carols_token_to_receive_account).await, 0);
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_settling_escrow() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // An escrow caught in the middle of another exchange
+    let escrow = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: pda,
+            amount: 10,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            status: EscrowStatus::Settling,
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: Pubkey::new_unique(),
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidState as u32)
+    );
+}
+
+fn reap_expired_instruction(
+    program_id: &Pubkey,
+    keeper: &Pubkey,
+    token_program: &Pubkey,
+    escrows: &[(&EscrowAccounts, Pubkey, Pubkey)],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*keeper, true),
+        AccountMeta::new_readonly(*token_program, false),
+    ];
+    for (escrow_accounts, initializer, initializers_token_x_account) in escrows {
+        accounts.extend([
+            AccountMeta::new(escrow_accounts.escrow, false),
+            AccountMeta::new(escrow_accounts.temp_token_account, false),
+            AccountMeta::new_readonly(escrow_accounts.pda, false),
+            AccountMeta::new(*initializer, false),
+            AccountMeta::new(*initializers_token_x_account, false),
+            AccountMeta::new(escrow_accounts.rent_payer.unwrap_or(*initializer), false),
+            AccountMeta::new_readonly(escrow_accounts.temp_token_mint, false),
+        ]);
+    }
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ReapExpired.pack(),
+    }
+}
+
+#[tokio::test]
+async fn test_reap_expired_escrows() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let carol = Keypair::new();
+    let keeper = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let expire_at = env.unix_timestamp().await - 1;
+    let alices_escrow = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let carols_escrow = env
+        .init_escrow(
+            &carol,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(30, 40)
+            },
+        )
+        .await;
+    let alices_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let carols_token_x_account = env.create_token_account(&mint_x, &carol.pubkey()).await;
+
+    let reap_expired_ix = reap_expired_instruction(
+        &env.program_id,
+        &keeper.pubkey(),
+        &env.token_program,
+        &[
+            (&alices_escrow, alice.pubkey(), alices_token_x_account),
+            (&carols_escrow, carol.pubkey(), carols_token_x_account),
+        ],
+    );
+    env.process(&[reap_expired_ix], &[&keeper]).await.unwrap();
+
+    assert_eq!(env.token_balance(&alices_token_x_account).await, 10);
+    assert_eq!(env.token_balance(&carols_token_x_account).await, 30);
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    for (escrow_accounts, initializer) in [(&alices_escrow, &alice), (&carols_escrow, &carol)] {
+        assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+        assert!(env
+            .get_account(&escrow_accounts.temp_token_account)
+            .await
+            .is_none());
+        let initializer_account = env.get_account(&initializer.pubkey()).await.unwrap();
+        assert_eq!(
+            initializer_account.lamports,
+            token_account_rent + escrow_rent
+        );
+    }
+}
+
+// Open and already cancelled escrows are skipped, without holding up the expired one between them
+#[tokio::test]
+async fn test_reap_expired_skips_escrows_it_cannot_reap() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let carol = Keypair::new();
+    let dave = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let expire_at = env.unix_timestamp().await - 1;
+    let alices_escrow = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let carols_escrow = env
+        .init_escrow(
+            &carol,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(30, 40)
+            },
+        )
+        .await;
+    let daves_escrow = env
+        .init_escrow(
+            &dave,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(50, 60)
+            },
+        )
+        .await;
+    let alices_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let carols_token_x_account = env.create_token_account(&mint_x, &carol.pubkey()).await;
+    let daves_token_x_account = env.create_token_account(&mint_x, &dave.pubkey()).await;
+
+    // Dave cancels his before the keeper gets to it
+    let cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &dave.pubkey(),
+        &daves_token_x_account,
+        &daves_escrow,
+    );
+    env.process(&[cancel_escrow_ix], &[&dave]).await.unwrap();
+
+    // and the keeper needn't sign to crank it
+    let mut reap_expired_ix = reap_expired_instruction(
+        &env.program_id,
+        &Pubkey::new_unique(),
+        &env.token_program,
+        &[
+            (&alices_escrow, alice.pubkey(), alices_token_x_account),
+            (&carols_escrow, carol.pubkey(), carols_token_x_account),
+            (&daves_escrow, dave.pubkey(), daves_token_x_account),
+        ],
+    );
+    reap_expired_ix.accounts[0].is_signer = false;
+    env.process(&[reap_expired_ix], &[]).await.unwrap();
+
+    // Alice's escrow is still open
+    assert_eq!(env.token_balance(&alices_token_x_account).await, 0);
+    assert_eq!(
+        env.token_balance(&alices_escrow.temp_token_account).await,
+        10
+    );
+    assert!(env.get_account(&alices_escrow.escrow).await.is_some());
+    // Carol's is reaped
+    assert_eq!(env.token_balance(&carols_token_x_account).await, 30);
+    assert!(env.get_account(&carols_escrow.escrow).await.is_none());
+    // and Dave got his tokens back once, from his own cancel
+    assert_eq!(env.token_balance(&daves_token_x_account).await, 50);
+}
+
+#[tokio::test]
+async fn test_reap_expired_rejects_open_escrow() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let keeper = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    let reap_expired_ix = reap_expired_instruction(
+        &env.program_id,
+        &keeper.pubkey(),
+        &env.token_program,
+        &[(
+            &escrow_accounts,
+            alice.pubkey(),
+            initializers_token_x_account,
+        )],
+    );
+    let error = env
+        .process(&[reap_expired_ix], &[&keeper])
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::EscrowNotExpired as u32)
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_grace_period_leaves_expired_escrow_to_initializer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let keeper = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Expired a moment ago, with an hour of grace left
+    let expire_at = env.unix_timestamp().await - 10;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                grace_seconds: 3600,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Bob is too late to take it
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::EscrowExpired as u32)
+    );
+
+    // the keeper is too early to reap it
+    let reap_expired_ix = reap_expired_instruction(
+        &env.program_id,
+        &keeper.pubkey(),
+        &env.token_program,
+        &[(
+            &escrow_accounts,
+            alice.pubkey(),
+            initializers_token_x_account,
+        )],
+    );
+    let error = env
+        .process(&[reap_expired_ix], &[&keeper])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InGracePeriod as u32)
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+
+    // but Alice may still cancel it herself
+    let cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_reap_expired_after_grace_period() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let keeper = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Expired two hours ago, and the hour of grace is over
+    let expire_at = env.unix_timestamp().await - 7200;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                grace_seconds: 3600,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    let reap_expired_ix = reap_expired_instruction(
+        &env.program_id,
+        &keeper.pubkey(),
+        &env.token_program,
+        &[(
+            &escrow_accounts,
+            alice.pubkey(),
+            initializers_token_x_account,
+        )],
+    );
+    env.process(&[reap_expired_ix], &[&keeper]).await.unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_uninitialized_escrow() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Right size and owner, but nobody ever ran InitEscrow on it
+    let escrow = env.create_escrow_account().await;
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let initializer_token_to_receive_account =
+        env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let (pda, _bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &env.program_id);
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: mint_y,
+        temp_token_mint: mint_x,
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::UninitializedAccount
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_slippage() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (bobs_sending_token_account, bobs_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    // Bob saw 10 Token X's in the escrow and asks for no less
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &bobs_sending_token_account,
+        &bobs_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+
+    // but Carol takes half of them before him
+    let (carols_sending_token_account, carols_token_to_receive_account) = env
+        .create_taker_accounts(&carol.pubkey(), &mint_x, &mint_y, 10)
+        .await;
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &carol.pubkey(),
+        &carols_sending_token_account,
+        &carols_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        5,
+    );
+    env.process(&[partial_exchange_ix], &[&carol])
+        .await
+        .unwrap();
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        5
+    );
+
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::SlippageExceeded as u32)
+    );
+    assert_eq!(env.token_balance(&bobs_sending_token_account).await, 20);
+}
+
+#[tokio::test]
+async fn test_exchange_refunds_rent_to_rent_payer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    // The front-end that paid for the escrow account
+    let sponsor = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                rent_payer: Some(sponsor.pubkey()),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.rent_payer, sponsor.pubkey());
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    // The sponsor gets the escrow's rent back, Alice only that of her temp account
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let sponsor_account = env.get_account(&sponsor.pubkey()).await.unwrap();
+    assert_eq!(sponsor_account.lamports, escrow_rent);
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, token_account_rent);
+}
+
+#[tokio::test]
+async fn test_cancel_escrow_refunds_rent_to_rent_payer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let sponsor = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let mut escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                rent_payer: Some(sponsor.pubkey()),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    // Alice can't keep the sponsor's rent by leaving their account out
+    escrow_accounts.rent_payer = None;
+    let cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    let error = env
+        .process(&[cancel_escrow_ix], &[&alice])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::NotEnoughAccountKeys
+    );
+
+    escrow_accounts.rent_payer = Some(sponsor.pubkey());
+    let cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    // The sponsor gets the escrow's rent back, Alice only that of her temp account
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let sponsor_account = env.get_account(&sponsor.pubkey()).await.unwrap();
+    assert_eq!(sponsor_account.lamports, escrow_rent);
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, token_account_rent);
+}
+
+#[tokio::test]
+async fn test_reap_expired_refunds_rent_to_rent_payer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let carol = Keypair::new();
+    let sponsor = Keypair::new();
+    let keeper = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Only Alice's escrow was sponsored, Carol paid her own
+    let expire_at = env.unix_timestamp().await - 1;
+    let alices_escrow = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                rent_payer: Some(sponsor.pubkey()),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let carols_escrow = env
+        .init_escrow(
+            &carol,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                expire_at,
+                ..EscrowTerms::new(30, 40)
+            },
+        )
+        .await;
+    let alices_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let carols_token_x_account = env.create_token_account(&mint_x, &carol.pubkey()).await;
+
+    let reap_expired_ix = reap_expired_instruction(
+        &env.program_id,
+        &keeper.pubkey(),
+        &env.token_program,
+        &[
+            (&alices_escrow, alice.pubkey(), alices_token_x_account),
+            (&carols_escrow, carol.pubkey(), carols_token_x_account),
+        ],
+    );
+    env.process(&[reap_expired_ix], &[&keeper]).await.unwrap();
+
+    assert_eq!(env.token_balance(&alices_token_x_account).await, 10);
+    assert_eq!(env.token_balance(&carols_token_x_account).await, 30);
+    let token_account_rent = env.minimum_balance(TokenAccount::LEN).await;
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    let sponsor_account = env.get_account(&sponsor.pubkey()).await.unwrap();
+    assert_eq!(sponsor_account.lamports, escrow_rent);
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, token_account_rent);
+    let carol_account = env.get_account(&carol.pubkey()).await.unwrap();
+    assert_eq!(carol_account.lamports, token_account_rent + escrow_rent);
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_wrong_rent_payer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let mut escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                rent_payer: Some(Pubkey::new_unique()),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    // Bob tries to pocket the escrow's rent
+    escrow_accounts.rent_payer = Some(bob.pubkey());
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_records_creation_slot() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.created_at_slot, env.slot().await);
+}
+
+fn prepare_exchange_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*taker, true),
+            AccountMeta::new(*escrow, false),
+        ],
+        data: EscrowInstruction::PrepareExchange.pack(),
+    }
+}
+
+fn finalize_exchange_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    takers_token_to_receive_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    amount: u64,
+) -> Instruction {
+    // Same accounts as a full exchange, only the data differs
+    let mut instruction = exchange_instruction(
+        program_id,
+        taker,
+        takers_sending_token_account,
+        takers_token_to_receive_account,
+        initializer,
+        escrow_accounts,
+        amount,
+    );
+    instruction.data = EscrowInstruction::FinalizeExchange { amount }.pack();
+    instruction
+}
+
+#[tokio::test]
+async fn test_prepare_and_finalize_exchange() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+
+    let prepare_exchange_ix =
+        prepare_exchange_instruction(&env.program_id, &bob.pubkey(), &escrow_accounts.escrow);
+    env.process(&[prepare_exchange_ix], &[&bob]).await.unwrap();
+
+    let now = env.unix_timestamp().await;
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.status, EscrowStatus::Settling);
+    assert_eq!(escrow.prepared_taker, bob.pubkey());
+    assert_eq!(
+        escrow.prepared_deadline,
+        now + config::PREPARED_EXCHANGE_DURATION
+    );
+
+    // The escrow is Bob's to take for now, Carol can't get in between
+    let (carols_sending_token_account, carols_token_to_receive_account) = env
+        .create_taker_accounts(&carol.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &carol.pubkey(),
+        &carols_sending_token_account,
+        &carols_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&carol]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidState as u32)
+    );
+    // nor finalize it in his place
+    let finalize_exchange_ix = finalize_exchange_instruction(
+        &env.program_id,
+        &carol.pubkey(),
+        &carols_sending_token_account,
+        &carols_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env
+        .process(&[finalize_exchange_ix], &[&carol])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::ExchangeNotPrepared as u32)
+    );
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let finalize_exchange_ix = finalize_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[finalize_exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+// Bob locking the escrow up doesn't keep Alice from taking her tokens back
+#[tokio::test]
+async fn test_cancel_prepared_escrow() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let prepare_exchange_ix =
+        prepare_exchange_instruction(&env.program_id, &bob.pubkey(), &escrow_accounts.escrow);
+    env.process(&[prepare_exchange_ix], &[&bob]).await.unwrap();
+
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_finalize_exchange_after_deadline() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // Bob prepared the exchange long ago and never finalized it
+    let escrow = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: pda,
+            amount: 10,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            status: EscrowStatus::Settling,
+            prepared_taker: bob.pubkey(),
+            prepared_deadline: 1,
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: Pubkey::new_unique(),
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let finalize_exchange_ix = finalize_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env
+        .process(&[finalize_exchange_ix], &[&bob])
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::EscrowExpired as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_empty_temp_account() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // An open escrow whose temp account was drained somehow
+    let escrow = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: pda,
+            amount: 0,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: Pubkey::new_unique(),
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    // Asking for exactly what is there, nothing
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        0,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidAmount as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_signing_escrow_account() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // Bob somehow holds the key of an open escrow
+    let escrow = Keypair::new();
+    let temp_token_account = Pubkey::new_unique();
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let (pda, bump_seed) = Pubkey::find_program_address(
+        &[config::ESCROW_SEED, escrow.pubkey().as_ref()],
+        &program_id,
+    );
+    program_test.add_packable_account(
+        temp_token_account,
+        1_000_000_000,
+        &TokenAccount {
+            mint: Pubkey::new_unique(),
+            owner: pda,
+            amount: 10,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        },
+        &spl_token::id(),
+    );
+    program_test.add_packable_account(
+        escrow.pubkey(),
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: temp_token_account,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow: escrow.pubkey(),
+        temp_token_account,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: Pubkey::new_unique(),
+        temp_token_mint: Pubkey::new_unique(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let mut exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &Pubkey::new_unique(),
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    exchange_ix.accounts[6].is_signer = true;
+    let error = env
+        .process(&[exchange_ix], &[&bob, &escrow])
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+}
+
+fn split_escrow_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    new_escrow: &Pubkey,
+    new_temp_token_account: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (_new_pda, new_escrow_bump) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, new_escrow.as_ref()], program_id);
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(escrow_accounts.escrow, false),
+            AccountMeta::new(escrow_accounts.temp_token_account, false),
+            AccountMeta::new_readonly(escrow_accounts.pda, false),
+            AccountMeta::new(*new_escrow, false),
+            AccountMeta::new(*new_temp_token_account, false),
+            AccountMeta::new_readonly(escrow_accounts.token_program, false),
+            AccountMeta::new_readonly(escrow_accounts.temp_token_mint, false),
+        ],
+        data: EscrowInstruction::SplitEscrow {
+            amount,
+            new_escrow_bump,
+        }
+        .pack(),
+    }
+}
+
+#[tokio::test]
+async fn test_split_escrow_and_exchange_each_part() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(100, 200))
+        .await;
+
+    // Alice moves 40 of her 100 Token X into a second escrow
+    let new_escrow = env.create_escrow_account().await;
+    let new_temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let split_escrow_ix = split_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts,
+        &new_escrow,
+        &new_temp_token_account,
+        40,
+    );
+    env.process(&[split_escrow_ix], &[&alice]).await.unwrap();
+
+    let (new_pda, _bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, new_escrow.as_ref()], &env.program_id);
+    let new_escrow_accounts = EscrowAccounts {
+        escrow: new_escrow,
+        temp_token_account: new_temp_token_account,
+        initializer_token_to_receive_account: escrow_accounts.initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: mint_y,
+        temp_token_mint: mint_x,
+        pda: new_pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: env.token_program,
+    };
+
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.remaining_amount, 60);
+    assert_eq!(escrow.offered_amount, 60);
+    assert_eq!(escrow.expected_amount, 120);
+    let new_escrow_info = env.get_account(&new_escrow).await.unwrap();
+    let new_escrow_info = Escrow::unpack(&new_escrow_info.data).unwrap();
+    assert_eq!(new_escrow_info.initializer_pubkey, alice.pubkey());
+    assert_eq!(
+        new_escrow_info.temp_token_account_pubkey,
+        new_temp_token_account
+    );
+    assert_eq!(new_escrow_info.remaining_amount, 40);
+    assert_eq!(new_escrow_info.offered_amount, 40);
+    assert_eq!(new_escrow_info.expected_amount, 80);
+    assert_eq!(new_escrow_info.status, EscrowStatus::Open);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        60
+    );
+    let new_temp = env.get_account(&new_temp_token_account).await.unwrap();
+    let new_temp = TokenAccount::unpack(&new_temp.data).unwrap();
+    assert_eq!(new_temp.owner, new_pda);
+    assert_eq!(new_temp.amount, 40);
+
+    // Bob takes the 60 left in the original escrow...
+    let (bobs_sending_token_account, bobs_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 120)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &bobs_sending_token_account,
+        &bobs_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        60,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    // ...and Carol the 40 in the new one, neither caring about the other
+    let (carols_sending_token_account, carols_token_to_receive_account) = env
+        .create_taker_accounts(&carol.pubkey(), &mint_x, &mint_y, 80)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &carol.pubkey(),
+        &carols_sending_token_account,
+        &carols_token_to_receive_account,
+        &alice.pubkey(),
+        &new_escrow_accounts,
+        40,
+    );
+    env.process(&[exchange_ix], &[&carol]).await.unwrap();
+
+    assert_eq!(env.token_balance(&bobs_token_to_receive_account).await, 60);
+    assert_eq!(
+        env.token_balance(&carols_token_to_receive_account).await,
+        40
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        200
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    assert!(env.get_account(&new_escrow).await.is_none());
+}
+
+// Splits 40 of a 100 Token X escrow into `new_temp_token_account`, which is expected to be turned away
+async fn split_escrow_error(
+    env: &mut TestEnv,
+    alice: &Keypair,
+    escrow_accounts: &EscrowAccounts,
+    new_temp_token_account: &Pubkey,
+) -> InstructionError {
+    let new_escrow = env.create_escrow_account().await;
+    let split_escrow_ix = split_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        escrow_accounts,
+        &new_escrow,
+        new_temp_token_account,
+        40,
+    );
+    let error = env.process(&[split_escrow_ix], &[alice]).await.unwrap_err();
+
+    // Nothing moved
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        100
+    );
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    assert_eq!(Escrow::unpack(&escrow.data).unwrap().remaining_amount, 100);
+    instruction_error(error)
+}
+
+#[tokio::test]
+async fn test_split_escrow_rejects_funded_temp_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(100, 200))
+        .await;
+
+    // Tokens already in the new temp account would be in the new escrow without being offered
+    let new_temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &new_temp_token_account, 5).await;
+
+    assert_eq!(
+        split_escrow_error(&mut env, &alice, &escrow_accounts, &new_temp_token_account).await,
+        InstructionError::Custom(EscrowError::InvalidAmount as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_split_escrow_rejects_delegated_temp_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(100, 200))
+        .await;
+
+    // Whoever Alice approved could move the new escrow's tokens out from under its PDA
+    let new_temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let approve_ix = spl_token::instruction::approve(
+        &spl_token::id(),
+        &new_temp_token_account,
+        &Pubkey::new_unique(),
+        &alice.pubkey(),
+        &[],
+        40,
+    )
+    .unwrap();
+    env.process(&[approve_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(
+        split_escrow_error(&mut env, &alice, &escrow_accounts, &new_temp_token_account).await,
+        InstructionError::InvalidAccountData
+    );
+}
+
+#[tokio::test]
+async fn test_split_escrow_rejects_temp_account_with_close_authority() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(100, 200))
+        .await;
+
+    // The close authority stays when the account is handed over to the new PDA
+    let new_temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let set_close_authority_ix = spl_token::instruction::set_authority(
+        &spl_token::id(),
+        &new_temp_token_account,
+        Some(&alice.pubkey()),
+        spl_token::instruction::AuthorityType::CloseAccount,
+        &alice.pubkey(),
+        &[],
+    )
+    .unwrap();
+    env.process(&[set_close_authority_ix], &[&alice])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        split_escrow_error(&mut env, &alice, &escrow_accounts, &new_temp_token_account).await,
+        InstructionError::InvalidAccountData
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_pays_taker_fee() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let marketplace = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let fee_token_account = env
+        .create_token_account(&mint_x, &marketplace.pubkey())
+        .await;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                taker_fee_bps: 300,
+                fee_token_account,
+                ..EscrowTerms::new(100, 200)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 200)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        100,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    // 3% of the 100 Token X goes to the marketplace, the rest to Bob, and nothing is left behind
+    let fee = env.token_balance(&fee_token_account).await;
+    let taken = env.token_balance(&takers_token_to_receive_account).await;
+    assert_eq!(fee, 3);
+    assert_eq!(taken, 97);
+    assert_eq!(fee + taken, 100);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        200
+    );
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_partial_exchanges_pay_taker_fee_on_each_fill() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let marketplace = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let fee_token_account = env
+        .create_token_account(&mint_x, &marketplace.pubkey())
+        .await;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                taker_fee_bps: 250,
+                fee_token_account,
+                ..EscrowTerms::new(100, 200)
+            },
+        )
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 200)
+        .await;
+
+    // 2.5% of 40 is 1, rounded down in Bob's favour...
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        40,
+    );
+    env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+    assert_eq!(env.token_balance(&fee_token_account).await, 1);
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        39
+    );
+
+    // ...and so is 2.5% of the remaining 60
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        60,
+    );
+    env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+
+    let fee = env.token_balance(&fee_token_account).await;
+    let taken = env.token_balance(&takers_token_to_receive_account).await;
+    assert_eq!(fee, 2);
+    assert_eq!(taken, 98);
+    assert_eq!(fee + taken, 100);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_taker_fee_above_whole() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms {
+            taker_fee_bps: 10_001,
+            fee_token_account: Pubkey::new_unique(),
+            ..EscrowTerms::new(10, 20)
+        },
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidAmount as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_rejects_initialized_account_below_rent_exemption() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+
+    // A live escrow state, in an account that doesn't even pay its rent
+    let escrow = Pubkey::new_unique();
+    program_test.add_packable_account(
+        escrow,
+        1_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            expected_amount: 20,
+            expire_at: i64::MAX,
+            remaining_amount: 10,
+            token_program: spl_token::id(),
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+    assert!(env.minimum_balance(Escrow::LEN).await > 1_000_000);
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+
+    let init_escrow_ix = init_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+
+    // Not `NotRentExempt`, the account being taken is what matters
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::AccountAlreadyInitialized
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_records_reference() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    // Whatever the desk's system uses, the escrow keeps it byte for byte
+    let mut reference = [0; 32];
+    reference[..12].copy_from_slice(b"OTC-2024-001");
+    reference[31] = 0xFF;
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                reference,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.reference, reference);
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_mismatched_initializer_main_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mallory = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Every account is right except the one the lamports are swept into
+    let mut exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    exchange_ix.accounts[4].pubkey = mallory.pubkey();
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+    assert!(env.get_account(&mallory.pubkey()).await.is_none());
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+// Same accounts as a full exchange, with a taker who hasn't signed anything yet
+fn simulate_exchange_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    takers_token_to_receive_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    amount: u64,
+) -> Instruction {
+    let mut instruction = exchange_instruction(
+        program_id,
+        taker,
+        takers_sending_token_account,
+        takers_token_to_receive_account,
+        initializer,
+        escrow_accounts,
+        amount,
+    );
+    instruction.accounts[0].is_signer = false;
+    instruction.data = EscrowInstruction::SimulateExchange { amount }.pack();
+    instruction
+}
+
+#[tokio::test]
+async fn test_simulate_exchange_agrees_with_exchange() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let mint_z = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    // Bob would be paid in a token Alice never put in the escrow
+    let takers_wrong_token_to_receive_account =
+        env.create_token_account(&mint_z, &bob.pubkey()).await;
+
+    let simulate_exchange_ix = simulate_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_wrong_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let simulated = env.process(&[simulate_exchange_ix], &[]).await.unwrap_err();
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_wrong_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let exchanged = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(simulated),
+        InstructionError::Custom(EscrowError::MintMismatch as u32)
+    );
+    assert_eq!(
+        instruction_error(exchanged),
+        InstructionError::Custom(EscrowError::MintMismatch as u32)
+    );
+
+    // The right accounts pass the simulation, which leaves everything as it was...
+    let simulate_exchange_ix = simulate_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[simulate_exchange_ix], &[]).await.unwrap();
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.status, EscrowStatus::Open);
+
+    // ...and the real exchange goes through just the same
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_frozen_taker_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    env.freeze_account(&mint_y, &takers_sending_token_account)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    // Our own error, not the token program's `AccountFrozen` from inside the transfer
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::AccountFrozen as u32)
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_partial_exchange_settles_escrow_with_dust_left() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                dust_threshold: 1,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let initializer_token_x_account = escrow_accounts.initializer_token_x_account.unwrap();
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Bob takes 9 of 10 Token X, leaving a single one behind
+    let partial_exchange_ix = partial_exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        9,
+    );
+    env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+
+    // That one is dust, so it goes back to Alice and the escrow is done with
+    assert_eq!(env.token_balance(&takers_token_to_receive_account).await, 9);
+    assert_eq!(env.token_balance(&initializer_token_x_account).await, 1);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        18
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    assert!(env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .is_none());
+}
+
+fn set_receive_account_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    current_token_to_receive_account: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(*escrow, false),
+            AccountMeta::new_readonly(*token_to_receive_account, false),
+            AccountMeta::new_readonly(*current_token_to_receive_account, false),
+        ],
+        data: EscrowInstruction::SetReceiveAccount.pack(),
+    }
+}
+
+#[tokio::test]
+async fn test_exchange_pays_updated_receive_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let mut escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let old_token_to_receive_account = escrow_accounts.initializer_token_to_receive_account;
+
+    // An account of the token Alice offers is no place to be paid in
+    let wrong_mint_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    let set_receive_account_ix = set_receive_account_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts.escrow,
+        &wrong_mint_account,
+        &old_token_to_receive_account,
+    );
+    let error = env
+        .process(&[set_receive_account_ix], &[&alice])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::UnacceptedMint as u32)
+    );
+
+    let new_token_to_receive_account = env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let set_receive_account_ix = set_receive_account_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts.escrow,
+        &new_token_to_receive_account,
+        &old_token_to_receive_account,
+    );
+    env.process(&[set_receive_account_ix], &[&alice])
+        .await
+        .unwrap();
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Bob can no longer pay into the account the escrow was made with
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+
+    escrow_accounts.initializer_token_to_receive_account = new_token_to_receive_account;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(env.token_balance(&new_token_to_receive_account).await, 20);
+    assert_eq!(env.token_balance(&old_token_to_receive_account).await, 0);
+}
+
+// Same accounts as `InitEscrow`, with Alice paying for the escrow account the program creates
+#[allow(clippy::too_many_arguments)]
+fn init_escrow_pda_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    temp_token_account: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    token_to_receive_mint: &Pubkey,
+    escrow_account: &Pubkey,
+    token_program: &Pubkey,
+    terms: &EscrowTerms,
+) -> Instruction {
+    let mut instruction = init_escrow_instruction(
+        program_id,
+        initializer,
+        temp_token_account,
+        token_to_receive_account,
+        token_to_receive_mint,
+        escrow_account,
+        token_program,
+        terms,
+    );
+    instruction.accounts[0].is_writable = true;
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(system_program::id(), false));
+    instruction.data = EscrowInstruction::InitEscrowPda {
+        params: terms.params(),
+    }
+    .pack();
+    instruction
+}
+
+#[tokio::test]
+async fn test_init_escrow_pda_creates_escrow_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let rent = env.minimum_balance(Escrow::LEN).await;
+    env.transfer_lamports(&alice.pubkey(), rent + 1_000_000)
+        .await;
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let initializer_token_to_receive_account =
+        env.create_token_account(&mint_y, &alice.pubkey()).await;
+
+    // Any address but the derived one is turned down
+    let init_escrow_ix = init_escrow_pda_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &initializer_token_to_receive_account,
+        &mint_y,
+        &Keypair::new().pubkey(),
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+    assert_eq!(instruction_error(error), InstructionError::InvalidSeeds);
+
+    let (escrow, _bump_seed) = Pubkey::find_program_address(
+        &[
+            config::ESCROW_STATE_SEED,
+            alice.pubkey().as_ref(),
+            temp_token_account.as_ref(),
+        ],
+        &env.program_id,
+    );
+    let init_escrow_ix = init_escrow_pda_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &initializer_token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    env.process(&[init_escrow_ix], &[&alice]).await.unwrap();
+
+    let escrow_account = env.get_account(&escrow).await.unwrap();
+    assert_eq!(escrow_account.owner, env.program_id);
+    assert_eq!(escrow_account.data.len(), Escrow::LEN);
+    assert_eq!(escrow_account.lamports, rent);
+    let escrow_info = Escrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(escrow_info.initializer_pubkey, alice.pubkey());
+    assert_eq!(escrow_info.temp_token_account_pubkey, temp_token_account);
+    assert_eq!(escrow_info.remaining_amount, 10);
+
+    // Alice paid for it out of her own pocket
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, 1_000_000);
+}
+
+// Someone sending the derived address a lamport first doesn't keep Alice from creating her escrow there
+#[tokio::test]
+async fn test_init_escrow_pda_at_funded_address() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let rent = env.minimum_balance(Escrow::LEN).await;
+    env.transfer_lamports(&alice.pubkey(), rent + 1_000_000)
+        .await;
+    let temp_token_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &temp_token_account, 10).await;
+    let initializer_token_to_receive_account =
+        env.create_token_account(&mint_y, &alice.pubkey()).await;
+
+    let (escrow, _bump_seed) = Pubkey::find_program_address(
+        &[
+            config::ESCROW_STATE_SEED,
+            alice.pubkey().as_ref(),
+            temp_token_account.as_ref(),
+        ],
+        &env.program_id,
+    );
+    env.transfer_lamports(&escrow, 1).await;
+
+    let init_escrow_ix = init_escrow_pda_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &initializer_token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    env.process(&[init_escrow_ix], &[&alice]).await.unwrap();
+
+    let escrow_account = env.get_account(&escrow).await.unwrap();
+    assert_eq!(escrow_account.owner, env.program_id);
+    assert_eq!(escrow_account.data.len(), Escrow::LEN);
+    assert_eq!(escrow_account.lamports, rent);
+    assert_eq!(
+        Escrow::unpack(&escrow_account.data)
+            .unwrap()
+            .remaining_amount,
+        10
+    );
+
+    // Alice only paid what the address lacked
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, 1_000_001);
+}
+
+// Same accounts as `InitEscrow`, with Alice paying for the temp token account the program creates
+// and filling it from her own Token X account
+#[allow(clippy::too_many_arguments)]
+fn init_escrow_managed_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    temp_token_account: &Pubkey,
+    initializers_token_x_account: &Pubkey,
+    mint_x: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    token_to_receive_mint: &Pubkey,
+    escrow_account: &Pubkey,
+    token_program: &Pubkey,
+    terms: &EscrowTerms,
+) -> Instruction {
+    let mut instruction = init_escrow_instruction(
+        program_id,
+        initializer,
+        temp_token_account,
+        token_to_receive_account,
+        token_to_receive_mint,
+        escrow_account,
+        token_program,
+        terms,
+    );
+    instruction.accounts[0].is_writable = true;
+    instruction.accounts.extend([
+        AccountMeta::new(*initializers_token_x_account, false),
+        AccountMeta::new_readonly(*mint_x, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ]);
+    instruction.data = EscrowInstruction::InitEscrowManaged {
+        params: terms.params(),
+        offered_amount: terms.offered_amount,
+    }
+    .pack();
+    instruction
+}
+
+#[tokio::test]
+async fn test_init_escrow_managed_creates_temp_token_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let rent = env.minimum_balance(TokenAccount::LEN).await;
+    env.transfer_lamports(&alice.pubkey(), rent + 1_000_000)
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+    env.mint_to(&mint_x, &initializers_token_x_account, 25)
+        .await;
+    let initializer_token_to_receive_account =
+        env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+
+    // Any address but the derived one is turned down
+    let init_escrow_ix = init_escrow_managed_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &Keypair::new().pubkey(),
+        &initializers_token_x_account,
+        &mint_x,
+        &initializer_token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+    assert_eq!(instruction_error(error), InstructionError::InvalidSeeds);
+
+    // The derived one is taken even once someone sent it a lamport
+    let (temp_token_account, _bump_seed) = Pubkey::find_program_address(
+        &[config::TEMP_TOKEN_ACCOUNT_SEED, escrow.as_ref()],
+        &env.program_id,
+    );
+    env.transfer_lamports(&temp_token_account, 1).await;
+    let init_escrow_ix = init_escrow_managed_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &temp_token_account,
+        &initializers_token_x_account,
+        &mint_x,
+        &initializer_token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &EscrowTerms::new(10, 20),
+    );
+    env.process(&[init_escrow_ix], &[&alice]).await.unwrap();
+
+    // The temp token account exists, under the PDA, holding just what Alice offered
+    let (pda, _bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &env.program_id);
+    let temp_account = env.get_account(&temp_token_account).await.unwrap();
+    assert_eq!(temp_account.owner, env.token_program);
+    assert_eq!(temp_account.lamports, rent);
+    let temp_account_info = TokenAccount::unpack(&temp_account.data).unwrap();
+    assert_eq!(temp_account_info.mint, mint_x);
+    assert_eq!(temp_account_info.owner, pda);
+    assert_eq!(temp_account_info.amount, 10);
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 15);
+
+    let escrow_account = env.get_account(&escrow).await.unwrap();
+    let escrow_info = Escrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(escrow_info.temp_token_account_pubkey, temp_token_account);
+    assert_eq!(escrow_info.remaining_amount, 10);
+
+    // Alice paid for it out of her own pocket, but for the lamport it already held
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_account.lamports, 1_000_001);
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_substituted_temp_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+
+    // Same mint, same balance, even under the PDA, but not the account the escrow was made with
+    let substituted_temp_token_account = env
+        .create_token_account(&mint_x, &escrow_accounts.pda)
+        .await;
+    env.mint_to(&mint_x, &substituted_temp_token_account, 10)
+        .await;
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let substituted_accounts = EscrowAccounts {
+        temp_token_account: substituted_temp_token_account,
+        ..escrow_accounts
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &substituted_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidAccountData
+    );
+
+    assert_eq!(env.token_balance(&substituted_temp_token_account).await, 10);
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(env.token_balance(&takers_token_to_receive_account).await, 0);
+}
+
+#[tokio::test]
+async fn test_open_escrow_takes_any_taker() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.allowed_taker, Pubkey::default());
+
+    // Whoever shows up first gets it
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&carol.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &carol.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&carol]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+}
+
+#[tokio::test]
+async fn test_private_escrow_rejects_other_takers() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let carol = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                allowed_taker: bob.pubkey(),
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+
+    // Carol can neither take the deal Alice made for Bob...
+    let (carols_sending_token_account, carols_token_to_receive_account) = env
+        .create_taker_accounts(&carol.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &carol.pubkey(),
+        &carols_sending_token_account,
+        &carols_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&carol]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::UnauthorizedTaker as u32)
+    );
+
+    // ...nor lock it away from him
+    let prepare_exchange_ix =
+        prepare_exchange_instruction(&env.program_id, &carol.pubkey(), &escrow_accounts.escrow);
+    let error = env
+        .process(&[prepare_exchange_ix], &[&carol])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::UnauthorizedTaker as u32)
+    );
+    assert_eq!(env.token_balance(&carols_sending_token_account).await, 20);
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+}
+
+#[tokio::test]
+async fn test_second_exchange_in_same_transaction_fails() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 40)
+        .await;
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    // The first exchange closes the escrow, which the second one must notice
+    let error = env
+        .process(&[exchange_ix.clone(), exchange_ix.clone()], &[&bob])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(EscrowError::InvalidState as u32)
+        )
+    );
+
+    // Nothing of the first one sticks either
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 40);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[cfg(feature = "maker-rebate")]
+#[tokio::test]
+async fn test_exchange_pays_maker_rebate() {
+    use solana_escrow_program::config::{FEE_VAULT_SEED, MAKER_REBATE_LAMPORTS};
+
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let (fee_vault, _bump_seed) = Pubkey::find_program_address(&[FEE_VAULT_SEED], &env.program_id);
+    env.transfer_lamports(&fee_vault, 10 * MAKER_REBATE_LAMPORTS)
+        .await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Alice gets back the rent of both the escrow and the temp account
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let temp_token_account = env
+        .get_account(&escrow_accounts.temp_token_account)
+        .await
+        .unwrap();
+    let rent = escrow.lamports + temp_token_account.lamports;
+    let lamports_before = env
+        .get_account(&alice.pubkey())
+        .await
+        .map_or(0, |account| account.lamports);
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    let alice_account = env.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(
+        alice_account.lamports,
+        lamports_before + rent + MAKER_REBATE_LAMPORTS
+    );
+    let fee_vault = env.get_account(&fee_vault).await.unwrap();
+    assert_eq!(fee_vault.lamports, 9 * MAKER_REBATE_LAMPORTS);
+}
+
+#[tokio::test]
+async fn test_cancel_escrow_tells_clock_account_apart_by_key() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let not_before = env.unix_timestamp().await + 3600;
+    let locked_escrow_accounts = env
+        .init_escrow(
+            &alice,
+            &mint_x,
+            &mint_y,
+            EscrowTerms {
+                not_before,
+                ..EscrowTerms::new(10, 20)
+            },
+        )
+        .await;
+    let initializers_token_x_account = env.create_token_account(&mint_x, &alice.pubkey()).await;
+
+    // Any sysvar but the Clock is no clock at all, the lockup still holds by `Clock::get()`...
+    let mut cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &locked_escrow_accounts,
+    );
+    cancel_escrow_ix
+        .accounts
+        .push(AccountMeta::new_readonly(sysvar::rent::id(), false));
+    let error = env
+        .process(&[cancel_escrow_ix], &[&alice])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::LockupActive as u32)
+    );
+    assert_eq!(
+        env.token_balance(&locked_escrow_accounts.temp_token_account)
+            .await,
+        10
+    );
+
+    // ...while the Clock itself does what `Clock::get()` would
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let mut cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &initializers_token_x_account,
+        &escrow_accounts,
+    );
+    cancel_escrow_ix
+        .accounts
+        .push(AccountMeta::new_readonly(sysvar::clock::id(), false));
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    assert_eq!(env.token_balance(&initializers_token_x_account).await, 10);
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_exchange_creates_closed_associated_token_account() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let mut escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+
+    // Alice moves to her associated Token Y account...
+    let associated_token_account =
+        token::associated_token_address(&alice.pubkey(), &mint_y, &env.token_program);
+    let create_account_ix = token::create_associated_token_account(
+        &env.payer.pubkey(),
+        &alice.pubkey(),
+        &mint_y,
+        &env.token_program,
+    );
+    env.process(&[create_account_ix], &[]).await.unwrap();
+    let set_receive_account_ix = set_receive_account_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow_accounts.escrow,
+        &associated_token_account,
+        &escrow_accounts.initializer_token_to_receive_account,
+    );
+    env.process(&[set_receive_account_ix], &[&alice])
+        .await
+        .unwrap();
+    escrow_accounts.initializer_token_to_receive_account = associated_token_account;
+
+    // ...and closes it while cleaning up her wallet
+    let close_account_ix = spl_token::instruction::close_account(
+        &env.token_program,
+        &associated_token_account,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &[],
+    )
+    .unwrap();
+    env.process(&[close_account_ix], &[&alice]).await.unwrap();
+    assert!(env.get_account(&associated_token_account).await.is_none());
+
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    env.transfer_lamports(&bob.pubkey(), 10_000_000).await;
+
+    let exchange_ix = EscrowInstruction::exchange(
+        &env.program_id,
+        &ExchangeAccounts {
+            initializers_token_to_receive_account: associated_token_account,
+            create_initializers_token_to_receive_account: true,
+            ..escrow_accounts.exchange_accounts(
+                &bob.pubkey(),
+                &takers_sending_token_account,
+                &takers_token_to_receive_account,
+                &alice.pubkey(),
+            )
+        },
+        10,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    // Bob paid for the account Alice got paid into
+    let rent = env.minimum_balance(TokenAccount::LEN).await;
+    let bob_account = env.get_account(&bob.pubkey()).await.unwrap();
+    assert_eq!(bob_account.lamports, 10_000_000 - rent);
+    let associated_token_account = env.get_account(&associated_token_account).await.unwrap();
+    let associated_token_account = TokenAccount::unpack(&associated_token_account.data).unwrap();
+    assert_eq!(associated_token_account.owner, alice.pubkey());
+    assert_eq!(associated_token_account.mint, mint_y);
+    assert_eq!(associated_token_account.amount, 20);
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+}
+
+fn migrate_state_instruction(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    escrow: &Pubkey,
+    program_data: Option<&Pubkey>,
+    top_up_rent: bool,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*authority, true),
+        AccountMeta::new(*escrow, false),
+    ];
+    if let Some(program_data) = program_data {
+        accounts.push(AccountMeta::new_readonly(*program_data, false));
+    }
+    if top_up_rent {
+        accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+    }
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::MigrateState.pack(),
+    }
+}
+
+// The layout version 12 stored an escrow in, the last before `grace_seconds`, rent exempt at that size
+const V12_LEN: usize = Escrow::OUTDATED_LENS[11];
+
+fn v12_escrow_account(program_id: &Pubkey, escrow: &Escrow) -> Account {
+    let mut packed = [0u8; Escrow::LEN];
+    Escrow::pack(escrow.clone(), &mut packed).unwrap();
+    let mut data = packed[..V12_LEN].to_vec();
+    data[0] = 12;
+    Account {
+        lamports: Rent::default().minimum_balance(V12_LEN),
+        data,
+        owner: *program_id,
+        ..Account::default()
+    }
+}
+
+#[tokio::test]
+async fn test_migrate_state_from_v12() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+
+    let escrow = Pubkey::new_unique();
+    let escrow_info = Escrow {
+        version: Escrow::VERSION,
+        is_initialized: true,
+        initializer_pubkey: alice.pubkey(),
+        temp_token_account_pubkey: Pubkey::new_unique(),
+        initializer_token_to_receive_account_pubkey: Pubkey::new_unique(),
+        expected_amount: 20,
+        expire_at: i64::MAX,
+        remaining_amount: 10,
+        token_program: spl_token::id(),
+        reference: [7; 32],
+        ..Escrow::default()
+    };
+    program_test.add_account(escrow, v12_escrow_account(&program_id, &escrow_info));
+    let mut env = TestEnv::start(program_id, program_test).await;
+    env.transfer_lamports(&alice.pubkey(), 1_000_000_000).await;
+
+    // The current program can't read it as it is
+    let set_receive_account_ix = set_receive_account_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &escrow,
+        &Pubkey::new_unique(),
+        &escrow_info.initializer_token_to_receive_account_pubkey,
+    );
+    assert!(env
+        .process(&[set_receive_account_ix], &[&alice])
+        .await
+        .is_err());
+
+    let migrate_state_ix =
+        migrate_state_instruction(&env.program_id, &alice.pubkey(), &escrow, None, true);
+    env.process(&[migrate_state_ix], &[&alice]).await.unwrap();
+
+    let escrow_account = env.get_account(&escrow).await.unwrap();
+    assert_eq!(escrow_account.data.len(), Escrow::LEN);
+    assert_eq!(
+        escrow_account.lamports,
+        env.minimum_balance(Escrow::LEN).await
+    );
+    let migrated = Escrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(migrated, escrow_info);
+    assert_eq!(migrated.grace_seconds, 0);
+    assert_eq!(migrated.offered_amount, 0);
+
+    // and there is nothing left to migrate
+    let migrate_state_ix =
+        migrate_state_instruction(&env.program_id, &alice.pubkey(), &escrow, None, true);
+    let error = env
+        .process(&[migrate_state_ix], &[&alice])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::UnsupportedStateVersion as u32)
+    );
+}
+
+// An escrow made before the version byte, its temp account still under the PDA all of them shared
+#[tokio::test]
+async fn test_migrate_state_from_baseline() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let temp_token_account = Keypair::new();
+    let token_to_receive_account = Keypair::new();
+
+    let escrow = Pubkey::new_unique();
+    let mut baseline = vec![1];
+    baseline.extend_from_slice(alice.pubkey().as_ref());
+    baseline.extend_from_slice(temp_token_account.pubkey().as_ref());
+    baseline.extend_from_slice(token_to_receive_account.pubkey().as_ref());
+    baseline.extend_from_slice(&20u64.to_le_bytes());
+    program_test.add_account(
+        escrow,
+        Account {
+            lamports: Rent::default().minimum_balance(Escrow::BASELINE_LEN),
+            data: baseline,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+    env.transfer_lamports(&alice.pubkey(), 1_000_000_000).await;
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+    let (baseline_pda, _bump_seed) =
+        Pubkey::find_program_address(&[config::BASELINE_ESCROW_SEED], &program_id);
+    env.create_token_account_at(&temp_token_account, &mint_x, &baseline_pda)
+        .await;
+    env.mint_to(&mint_x, &temp_token_account.pubkey(), 10).await;
+    env.create_token_account_at(&token_to_receive_account, &mint_y, &alice.pubkey())
+        .await;
+
+    // The system program is a must, the escrow account grows far past what its rent covers
+    let mut migrate_state_ix =
+        migrate_state_instruction(&env.program_id, &alice.pubkey(), &escrow, None, true);
+    migrate_state_ix.accounts.extend([
+        AccountMeta::new(temp_token_account.pubkey(), false),
+        AccountMeta::new_readonly(token_to_receive_account.pubkey(), false),
+        AccountMeta::new_readonly(mint_y, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(baseline_pda, false),
+    ]);
+    env.process(&[migrate_state_ix], &[&alice]).await.unwrap();
+
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    let escrow_account = env.get_account(&escrow).await.unwrap();
+    assert_eq!(escrow_account.data.len(), Escrow::LEN);
+    let migrated = Escrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(migrated.expected_amount, 20);
+    assert_eq!(migrated.remaining_amount, 10);
+    assert_eq!(migrated.offered_amount, 10);
+    assert_eq!(migrated.bump_seed, bump_seed);
+    assert_eq!(migrated.expire_at, i64::MAX);
+    assert_eq!(migrated.token_program, spl_token::id());
+    let temp_account = env.get_account(&temp_token_account.pubkey()).await.unwrap();
+    assert_eq!(TokenAccount::unpack(&temp_account.data).unwrap().owner, pda);
+
+    // and Bob takes it like any escrow made today
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account: temp_token_account.pubkey(),
+        initializer_token_to_receive_account: token_to_receive_account.pubkey(),
+        initializer_token_to_receive_mint: mint_y,
+        temp_token_mint: mint_x,
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&token_to_receive_account.pubkey()).await,
+        20
+    );
+    assert!(env.get_account(&escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn test_migrate_state_by_upgrade_authority() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let upgrade_authority = Keypair::new();
+    let mallory = Keypair::new();
+
+    let escrow = Pubkey::new_unique();
+    let escrow_info = Escrow {
+        version: Escrow::VERSION,
+        is_initialized: true,
+        initializer_pubkey: alice.pubkey(),
+        expected_amount: 20,
+        expire_at: i64::MAX,
+        token_program: spl_token::id(),
+        ..Escrow::default()
+    };
+    program_test.add_account(escrow, v12_escrow_account(&program_id, &escrow_info));
+    let program_data =
+        add_program_data(&mut program_test, &program_id, &upgrade_authority.pubkey());
+    let mut env = TestEnv::start(program_id, program_test).await;
+    env.transfer_lamports(&upgrade_authority.pubkey(), 1_000_000_000)
+        .await;
+    env.transfer_lamports(&mallory.pubkey(), 1_000_000_000)
+        .await;
+
+    // Neither Alice nor the upgrade authority
+    let migrate_state_ix = migrate_state_instruction(
+        &env.program_id,
+        &mallory.pubkey(),
+        &escrow,
+        Some(&program_data),
+        true,
+    );
+    let error = env
+        .process(&[migrate_state_ix], &[&mallory])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InitializerMismatch as u32)
+    );
+
+    let migrate_state_ix = migrate_state_instruction(
+        &env.program_id,
+        &upgrade_authority.pubkey(),
+        &escrow,
+        Some(&program_data),
+        true,
+    );
+    env.process(&[migrate_state_ix], &[&upgrade_authority])
+        .await
+        .unwrap();
+
+    let escrow_account = env.get_account(&escrow).await.unwrap();
+    assert_eq!(Escrow::unpack(&escrow_account.data).unwrap(), escrow_info);
+}
+
+#[tokio::test]
+async fn test_migrate_state_keeps_escrow_rent_exempt() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+
+    let escrow = Pubkey::new_unique();
+    let escrow_info = Escrow {
+        version: Escrow::VERSION,
+        is_initialized: true,
+        initializer_pubkey: alice.pubkey(),
+        expected_amount: 20,
+        expire_at: i64::MAX,
+        token_program: spl_token::id(),
+        ..Escrow::default()
+    };
+    program_test.add_account(escrow, v12_escrow_account(&program_id, &escrow_info));
+    let mut env = TestEnv::start(program_id, program_test).await;
+    env.transfer_lamports(&alice.pubkey(), 1_000_000_000).await;
+
+    // Without the system program nobody tops up the rent, and the v12 rent is not enough
+    let migrate_state_ix =
+        migrate_state_instruction(&env.program_id, &alice.pubkey(), &escrow, None, false);
+    let error = env
+        .process(&[migrate_state_ix], &[&alice])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::NotRentExempt as u32)
+    );
+    assert_eq!(env.get_account(&escrow).await.unwrap().data.len(), V12_LEN);
+
+    // Funded for its new size beforehand, it grows all the same
+    let v12_rent = env.get_account(&escrow).await.unwrap().lamports;
+    let rent = env.minimum_balance(Escrow::LEN).await;
+    env.transfer_lamports(&escrow, rent - v12_rent).await;
+    let migrate_state_ix =
+        migrate_state_instruction(&env.program_id, &alice.pubkey(), &escrow, None, false);
+    env.process(&[migrate_state_ix], &[&alice]).await.unwrap();
+
+    let escrow_account = env.get_account(&escrow).await.unwrap();
+    assert_eq!(escrow_account.data.len(), Escrow::LEN);
+    assert_eq!(escrow_account.lamports, rent);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn init_escrow_native_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    pda: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    token_to_receive_mint: &Pubkey,
+    escrow_account: &Pubkey,
+    token_program: &Pubkey,
+    terms: &EscrowTerms,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*pda, false),
+        AccountMeta::new_readonly(*token_to_receive_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(*token_program, false),
+        AccountMeta::new_readonly(*token_to_receive_mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some(rent_payer) = terms.rent_payer {
+        accounts.push(AccountMeta::new_readonly(rent_payer, false));
+    }
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitEscrowNative {
+            params: terms.params(),
+            offered_lamports: terms.offered_amount,
+        }
+        .pack(),
+    }
+}
+
+fn exchange_native_offer_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    amount: u64,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*taker, true),
+        AccountMeta::new(*takers_sending_token_account, false),
+        AccountMeta::new(escrow_accounts.pda, false),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(escrow_accounts.initializer_token_to_receive_account, false),
+        AccountMeta::new(escrow_accounts.escrow, false),
+        AccountMeta::new_readonly(escrow_accounts.token_program, false),
+        AccountMeta::new_readonly(escrow_accounts.initializer_token_to_receive_mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some(fee_account) = escrow_accounts.fee_account {
+        accounts.push(AccountMeta::new(fee_account, false));
+    }
+    if let Some(rent_payer) = escrow_accounts.rent_payer {
+        accounts.push(AccountMeta::new(rent_payer, false));
+    }
+    if config::MAKER_REBATE_LAMPORTS != 0 {
+        let (fee_vault, _bump_seed) =
+            Pubkey::find_program_address(&[config::FEE_VAULT_SEED], program_id);
+        accounts.push(AccountMeta::new(fee_vault, false));
+        accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+    }
+    let (program_config, _bump_seed) =
+        Pubkey::find_program_address(&[config::PROGRAM_CONFIG_SEED], program_id);
+    accounts.push(AccountMeta::new_readonly(program_config, false));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ExchangeNativeOffer { amount }.pack(),
+    }
+}
+
+// Alice offers 1 SOL for 20 Token Y, Bob takes it
+#[tokio::test]
+async fn test_exchange_native_offer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_y = env.create_mint().await;
+    env.transfer_lamports(&alice.pubkey(), 2_000_000_000).await;
+
+    let escrow_accounts = env
+        .init_escrow_native(&alice, &mint_y, EscrowTerms::new(1_000_000_000, 20))
+        .await;
+    assert_eq!(
+        env.get_account(&escrow_accounts.pda)
+            .await
+            .unwrap()
+            .lamports,
+        1_000_000_000
+    );
+    let escrow = env.get_account(&escrow_accounts.escrow).await.unwrap();
+    let escrow = Escrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.mode, EscrowMode::NativeOffer);
+    assert_eq!(escrow.remaining_amount, 1_000_000_000);
+
+    let takers_sending_token_account = env.create_token_account(&mint_y, &bob.pubkey()).await;
+    env.mint_to(&mint_y, &takers_sending_token_account, 20)
+        .await;
+
+    // Bob has to take all of it
+    let exchange_ix = exchange_native_offer_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        500_000_000,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::ExpectedAmountMismatch as u32)
+    );
+
+    let exchange_ix = exchange_native_offer_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        1_000_000_000,
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.get_account(&bob.pubkey()).await.unwrap().lamports,
+        1_000_000_000
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 0);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.pda).await.is_none());
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    // Alice got the escrow's rent back on top of what she kept
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    assert_eq!(
+        env.get_account(&alice.pubkey()).await.unwrap().lamports,
+        1_000_000_000 + escrow_rent
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_native_offer_rejects_mismatched_decimals() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(&program_id);
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+
+    // An offer priced for a 6 decimals Token Y, while Alice's Token Y mint has 9
+    let mint_y = Pubkey::new_unique();
+    program_test.add_packable_account(
+        mint_y,
+        1_000_000_000,
+        &Mint {
+            decimals: 9,
+            is_initialized: true,
+            ..Mint::default()
+        },
+        &spl_token::id(),
+    );
+    let escrow = Pubkey::new_unique();
+    let (pda, bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &program_id);
+    let initializer_token_to_receive_account = Pubkey::new_unique();
+    let takers_sending_token_account = Pubkey::new_unique();
+    for (account, owner, amount) in [
+        (initializer_token_to_receive_account, alice.pubkey(), 0),
+        (takers_sending_token_account, bob.pubkey(), 20),
+    ] {
+        program_test.add_packable_account(
+            account,
+            1_000_000_000,
+            &TokenAccount {
+                mint: mint_y,
+                owner,
+                amount,
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            },
+            &spl_token::id(),
+        );
+    }
+    program_test.add_packable_account(
+        escrow,
+        1_000_000_000,
+        &Escrow {
+            version: Escrow::VERSION,
+            is_initialized: true,
+            initializer_pubkey: alice.pubkey(),
+            temp_token_account_pubkey: pda,
+            initializer_token_to_receive_account_pubkey: initializer_token_to_receive_account,
+            expected_amount: 20,
+            bump_seed,
+            expire_at: i64::MAX,
+            remaining_amount: 1_000_000_000,
+            initializer_mint_decimals: 6,
+            token_program: spl_token::id(),
+            mode: EscrowMode::NativeOffer,
+            offered_amount: 1_000_000_000,
+            ..Escrow::default()
+        },
+        &program_id,
+    );
+    let mut env = TestEnv::start(program_id, program_test).await;
+
+    let escrow_accounts = EscrowAccounts {
+        escrow,
+        temp_token_account: pda,
+        initializer_token_to_receive_account,
+        initializer_token_to_receive_mint: mint_y,
+        temp_token_mint: Pubkey::default(),
+        pda,
+        fee_account: None,
+        fee_token_account: None,
+        initializer_token_x_account: None,
+        rent_payer: None,
+        token_program: spl_token::id(),
+    };
+    let exchange_ix = exchange_native_offer_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        1_000_000_000,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::DecimalsMismatch as u32)
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+}
+
+#[tokio::test]
+async fn test_cancel_native_offer() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_y = env.create_mint().await;
+    env.transfer_lamports(&alice.pubkey(), 2_000_000_000).await;
+
+    let escrow_accounts = env
+        .init_escrow_native(&alice, &mint_y, EscrowTerms::new(1_000_000_000, 20))
+        .await;
+
+    // The system program moves the lamports back, in place of Alice's Token X account
+    let mut cancel_escrow_ix = cancel_escrow_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &system_program::id(),
+        &escrow_accounts,
+    );
+    cancel_escrow_ix.accounts[2] = AccountMeta::new_readonly(system_program::id(), false);
+    env.process(&[cancel_escrow_ix], &[&alice]).await.unwrap();
+
+    assert!(env.get_account(&escrow_accounts.pda).await.is_none());
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+    let escrow_rent = env.minimum_balance(Escrow::LEN).await;
+    assert_eq!(
+        env.get_account(&alice.pubkey()).await.unwrap().lamports,
+        2_000_000_000 + escrow_rent
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_native_rejects_token_x_terms() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let mint_y = env.create_mint().await;
+    env.transfer_lamports(&alice.pubkey(), 2_000_000_000).await;
+    let initializer_token_to_receive_account =
+        env.create_token_account(&mint_y, &alice.pubkey()).await;
+    let escrow = env.create_escrow_account().await;
+    let (pda, _bump_seed) =
+        Pubkey::find_program_address(&[config::ESCROW_SEED, escrow.as_ref()], &env.program_id);
+
+    // A taker fee only ever comes out of Token X, which a native offer has none of
+    let terms = EscrowTerms {
+        taker_fee_bps: 250,
+        fee_token_account: Pubkey::new_unique(),
+        ..EscrowTerms::new(1_000_000_000, 20)
+    };
+    let init_escrow_ix = init_escrow_native_instruction(
+        &env.program_id,
+        &alice.pubkey(),
+        &pda,
+        &initializer_token_to_receive_account,
+        &mint_y,
+        &escrow,
+        &env.token_program,
+        &terms,
+    );
+    let error = env.process(&[init_escrow_ix], &[&alice]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::InvalidInstruction as u32)
+    );
+    assert!(env.get_account(&pda).await.is_none());
+}
+
+// Same accounts as `exchange_instruction`, with `memo_program` standing in for the memo program
+#[allow(clippy::too_many_arguments)]
+fn exchange_with_memo_instruction(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    takers_token_to_receive_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow_accounts: &EscrowAccounts,
+    amount: u64,
+    memo_program: &Pubkey,
+    memo: &[u8],
+) -> Instruction {
+    let mut instruction = EscrowInstruction::exchange_with_memo(
+        program_id,
+        &escrow_accounts.exchange_accounts(
+            taker,
+            takers_sending_token_account,
+            takers_token_to_receive_account,
+            initializer,
+        ),
+        amount,
+        amount,
+        memo,
+    )
+    .unwrap();
+    instruction.accounts.last_mut().unwrap().pubkey = *memo_program;
+    instruction
+}
+
+#[tokio::test]
+async fn test_exchange_with_memo() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Only the memo program records the note
+    let exchange_ix = exchange_with_memo_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+        &system_program::id(),
+        b"trade #42",
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::IncorrectProgramId
+    );
+
+    // A memo too long to fit in the transaction is turned down before it is sent
+    assert_eq!(
+        EscrowInstruction::exchange_with_memo(
+            &env.program_id,
+            &escrow_accounts.exchange_accounts(
+                &bob.pubkey(),
+                &takers_sending_token_account,
+                &takers_token_to_receive_account,
+                &alice.pubkey(),
+            ),
+            10,
+            10,
+            &[b'a'; EscrowInstruction::MAX_MEMO_LEN + 1],
+        ),
+        Err(EscrowError::MemoTooLong.into())
+    );
+
+    // The memo program gets the very bytes Bob sent, and turns down any that aren't UTF-8,
+    // taking the whole exchange down with it
+    let exchange_ix = exchange_with_memo_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+        &memo::id(),
+        &[0xF0, 0x9F, 0x92],
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::InvalidInstructionData
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+    assert_eq!(
+        env.token_balance(&escrow_accounts.temp_token_account).await,
+        10
+    );
+
+    let exchange_ix = exchange_with_memo_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+        &memo::id(),
+        "trade #42 \u{1F91D}".as_bytes(),
+    );
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+// The keypair behind `config::ADMIN_PUBKEY`, which only ever signs in tests
+const ADMIN_KEYPAIR: [u8; 64] = [
+    10, 170, 121, 181, 133, 132, 105, 68, 216, 187, 239, 161, 147, 68, 3, 250, 158, 142, 92, 155,
+    139, 65, 236, 28, 174, 92, 59, 17, 114, 55, 242, 120, 117, 150, 39, 96, 198, 179, 80, 147, 123,
+    138, 126, 232, 90, 156, 200, 111, 201, 80, 177, 118, 213, 145, 75, 225, 221, 224, 65, 74, 108,
+    24, 27, 67,
+];
+
+// The admin pauses every exchange, Bob is turned away, then takes the escrow once they resume
+#[tokio::test]
+async fn test_toggle_exchanges() {
+    let mut env = TestEnv::new().await;
+    let admin = Keypair::from_bytes(&ADMIN_KEYPAIR).unwrap();
+    assert_eq!(admin.pubkey(), config::ADMIN_PUBKEY);
+    env.transfer_lamports(&admin.pubkey(), 1_000_000_000).await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    // Nobody but the admin may pause them
+    let toggle_exchanges_ix =
+        EscrowInstruction::toggle_exchanges(&env.program_id, &bob.pubkey(), true);
+    let error = env
+        .process(&[toggle_exchanges_ix], &[&bob])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::MissingRequiredSignature
+    );
+
+    // The first toggle creates the program config, even once someone sent its address a lamport
+    let (program_config, _bump_seed) =
+        Pubkey::find_program_address(&[config::PROGRAM_CONFIG_SEED], &env.program_id);
+    assert!(env.get_account(&program_config).await.is_none());
+    env.transfer_lamports(&program_config, 1).await;
+    let toggle_exchanges_ix =
+        EscrowInstruction::toggle_exchanges(&env.program_id, &admin.pubkey(), true);
+    env.process(&[toggle_exchanges_ix], &[&admin])
+        .await
+        .unwrap();
+    let program_config_account = env.get_account(&program_config).await.unwrap();
+    assert_eq!(program_config_account.owner, env.program_id);
+    assert!(
+        ProgramConfig::unpack(&program_config_account.data)
+            .unwrap()
+            .exchanges_paused
+    );
+
+    let exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let error = env.process(&[exchange_ix], &[&bob]).await.unwrap_err();
+    assert_eq!(
+        instruction_error(error),
+        InstructionError::Custom(EscrowError::ExchangesPaused as u32)
+    );
+    assert_eq!(env.token_balance(&takers_sending_token_account).await, 20);
+
+    let toggle_exchanges_ix =
+        EscrowInstruction::toggle_exchanges(&env.program_id, &admin.pubkey(), false);
+    env.process(&[toggle_exchanges_ix], &[&admin])
+        .await
+        .unwrap();
+    let program_config_account = env.get_account(&program_config).await.unwrap();
+    assert!(
+        !ProgramConfig::unpack(&program_config_account.data)
+            .unwrap()
+            .exchanges_paused
+    );
+
+    // Bob takes it in two parts, so that no transaction repeats the one turned away
+    for amount in [4, 6] {
+        let partial_exchange_ix = partial_exchange_instruction(
+            &env.program_id,
+            &bob.pubkey(),
+            &takers_sending_token_account,
+            &takers_token_to_receive_account,
+            &alice.pubkey(),
+            &escrow_accounts,
+            amount,
+        );
+        env.process(&[partial_exchange_ix], &[&bob]).await.unwrap();
+    }
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert_eq!(
+        env.token_balance(&escrow_accounts.initializer_token_to_receive_account)
+            .await,
+        20
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}
+
+// Clients from before the program config don't pass it, and take escrows just as they did
+#[tokio::test]
+async fn test_exchange_without_program_config() {
+    let mut env = TestEnv::new().await;
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint_x = env.create_mint().await;
+    let mint_y = env.create_mint().await;
+
+    let escrow_accounts = env
+        .init_escrow(&alice, &mint_x, &mint_y, EscrowTerms::new(10, 20))
+        .await;
+    let (takers_sending_token_account, takers_token_to_receive_account) = env
+        .create_taker_accounts(&bob.pubkey(), &mint_x, &mint_y, 20)
+        .await;
+
+    let mut exchange_ix = exchange_instruction(
+        &env.program_id,
+        &bob.pubkey(),
+        &takers_sending_token_account,
+        &takers_token_to_receive_account,
+        &alice.pubkey(),
+        &escrow_accounts,
+        10,
+    );
+    let (program_config, _bump_seed) =
+        Pubkey::find_program_address(&[config::PROGRAM_CONFIG_SEED], &env.program_id);
+    assert_eq!(exchange_ix.accounts.pop().unwrap().pubkey, program_config);
+    env.process(&[exchange_ix], &[&bob]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&takers_token_to_receive_account).await,
+        10
+    );
+    assert!(env.get_account(&escrow_accounts.escrow).await.is_none());
+}